@@ -1,13 +1,13 @@
 use std::future::{Ready, ready};
 use std::net::SocketAddr;
 use std::ops::Add;
-use std::time::{Duration, SystemTime};
+use std::time::SystemTime;
 use anyhow::anyhow;
 use hyper::body::Incoming;
 use hyper::Response;
 use hyper::server::conn::http1;
 use hyper::service::Service;
-use oauth2::{AccessToken, AuthorizationCode, AuthUrl, ClientId, ClientSecret, CsrfToken, ExtraTokenFields, PkceCodeChallenge, RedirectUrl, RefreshToken, Scope, StandardRevocableToken, StandardTokenResponse, TokenResponse, TokenUrl};
+use oauth2::{AuthorizationCode, AuthUrl, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl};
 use oauth2::basic::{BasicClient, BasicTokenResponse};
 use reqwest::Client;
 use serde::Deserialize;
@@ -18,8 +18,12 @@ const CLIENT_ID: &str = env!("GOOGLE_CLIENT_ID");
 const CLIENT_SECRET: &str = env!("GOOGLE_CLIENT_SECRET");
 const AUTH_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
 const TOKEN_URL: &str = "https://www.googleapis.com/oauth2/v3/token";
+/// Access to the hidden appDataFolder, where dsync keeps state shared between machines
+pub const APPDATA_SCOPE: &str = "https://www.googleapis.com/auth/drive.appdata";
 
-async fn auth_server(list: TcpListener) -> anyhow::Result<AuthorizationCode> {
+/// Wait for the browser to be redirected back with the authorization code, `csrf` is the state
+/// the redirect must carry
+async fn auth_server(list: TcpListener, csrf: CsrfToken) -> anyhow::Result<AuthorizationCode> {
     #[derive(Debug, Deserialize)]
     struct RedirectCallbackQuery {
         state: String,
@@ -28,6 +32,7 @@ async fn auth_server(list: TcpListener) -> anyhow::Result<AuthorizationCode> {
 
     struct OauthCallbackService {
         tx: tokio::sync::mpsc::Sender<AuthorizationCode>,
+        csrf: CsrfToken,
     }
 
     impl Service<hyper::Request<Incoming>> for OauthCallbackService {
@@ -43,11 +48,14 @@ async fn auth_server(list: TcpListener) -> anyhow::Result<AuthorizationCode> {
                 return ready(Err(anyhow!("Invalid query")));
             };
 
-            if let Ok(query) = serde_urlencoded::from_str::<RedirectCallbackQuery>(query) {
-                self.tx.try_send(query.code).unwrap();
-                return ready(Ok(Response::builder().body("Sucesfully logged in".to_string()).unwrap()));
+            match serde_urlencoded::from_str::<RedirectCallbackQuery>(query) {
+                Ok(query) if query.state == *self.csrf.secret() => {
+                    self.tx.try_send(query.code).unwrap();
+                    ready(Ok(Response::builder().body("Sucesfully logged in".to_string()).unwrap()))
+                }
+                Ok(_) => ready(Err(anyhow!("State of the redirect doesn't match the sign in request"))),
+                Err(_) => ready(Err(anyhow!("Invalid query"))),
             }
-            return ready(Err(anyhow!("Invalid query")));
         }
     }
 
@@ -59,11 +67,14 @@ async fn auth_server(list: TcpListener) -> anyhow::Result<AuthorizationCode> {
         let stream = hyper_util::rt::TokioIo::new(stream);
 
         let serve = http1::Builder::new()
-            .serve_connection(stream, OauthCallbackService { tx });
+            .serve_connection(stream, OauthCallbackService { tx, csrf: csrf.clone() });
 
         tokio::select! {
-            done = serve => {
-                panic!("Server closed");
+            _ = serve => {
+                // The browser may close the connection before or after delivering the code
+                if let Ok(code) = rx.try_recv() {
+                    return Ok(code);
+                }
             }
             code = rx.recv() => {
                 return Ok(code.unwrap())
@@ -98,12 +109,13 @@ pub(crate) async fn auth(client: &Client) -> anyhow::Result<(SystemTime, BasicTo
         .add_scope(Scope::new(
             "https://www.googleapis.com/auth/drive".to_string(),
         ))
+        .add_scope(Scope::new(APPDATA_SCOPE.to_string()))
         .set_pkce_challenge(pkce_code_challenge)
         .url();
 
-    open::that(&authorize_url.to_string()).unwrap();
+    open::that(authorize_url.to_string()).unwrap();
 
-    let code = auth_server(list).await.unwrap();
+    let code = auth_server(list, csrf_state).await?;
 
     let token_response = device_client
         .exchange_code(code)
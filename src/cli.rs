@@ -1,7 +1,17 @@
-use std::path::PathBuf;
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use clap::{Parser, Subcommand};
-use serde_json::to_string;
+use std::time::Duration;
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use crate::filter;
+use crate::gdrive::{Duplicates, LabelModification, Role};
+use crate::normalize::Normalization;
+use crate::query::Query;
+use crate::repo::{Checksum, Links};
+use crate::sync::{Answer, CompareMode, Confirm, ConflictStrategy, NoConfirm, Op, OrderBy, SyncOptions, SyncPlan};
 
 #[derive(Debug, Clone)]
 pub struct PrefixedPath {
@@ -9,31 +19,622 @@ pub struct PrefixedPath {
     pub path: PathBuf,
 }
 
+impl PrefixedPath {
+    /// Resolve local paths to absolute ones, remote paths are left as is
+    pub fn canonicalize(self) -> std::io::Result<Self> {
+        match self.prefix {
+            Some(_) => Ok(self),
+            None => Ok(Self { prefix: None, path: self.path.canonicalize()? }),
+        }
+    }
+}
+
+impl PrefixedPath {
+    /// Id of the folder a Drive path starts at with `drive:{id:...}/subdir`, or
+    /// [`crate::gdrive::SHARED`] for `drive:@shared/subdir`, along with the rest of the path
+    pub fn drive_folder(&self) -> (Option<String>, PathBuf) {
+        let mut parts = self.path.components();
+        let id = parts.next()
+            .and_then(|first| {
+                let first = first.as_os_str().to_str()?;
+                match first {
+                    crate::gdrive::SHARED => Some(first),
+                    _ => first.strip_prefix("{id:")?.strip_suffix('}'),
+                }
+            })
+            .map(str::to_owned);
+        match id {
+            Some(id) => (Some(id), parts.as_path().to_owned()),
+            None => (None, self.path.clone()),
+        }
+    }
+}
+
+impl Display for PrefixedPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.prefix {
+            Some(prefix) => write!(f, "{prefix}:{}", self.path.display()),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
 impl FromStr for PrefixedPath {
     type Err = core::convert::Infallible;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let pos = s.find(":");
         if let Some(pos) = pos {
-            return Ok(Self {
+            Ok(Self {
                 prefix: Some(s[..pos].to_string()),
                 path: FromStr::from_str(&s[pos + 1..])?,
-            });
+            })
         } else {
-            return Ok(Self {
+            Ok(Self {
                 prefix: None,
-                path: FromStr::from_str(&s)?,
-            });
+                path: FromStr::from_str(s)?,
+            })
         }
     }
 }
 
 #[derive(Debug, Parser)]
 pub struct Sync {
+    #[arg(name = "src", help = "Source path")]
+    pub src: PrefixedPath,
+    #[arg(name = "dst", required = true, num_args = 1.., help = "Destination paths")]
+    pub dst: Vec<PrefixedPath>,
+    #[arg(long, help = "Delete destination entries that don't exist in the source")]
+    pub delete: bool,
+    #[arg(long, help = "Also create source directories that contain no files")]
+    pub create_empty_dirs: bool,
+    #[arg(long, help = "Remove destination directories left empty by deletions")]
+    pub delete_empty_dirs: bool,
+    #[arg(long, value_enum, default_value_t, help = "How to resolve files that differ on both sides")]
+    pub conflict: ConflictStrategy,
+    #[arg(long, value_enum, default_value_t, help = "How to decide whether files are the same")]
+    pub compare: CompareMode,
+    #[arg(long, value_enum, help = "Checksum algorithm files are compared with, the strongest one both sides provide by default")]
+    pub hash: Option<Checksum>,
+    #[arg(long, help = "Only sync paths listed in this file, one per line, `-` reads from stdin")]
+    pub files_from: Option<PathBuf>,
+    #[command(flatten)]
+    pub filter: FilterArgs,
+    #[arg(long, default_value_t = 8, help = "Number of files to hash at once")]
+    pub checkers: usize,
+    #[arg(long, value_enum, default_value_t, help = "What to do with local symlinks, `keep` also lists Drive shortcuts as symlinks")]
+    pub links: Links,
+    #[arg(long, help = "Replace Drive locations with sample files held in memory, nothing is read from or written to Drive. The destination has to be on Drive")]
+    pub sandbox: bool,
+    #[arg(long, help = "Store names the local filesystem can't hold, like overly long ones, under a reversible encoding")]
+    pub sanitize_names: bool,
+    #[arg(long, value_enum, default_value_t, help = "Unicode form names are compared in, so that names differing only by normalization match")]
+    pub unicode_normalization: Normalization,
+    #[arg(long, help = "List whole trees upfront instead of each directory on its own, fewer requests to Drive but more memory. Listings of whole drives are kept, later runs only fetch what changed since. Folders within a drive are listed a level at a time, many folders per request")]
+    pub fast_list: bool,
+    #[arg(long, value_parser = parse_duration, default_value = "7d", help = "List Drive from scratch with --fast-list once the kept listing is older than this")]
+    pub listing_ttl: Duration,
+    #[arg(long, help = "List Drive from scratch with --fast-list, instead of updating the listing kept from previous runs")]
+    pub refresh: bool,
+    #[arg(long, value_delimiter = ',', default_value = "docx,xlsx,pptx,svg", help = "Formats Google documents of a Drive source are exported in, by extension. Each document uses the first one its kind supports, e.g. odt,ods,odp,pdf")]
+    pub export_formats: Vec<String>,
+    #[arg(long, conflicts_with = "export_formats", help = "Leave Google documents of a Drive source out instead of exporting them, only files with contents of their own are synced")]
+    pub skip_gdocs: bool,
+    #[arg(long, value_enum, default_value_t, help = "What to do with Drive files and folders sharing a name with a newer one in the same folder")]
+    pub duplicates: Duplicates,
+    #[arg(long, help = "Only sync this many levels of the tree, deeper directories are created empty with --create-empty-dirs")]
+    pub max_depth: Option<usize>,
+    #[arg(long, help = "Skip files and directories whose name starts with a `.`")]
+    pub skip_hidden: bool,
+    #[arg(long, value_name = "NAME", help = "Skip directories containing a file with this name, e.g. .nosync")]
+    pub exclude_if_present: Vec<String>,
+    #[arg(long, value_parser = parse_size, help = "Skip files smaller than this, e.g. 10k")]
+    pub min_size: Option<u64>,
+    #[arg(long, value_parser = parse_size, help = "Skip files larger than this, e.g. 2G")]
+    pub max_size: Option<u64>,
+    #[arg(long, value_parser = parse_duration, help = "Skip files modified more recently than this, e.g. 1h")]
+    pub min_age: Option<Duration>,
+    #[arg(long, value_parser = parse_duration, help = "Skip files modified longer ago than this, e.g. 7d")]
+    pub max_age: Option<Duration>,
+    #[arg(long, value_name = "MIME", help = "Only sync Drive files of this type, e.g. image/*")]
+    pub include_mime: Vec<String>,
+    #[arg(long, value_name = "MIME", help = "Don't sync Drive files of this type, e.g. application/vnd.google-apps.*")]
+    pub exclude_mime: Vec<String>,
+    #[arg(long, help = "Only sync Drive files you own, leaving out ones shared with you")]
+    pub owned_by_me: bool,
+    #[command(flatten)]
+    pub apply: ApplyArgs,
+}
+
+impl Sync {
+    /// Condition on files listed from a Drive source, filtering them before they're listed
+    pub fn drive_query(&self) -> Option<Query> {
+        let mime = Query::mime_patterns(&self.include_mime, &self.exclude_mime);
+        let owned = self.owned_by_me.then(Query::owned_by_me);
+        Query::all(mime.into_iter().chain(owned))
+    }
+
+    /// How both sides are opened
+    pub fn repo_options(&self) -> RepoOptions {
+        RepoOptions {
+            links: self.links,
+            sandbox: self.sandbox,
+            sanitize_names: self.sanitize_names,
+            unicode_normalization: self.unicode_normalization,
+            fast_list: self.fast_list,
+            listing_ttl: match self.refresh {
+                true => Duration::ZERO,
+                false => self.listing_ttl,
+            },
+            export_formats: match self.skip_gdocs {
+                true => vec![],
+                false => self.export_formats.clone(),
+            },
+            duplicates: self.duplicates,
+        }
+    }
+
+    pub fn options(&self) -> anyhow::Result<SyncOptions> {
+        let files_from = match &self.files_from {
+            Some(path) if path == Path::new("-") && self.apply.interactive => {
+                anyhow::bail!("--files-from - can't be combined with --interactive, both read stdin")
+            }
+            Some(path) if path == Path::new("-") => Some(read_paths(std::io::stdin().lock())?),
+            Some(path) => Some(read_paths(BufReader::new(std::fs::File::open(path)?))?),
+            None => None,
+        };
+        let filter = self.filter.filter()?;
+        Ok(SyncOptions {
+            delete: self.delete,
+            create_empty_dirs: self.create_empty_dirs,
+            delete_empty_dirs: self.delete_empty_dirs,
+            conflict: self.conflict,
+            compare: self.compare,
+            hash: self.hash,
+            files_from,
+            filter,
+            exclude_if_present: self.exclude_if_present.clone(),
+            skip_hidden: self.skip_hidden,
+            max_depth: self.max_depth,
+            checkers: self.checkers,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            min_age: self.min_age,
+            max_age: self.max_age,
+            ..self.apply.options()
+        })
+    }
+}
+
+/// Include/exclude rules, shared by `sync` and `filter test`
+#[derive(Debug, clap::Args)]
+pub struct FilterArgs {
+    #[arg(long, help = "Read include (`+ pattern`) and exclude (`- pattern`) rules from this file")]
+    pub filter_from: Option<PathBuf>,
+    #[arg(long, value_name = "REGEX", help = "Only sync files whose path matches this regex")]
+    pub include_regex: Vec<String>,
+    #[arg(long, value_name = "REGEX", help = "Skip paths matching this regex, directories end with `/`")]
+    pub exclude_regex: Vec<String>,
+}
+
+impl FilterArgs {
+    /// The filter to apply, if any rules were given
+    pub fn filter(&self) -> anyhow::Result<Option<filter::Filter>> {
+        let mut filter = self.filter_from.as_deref().map(filter::Filter::load).transpose()?;
+        if !self.include_regex.is_empty() || !self.exclude_regex.is_empty() {
+            let filter = filter.get_or_insert_with(filter::Filter::default);
+            for pattern in &self.include_regex {
+                filter.add_regex(true, pattern)?;
+            }
+            for pattern in &self.exclude_regex {
+                filter.add_regex(false, pattern)?;
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// Asks about each operation on the terminal, similar to `rm -i`
+pub struct TerminalConfirm;
+
+impl Confirm for TerminalConfirm {
+    fn confirm(&self, op: &Op) -> anyhow::Result<Answer> {
+        let kind = op.destructive().unwrap_or("apply");
+        loop {
+            eprint!("{kind} {:?}? [y/n/a/q] ", op.target());
+            std::io::stderr().flush()?;
+
+            let mut line = String::new();
+            if std::io::stdin().read_line(&mut line)? == 0 {
+                return Ok(Answer::Quit);
+            }
+            match line.trim().to_ascii_lowercase().as_str() {
+                "y" | "yes" => return Ok(Answer::Yes),
+                "n" | "no" | "" => return Ok(Answer::No),
+                "a" | "all" => return Ok(Answer::All),
+                "q" | "quit" => return Ok(Answer::Quit),
+                _ => eprintln!("Answer y(es), n(o), a(ll of this kind) or q(uit)"),
+            }
+        }
+    }
+}
+
+/// Read relative paths, one per line, ignoring empty lines and `#` comments
+pub fn read_paths(reader: impl BufRead) -> std::io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let path = Path::new(line);
+        let path = path.strip_prefix("./").or_else(|_| path.strip_prefix("/")).unwrap_or(path);
+        paths.push(path.to_owned());
+    }
+    Ok(paths)
+}
+
+/// Options affecting how changes are applied, shared by `sync` and `apply`
+#[derive(Debug, clap::Args)]
+pub struct ApplyArgs {
+    #[arg(long, default_value_t = 4, help = "Number of files to transfer in parallel")]
+    pub transfers: usize,
+    #[arg(long, value_enum, default_value_t, help = "Order in which files are transferred")]
+    pub order_by: OrderBy,
+    #[arg(long, value_parser = parse_size, help = "Stop after transferring this much data, e.g. 500M or 2G")]
+    pub max_transfer: Option<u64>,
+    #[arg(long, value_parser = parse_duration, help = "Stop after running this long, e.g. 90s, 30m or 8h")]
+    pub max_duration: Option<Duration>,
+    #[arg(long, help = "Move overwritten and deleted files into this destination directory")]
+    pub backup_dir: Option<PathBuf>,
+    #[arg(long, default_value_t = 3, help = "Retry failed operations as a whole this many times, on top of the backoff of each Drive request")]
+    pub retries: u32,
+    #[arg(short, long, help = "Ask before deleting or overwriting destination files")]
+    pub interactive: bool,
+    #[arg(long, help = "Keep overwritten and deleted files next to the originals with this suffix, e.g. .bak-{timestamp}")]
+    pub suffix: Option<String>,
+    #[arg(long, help = "Preserve permissions and extended attributes of transferred files, stored as appProperties on Drive")]
+    pub metadata: bool,
+    #[arg(long, help = "Preserve owner and group of transferred files, restoring them usually needs root")]
+    pub owner: bool,
+    #[arg(long, help = "Delete destination files permanently instead of moving them to the trash")]
+    pub permanent: bool,
+    #[arg(long, value_parser = parse_size, default_value = "8M", help = "Upload files smaller than this to Drive in a single request, larger ones in resumable sessions")]
+    pub upload_cutoff: u64,
+    #[arg(long, value_parser = parse_chunk_size, default_value = "8M", help = "Send resumable Drive uploads in pieces of this size, a multiple of 256K, larger pieces need more memory but fewer requests")]
+    pub drive_chunk_size: usize,
+    #[arg(long, value_delimiter = ',', help = "Convert files with these extensions into Google documents when uploading to Drive, e.g. docx,xlsx,pptx. They're listed back under their original names, so use a single format for each kind of document")]
+    pub import_formats: Vec<String>,
+    #[arg(long, help = "Keep revisions uploaded to Drive forever, instead of Drive deleting them 30 days after they're replaced. Drive keeps at most 200 of them per file")]
+    pub keep_forever: bool,
+    #[arg(long, help = "Download files into this directory before uploading them, instead of streaming them from one drive to the other")]
+    pub staging_dir: Option<PathBuf>,
+    #[arg(long, default_value_t = crate::gdrive::DEFAULT_RATE_LIMIT, help = "Send at most this many requests per second to Drive, 0 for no limit. Rate limited requests are retried with exponential backoff")]
+    pub drive_qps: f64,
+    #[arg(long, value_name = "LABEL[.FIELD=VALUE]", value_parser = parse_label, help = "Apply this Drive label to uploaded files, optionally setting one of its text fields, by their ids. Can be repeated")]
+    pub apply_label: Vec<LabelModification>,
+    #[arg(long, help = "Star files uploaded to Drive when they're listed in a .dsync-starred file at the root of the local source, one path per line")]
+    pub star_marked: bool,
+    #[arg(long, help = "Keep the snapshot of the last sync in the hidden appDataFolder of the drive, so every machine syncing the same Drive folder shares it")]
+    pub shared_state: bool,
+    #[arg(long, default_value_t = 4, help = "Download files of at least --multi-thread-cutoff in this many ranges at once, 1 downloads them front to back")]
+    pub multi_thread_streams: usize,
+    #[arg(long, value_parser = parse_size, default_value = "256M", help = "Size from which downloads are split into --multi-thread-streams ranges")]
+    pub multi_thread_cutoff: u64,
+}
+
+impl ApplyArgs {
+    pub fn confirm(&self) -> &'static dyn Confirm {
+        if self.interactive {
+            &TerminalConfirm
+        } else {
+            &NoConfirm
+        }
+    }
+
+    pub fn options(&self) -> SyncOptions {
+        SyncOptions {
+            transfers: self.transfers,
+            retries: self.retries,
+            order: self.order_by,
+            max_transfer: self.max_transfer,
+            max_duration: self.max_duration,
+            backup_dir: self.backup_dir.clone(),
+            suffix: self.suffix.clone(),
+            metadata: self.metadata,
+            owner: self.owner,
+            permanent: self.permanent,
+            staging_dir: self.staging_dir.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Parse a byte count with an optional binary unit suffix: `1024`, `64K`, `500M`, `2G`, `1T`
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let mult: u64 = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("Unknown size unit: {unit:?}")),
+    };
+    let num: u64 = num.parse().map_err(|e| format!("Invalid size {s:?}: {e}"))?;
+    num.checked_mul(mult).ok_or_else(|| format!("Size too large: {s:?}"))
+}
+
+/// Format a byte count with the largest binary unit it has at least one of: `1.5G`
+pub fn format_size(size: u64) -> String {
+    let (unit, scale) = [("T", 1u64 << 40), ("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)]
+        .into_iter()
+        .find(|(_, scale)| size >= *scale)
+        .unwrap_or(("", 1));
+    match scale {
+        1 => format!("{size}B"),
+        _ => format!("{:.1}{unit}", size as f64 / scale as f64),
+    }
+}
+
+/// Parse the size of resumable upload chunks, which Drive requires to be a multiple of 256 KiB
+fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    let size = parse_size(s)?;
+    if size == 0 || size % crate::gdrive::CHUNK_ALIGN != 0 {
+        return Err(format!("{s:?} is not a multiple of 256K"));
+    }
+    usize::try_from(size).map_err(|e| e.to_string())
+}
+
+/// Parse a Drive label to apply, `LABEL` or `LABEL.FIELD=VALUE` to also set one of its text
+/// fields, by their ids
+fn parse_label(s: &str) -> Result<LabelModification, String> {
+    let (key, value) = match s.split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (s, None),
+    };
+    let field = match (key.split_once('.'), value) {
+        (Some((label, field)), Some(value)) if !label.is_empty() && !field.is_empty() => Some((label, (field, value))),
+        (None, None) if !key.is_empty() => None,
+        _ => return Err(format!("Invalid label {s:?}, expected LABEL or LABEL.FIELD=VALUE")),
+    };
+    Ok(match field {
+        Some((label, field)) => LabelModification::new(label, Some(field)),
+        None => LabelModification::new(key, None),
+    })
+}
+
+/// Parse a date, `2024-01-01` for midnight UTC or an RFC 3339 timestamp
+fn parse_date(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Ok(date.and_time(NaiveTime::MIN).and_utc());
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| format!("Invalid date {s:?}, expected YYYY-MM-DD or an RFC 3339 timestamp: {e}"))
+}
+
+/// Parse a duration with a unit suffix: `90s`, `30m`, `8h`, `1d`, `2w`, plain numbers are seconds
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let mult = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("Unknown duration unit: {unit:?}")),
+    };
+    let num: u64 = num.parse().map_err(|e| format!("Invalid duration {s:?}: {e}"))?;
+    let secs = num.checked_mul(mult).ok_or_else(|| format!("Duration too long: {s:?}"))?;
+    Ok(Duration::from_secs(secs))
+}
+
+#[derive(Debug, Parser)]
+pub struct Plan {
+    #[command(flatten)]
+    pub sync: Sync,
+    #[arg(name = "output", short, long, help = "Write the plan to a file instead of stdout")]
+    pub output: Option<PathBuf>,
+}
+
+/// A plan saved to disk, along with the locations it was made for
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanFile {
+    pub src: String,
+    pub dst: String,
+    /// How the locations were opened, so that `apply` finds the planned paths
+    pub repos: RepoOptions,
+    #[serde(flatten)]
+    pub plan: SyncPlan,
+}
+
+/// Options of `sync` that change which paths the repos list, shared with `apply` through plans
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RepoOptions {
+    pub links: Links,
+    pub sandbox: bool,
+    pub sanitize_names: bool,
+    pub unicode_normalization: Normalization,
+    pub fast_list: bool,
+    /// Age after which kept Drive listings aren't updated anymore, but replaced
+    pub listing_ttl: Duration,
+    /// Formats Google documents of a Drive source are exported in, none when they're skipped
+    pub export_formats: Vec<String>,
+    pub duplicates: Duplicates,
+}
+
+#[derive(Debug, Parser)]
+pub struct Apply {
+    #[arg(name = "plan", required = true, help = "Plan created by `dsync plan`")]
+    pub plan: PathBuf,
+    #[command(flatten)]
+    pub apply: ApplyArgs,
+}
+
+#[derive(Debug, Parser)]
+pub struct Diff {
+    #[command(flatten)]
+    pub sync: Sync,
+    #[arg(long, help = "Also list identical files")]
+    pub identical: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct Check {
     #[arg(name = "src", help = "Source path")]
     pub src: PrefixedPath,
     #[arg(name = "dst", help = "Destination path")]
     pub dst: PrefixedPath,
+    #[arg(long, help = "Download files whose checksum isn't known to hash them locally")]
+    pub download: bool,
+    #[arg(long, value_enum, help = "Checksum algorithm files are compared with, the strongest one both sides provide by default")]
+    pub hash: Option<Checksum>,
+    #[arg(long, default_value_t = 8, help = "Number of files to hash at once")]
+    pub checkers: usize,
+}
+
+#[derive(Debug, Parser)]
+pub struct Dedupe {
+    #[arg(name = "path", help = "Drive folder to look for duplicates in, e.g. drive:/")]
+    pub path: PrefixedPath,
+    #[arg(long, help = "Rename older duplicate files to their suffixed names too, instead of trashing them")]
+    pub rename: bool,
+    #[arg(long, help = "Only show what would be done")]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct Share {
+    #[command(subcommand)]
+    pub command: Option<ShareCommand>,
+    #[arg(name = "path", required = true, help = "Drive file or folder to share, e.g. drive:/photos/beach.jpg")]
+    pub path: Option<PrefixedPath>,
+    #[arg(long, conflicts_with = "user", required_unless_present = "user", help = "Share with anyone who has the link")]
+    pub anyone: bool,
+    #[arg(long, value_name = "EMAIL", help = "Share with this user")]
+    pub user: Option<String>,
+    #[arg(long, value_enum, default_value_t, help = "Access that is granted")]
+    pub role: Role,
+}
+
+#[derive(Debug, Parser)]
+pub enum ShareCommand {
+    #[command(name = "list", alias = "ls", about = "List who has access to a Drive file or folder")]
+    List {
+        #[arg(name = "path", help = "Drive file or folder, e.g. drive:/photos/beach.jpg")]
+        path: PrefixedPath,
+    },
+    #[command(name = "rm", about = "Revoke access to a Drive file or folder")]
+    Rm {
+        #[arg(name = "path", help = "Drive file or folder, e.g. drive:/photos/beach.jpg")]
+        path: PrefixedPath,
+        #[arg(name = "permission", help = "Id of the permission, as shown by `dsync share list`")]
+        permission: String,
+    },
+}
+
+#[derive(Debug, Parser)]
+pub struct Revisions {
+    #[arg(name = "path", help = "Drive file, e.g. drive:/notes/todo.txt")]
+    pub path: PrefixedPath,
+}
+
+#[derive(Debug, Parser)]
+pub struct Star {
+    #[arg(name = "path", help = "Drive file or folder, e.g. drive:/contracts/2024.pdf")]
+    pub path: PrefixedPath,
+    #[arg(long, help = "Remove the star instead")]
+    pub unstar: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct Labels {
+    #[arg(name = "path", help = "Drive file or folder, e.g. drive:/contracts/2024.pdf")]
+    pub path: PrefixedPath,
+    #[arg(long, help = "Print the labels as JSON")]
+    pub json: bool,
+}
+
+#[derive(Debug, Parser)]
+#[command(group(clap::ArgGroup::new("conditions").required(true).multiple(true)
+    .args(["query", "name", "modified_after", "modified_before", "mime", "owned_by_me"])))]
+pub struct Search {
+    #[arg(name = "drive", help = "Drive to search, e.g. drive:, or a folder to only show results inside of it")]
+    pub location: PrefixedPath,
+    #[arg(long, value_name = "Q", help = "Raw Drive query, e.g. \"fullText contains 'invoice'\", combined with the other conditions")]
+    pub query: Option<String>,
+    #[arg(long, value_name = "GLOB", help = "Only show entries whose name matches, e.g. \"*.pdf\"")]
+    pub name: Option<String>,
+    #[arg(long, value_name = "DATE", value_parser = parse_date, help = "Only show entries modified after this date, e.g. 2024-01-01 or 2024-01-01T12:00:00Z")]
+    pub modified_after: Option<DateTime<Utc>>,
+    #[arg(long, value_name = "DATE", value_parser = parse_date, help = "Only show entries modified before this date")]
+    pub modified_before: Option<DateTime<Utc>>,
+    #[arg(long, value_name = "MIME", help = "Only show entries of this mime type, a trailing * matches any subtype, e.g. image/*")]
+    pub mime: Vec<String>,
+    #[arg(long, help = "Only show entries owned by the authenticated user")]
+    pub owned_by_me: bool,
+}
+
+impl Search {
+    /// Drive query for the given conditions, names are narrowed down as far as Drive allows
+    pub fn drive_query(&self) -> Option<Query> {
+        Query::all([
+            self.query.clone().map(Query::Raw),
+            self.name.as_deref().and_then(Query::name_glob),
+            self.modified_after.map(Query::modified_after),
+            self.modified_before.map(Query::modified_before),
+            Query::mime_patterns(&self.mime, &[]),
+            self.owned_by_me.then(Query::owned_by_me),
+        ].into_iter().flatten())
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct Cat {
+    #[arg(name = "path", help = "Drive file to print, e.g. drive:/notes/todo.txt")]
+    pub path: PrefixedPath,
+    #[arg(long, help = "Print this revision instead of the current contents, as shown by `dsync revisions`")]
+    pub revision: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+pub struct Cp {
+    #[arg(name = "src", help = "Drive file to download, e.g. drive:/notes/todo.txt")]
+    pub src: PrefixedPath,
+    #[arg(name = "dst", help = "Local file or directory to download it to")]
+    pub dst: PathBuf,
+    #[arg(long, help = "Download this revision instead of the current contents, as shown by `dsync revisions`")]
+    pub revision: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+pub struct About {
+    #[arg(name = "name", help = "Name of the drive, all drives when omitted")]
+    pub name: Option<String>,
+    #[arg(long, help = "Print the storage quota of each drive as JSON, by drive name")]
+    pub json: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct Undo {
+    #[arg(name = "run", help = "Run to revert, lists recorded runs when omitted")]
+    pub run: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+pub enum Filter {
+    #[command(name = "test", about = "Show whether paths would be synced, and which rule decides it")]
+    Test {
+        #[arg(name = "paths", help = "Paths relative to the root of the sync, directories end with `/`, read from stdin when omitted")]
+        paths: Vec<PathBuf>,
+        #[command(flatten)]
+        filter: FilterArgs,
+    },
 }
 
 #[derive(Debug, Parser)]
@@ -51,6 +652,24 @@ pub enum Drive {
         name: String,
         #[arg(name = "code", short, long, help = "Use code instead of browser to sign-in")]
         code: bool,
+        #[arg(long, value_name = "ID", help = "Start paths of the drive at this folder instead of the root of the account")]
+        root_folder: Option<String>,
+    },
+    #[command(name = "root", about = "Start paths of a drive at a folder, or at the root of the account again")]
+    Root {
+        #[arg(name = "name", required = true, help = "Name of the drive")]
+        name: String,
+        #[arg(name = "folder", help = "Id of the folder, e.g. from its Drive URL. Unpins the drive when omitted")]
+        folder: Option<String>,
+    },
+    #[command(name = "mime", about = "Upload files with an extension as a mime type, for types Drive misdetects")]
+    Mime {
+        #[arg(name = "name", required = true, help = "Name of the drive")]
+        name: String,
+        #[arg(name = "ext", help = "Extension of the files, e.g. gpx")]
+        ext: String,
+        #[arg(name = "mime", help = "Mime type to upload them as, e.g. application/gpx+xml. Leaves it to Drive again when omitted")]
+        mime: Option<String>,
     },
     #[command(name = "rm", alias = "del", about = "Disconnect google drive")]
     Rm {
@@ -59,10 +678,135 @@ pub enum Drive {
     },
 }
 
+#[derive(Debug, Parser)]
+pub enum Http {
+    #[command(name = "show", about = "Show the HTTP client settings")]
+    Show,
+    #[command(name = "set", about = "Change HTTP client settings, the others are kept")]
+    Set {
+        #[arg(long, value_name = "N", help = "Idle connections kept open per host, for parallel transfers to reuse")]
+        pool_max_idle: Option<usize>,
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, help = "How long idle connections are kept open, e.g. 90s")]
+        pool_idle_timeout: Option<Duration>,
+        #[arg(long, value_name = "BOOL", help = "Whether to negotiate HTTP/2, which multiplexes requests over a single connection")]
+        http2: Option<bool>,
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration, help = "Interval of TCP keepalive probes on open connections, e.g. 60s")]
+        tcp_keepalive: Option<Duration>,
+        #[arg(long, value_name = "PATH", help = "PEM file with root certificates to trust besides the built in ones, e.g. of a proxy intercepting TLS")]
+        ca_cert: Option<PathBuf>,
+    },
+    #[command(name = "reset", about = "Go back to the default HTTP client settings")]
+    Reset,
+}
+
+#[derive(Debug, Parser)]
+pub enum SharedDrives {
+    #[command(name = "list", alias = "ls", about = "List shared drives the account is a member of")]
+    List {
+        #[arg(name = "drive", help = "Name of the connected drive whose account is used")]
+        drive: String,
+    },
+    #[command(name = "create", about = "Create a shared drive and print its id")]
+    Create {
+        #[arg(name = "drive", help = "Name of the connected drive whose account is used")]
+        drive: String,
+        #[arg(name = "name", help = "Name of the new shared drive")]
+        name: String,
+    },
+    #[command(name = "rm", about = "Delete an empty shared drive")]
+    Rm {
+        #[arg(name = "drive", help = "Name of the connected drive whose account is used")]
+        drive: String,
+        #[arg(name = "id", help = "Id of the shared drive, as shown by `dsync drives list`")]
+        id: String,
+    },
+}
+
+#[derive(Debug, Parser)]
+pub struct Cli {
+    #[arg(long, global = true, value_name = "URL", help = "Proxy to reach Google through, e.g. http://proxy:3128 or socks5://localhost:1080. HTTPS_PROXY and ALL_PROXY are used without it, `dsync http set --proxy` keeps it")]
+    pub proxy: Option<String>,
+    #[arg(long, global = true, help = "DANGEROUS: accept any TLS certificate. Only for proxies intercepting TLS whose certificate can't be added with `dsync http set --ca-cert`")]
+    pub insecure: bool,
+    #[command(subcommand)]
+    pub command: Args,
+}
+
 #[derive(Debug, Parser)]
 pub enum Args {
     #[command(name = "sync")]
     Sync(Sync),
+    #[command(name = "plan", about = "Show what sync would do, without changing anything")]
+    Plan(Plan),
+    #[command(name = "apply", about = "Apply a previously created plan")]
+    Apply(Apply),
+    #[command(name = "diff", about = "Show how two locations differ, without changing anything")]
+    Diff(Diff),
+    #[command(name = "check", about = "Verify that two locations have identical contents")]
+    Check(Check),
+    #[command(name = "undo", about = "Revert the changes made by a previous run")]
+    Undo(Undo),
+    #[command(name = "dedupe", about = "Resolve Drive files and folders sharing a name, the newest one keeps it. Older files are trashed, older folders renamed")]
+    Dedupe(Dedupe),
+    #[command(name = "share", about = "Share a Drive file or folder and print its link, or manage who it's shared with")]
+    Share(Share),
+    #[command(name = "revisions", about = "List the revisions Drive keeps of a file")]
+    Revisions(Revisions),
+    #[command(name = "star", about = "Star a Drive file or folder, or unstar it")]
+    Star(Star),
+    #[command(name = "labels", about = "List the Drive labels applied to a file and the values of their fields")]
+    Labels(Labels),
+    #[command(name = "search", about = "Find Drive files and folders by name, modification time, type or a raw Drive query")]
+    Search(Search),
+    #[command(name = "cat", about = "Print the contents of a Drive file")]
+    Cat(Cat),
+    #[command(name = "cp", about = "Download a Drive file")]
+    Cp(Cp),
+    #[command(name = "about", about = "Show storage used by drives and their quota")]
+    About(About),
+    #[command(subcommand, name = "filter")]
+    Filter(Filter),
     #[command(subcommand, name = "drive")]
     Drive(Drive),
+    #[command(subcommand, name = "drives", about = "Manage shared drives of a Workspace account")]
+    Drives(SharedDrives),
+    #[command(subcommand, name = "http", about = "Tune the connections made to Drive")]
+    Http(Http),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("123"), Ok(123));
+        assert_eq!(parse_size("10K"), Ok(10 << 10));
+        assert_eq!(parse_size("1mb"), Ok(1 << 20));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert_eq!(parse_size(" 3T "), Ok(3 << 40));
+        assert!(parse_size("1X").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        assert_eq!(parse_chunk_size("256K"), Ok(256 << 10));
+        assert_eq!(parse_chunk_size("8M"), Ok(8 << 20));
+        assert!(parse_chunk_size("0").is_err());
+        assert!(parse_chunk_size("100K").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_duration("1w"), Ok(Duration::from_secs(604800)));
+        assert!(parse_duration("1y").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("999999999999999999w").is_err());
+    }
 }
@@ -0,0 +1,291 @@
+use std::path::Path;
+use anyhow::bail;
+use regex::Regex;
+
+/// Ordered include/exclude rules, with rsync-like semantics.
+///
+/// Each line of a rules file is either `+ <pattern>` to include or `- <pattern>` to exclude
+/// matching entries, empty lines and lines starting with `#` are ignored. The first matching
+/// rule decides, entries no rule matches are included. Excluded directories aren't descended
+/// into, so including files deep in the tree needs their parents to be included as well,
+/// e.g. with `+ */`.
+///
+/// Patterns:
+/// - `*` matches anything except `/`, `**` matches anything, `?` a single character except `/`
+///   and `[...]` one character from a set
+/// - a leading `/` anchors the pattern to the root of the sync, otherwise patterns containing
+///   `/` or `**` match the end of the path and others only the name of the entry
+/// - a trailing `/` matches only directories
+/// - a trailing `/***` matches the directory itself and everything inside of it
+///
+/// Regular expressions are checked before the rules, against the whole path. Directories are
+/// matched with a trailing `/`, but only files need to match one of the include regexes.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    rules: Vec<Rule>,
+    /// Regexes along with how they were specified
+    include_regex: Vec<(Regex, String)>,
+    exclude_regex: Vec<(Regex, String)>,
+}
+
+/// Outcome of filtering a single path
+pub struct Decision<'a> {
+    pub included: bool,
+    /// The rule that decided, none when the path was included because no rule matched
+    pub rule: Option<&'a str>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    /// The rule as it was written
+    text: String,
+    include: bool,
+    pattern: Vec<char>,
+    /// Matched against the whole path, instead of the end of it
+    anchored: bool,
+    /// Matched against the path, instead of only the name
+    full_path: bool,
+    dir_only: bool,
+}
+
+impl Filter {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(rules: &str) -> anyhow::Result<Self> {
+        let mut out = Self::default();
+        for (num, line) in rules.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (include, pattern) = match line.split_once(' ') {
+                Some(("+" | "include", pattern)) => (true, pattern),
+                Some(("-" | "exclude", pattern)) => (false, pattern),
+                _ => bail!("Invalid filter rule at line {}: {line:?}", num + 1),
+            };
+            out.add(include, pattern);
+        }
+        Ok(out)
+    }
+
+    /// Append a rule, it only applies to entries no previous rule matched
+    pub fn add(&mut self, include: bool, pattern: &str) {
+        let text = format!("{} {pattern}", if include { '+' } else { '-' });
+        self.push(include, pattern, text);
+    }
+
+    fn push(&mut self, include: bool, pattern: &str, text: String) {
+        if let Some(dir) = pattern.strip_suffix("/***") {
+            self.push(include, dir, text.clone());
+            self.push(include, &format!("{dir}/**"), text);
+            return;
+        }
+
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        let (pattern, anchored) = match pattern.strip_prefix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        self.rules.push(Rule {
+            text,
+            include,
+            full_path: anchored || pattern.contains('/') || pattern.contains("**"),
+            pattern: pattern.chars().collect(),
+            anchored,
+            dir_only,
+        });
+    }
+
+    /// Add a regular expression paths have to match to be included, or must not match
+    pub fn add_regex(&mut self, include: bool, pattern: &str) -> anyhow::Result<()> {
+        let regex = Regex::new(pattern)?;
+        if include {
+            self.include_regex.push((regex, format!("--include-regex {pattern}")));
+        } else {
+            self.exclude_regex.push((regex, format!("--exclude-regex {pattern}")));
+        }
+        Ok(())
+    }
+
+    /// Whether the entry at `path`, relative to the root of the sync, should be synced
+    pub fn included(&self, path: &Path, is_dir: bool) -> bool {
+        self.decide(path, is_dir).included
+    }
+
+    /// Decide whether to sync the entry at `path`, and which rule decided it
+    pub fn decide(&self, path: &Path, is_dir: bool) -> Decision<'_> {
+        let path = path.to_string_lossy();
+        let text = if is_dir { format!("{path}/") } else { path.to_string() };
+        if let Some((_, rule)) = self.exclude_regex.iter().find(|(r, _)| r.is_match(&text)) {
+            return Decision { included: false, rule: Some(rule) };
+        }
+        if !is_dir && !self.include_regex.is_empty() && !self.include_regex.iter().any(|(r, _)| r.is_match(&text)) {
+            return Decision { included: false, rule: Some("no --include-regex matches") };
+        }
+
+        let path: Vec<char> = path.chars().collect();
+        match self.rules.iter().find(|rule| rule.matches(&path, is_dir)) {
+            Some(rule) => Decision { included: rule.include, rule: Some(&rule.text) },
+            None => Decision { included: true, rule: None },
+        }
+    }
+}
+
+impl Rule {
+    fn matches(&self, path: &[char], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let mut starts = path.iter()
+            .enumerate()
+            .filter(|(_, c)| **c == '/')
+            .map(|(idx, _)| idx + 1);
+
+        if self.anchored {
+            glob(&self.pattern, path)
+        } else if self.full_path {
+            glob(&self.pattern, path) || starts.any(|idx| glob(&self.pattern, &path[idx..]))
+        } else {
+            let name = starts.next_back().unwrap_or(0);
+            glob(&self.pattern, &path[name..])
+        }
+    }
+}
+
+/// Whether `name` matches a glob pattern, with the same syntax as rule patterns
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    glob(&pattern, &name)
+}
+
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => (0..=text.len()).any(|idx| glob(rest, &text[idx..])),
+        ['*', rest @ ..] => {
+            let end = text.iter().position(|c| *c == '/').unwrap_or(text.len());
+            (0..=end).any(|idx| glob(rest, &text[idx..]))
+        }
+        ['?', rest @ ..] => matches!(text, [c, ..] if *c != '/') && glob(rest, &text[1..]),
+        ['[', class @ ..] => {
+            let Some(end) = class.iter().skip(1).position(|c| *c == ']').map(|idx| idx + 1) else {
+                return text.first() == Some(&'[') && glob(class, &text[1..]);
+            };
+            match text {
+                [c, ..] if *c != '/' && in_class(&class[..end], *c) => glob(&class[end + 1..], &text[1..]),
+                _ => false,
+            }
+        }
+        ['\\', c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+/// Match a character against the inside of a `[...]` set, like `a-z_` or `!0-9`
+fn in_class(class: &[char], c: char) -> bool {
+    let (negated, mut class) = match class {
+        ['!' | '^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    while let Some(&first) = class.first() {
+        match class {
+            [lo, '-', hi, rest @ ..] => {
+                found |= (*lo..=*hi).contains(&c);
+                class = rest;
+            }
+            [_, rest @ ..] => {
+                found |= first == c;
+                class = rest;
+            }
+            [] => unreachable!(),
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn included(filter: &Filter, path: &str) -> bool {
+        match path.strip_suffix('/') {
+            Some(dir) => filter.included(Path::new(dir), true),
+            None => filter.included(Path::new(path), false),
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let filter = Filter::parse("# comment\n\n+ *.rs\r\ninclude keep.txt\n- *.txt\nexclude target/\n").unwrap();
+        assert_eq!(filter.rules.len(), 4);
+        assert!(filter.rules[0].include && !filter.rules[2].include);
+        assert!(filter.rules[3].dir_only);
+
+        assert!(Filter::parse("* foo").is_err());
+        assert!(Filter::parse("+foo").is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let filter = Filter::parse("+ keep.txt\n- *.txt\n").unwrap();
+        assert!(included(&filter, "keep.txt"));
+        assert!(included(&filter, "dir/keep.txt"));
+        assert!(!included(&filter, "other.txt"));
+        assert!(included(&filter, "other.rs"));
+
+        let decision = filter.decide(Path::new("a/other.txt"), false);
+        assert_eq!(decision.rule, Some("- *.txt"));
+        assert_eq!(filter.decide(Path::new("a.rs"), false).rule, None);
+    }
+
+    #[test]
+    fn anchors_and_directories() {
+        let filter = Filter::parse("- /build\n- cache/\n- docs/*.md\n- /vendor/***\n").unwrap();
+        assert!(!included(&filter, "build"));
+        assert!(included(&filter, "src/build"));
+        assert!(!included(&filter, "src/cache/"));
+        assert!(included(&filter, "src/cache"));
+        assert!(!included(&filter, "docs/a.md"));
+        assert!(!included(&filter, "x/docs/a.md"));
+        assert!(included(&filter, "docs/sub/a.md"));
+        assert!(!included(&filter, "vendor/"));
+        assert!(!included(&filter, "vendor/a/b.rs"));
+        assert!(included(&filter, "src/vendor/a.rs"));
+    }
+
+    #[test]
+    fn regexes_before_rules() {
+        let mut filter = Filter::parse("+ *.log\n").unwrap();
+        filter.add_regex(false, r"^tmp/").unwrap();
+        filter.add_regex(true, r"\.(log|txt)$").unwrap();
+        assert!(!included(&filter, "tmp/a.log"));
+        assert!(!included(&filter, "tmp/"));
+        assert!(included(&filter, "a.txt"));
+        assert!(!included(&filter, "a.rs"));
+        // Directories don't need to match include regexes
+        assert!(included(&filter, "src/"));
+        assert!(filter.add_regex(true, "(").is_err());
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("*.txt", "a.txt"));
+        assert!(!glob_match("*.txt", "a/b.txt"));
+        assert!(glob_match("**.txt", "a/b.txt"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(!glob_match("a?c", "a/c"));
+        assert!(glob_match("[a-c]x", "bx"));
+        assert!(!glob_match("[!a-c]x", "bx"));
+        assert!(glob_match("[^0-9_]", "z"));
+        assert!(glob_match(r"\*", "*"));
+        assert!(!glob_match(r"\*", "a"));
+        assert!(glob_match("[ab", "[ab"));
+    }
+}
@@ -1,28 +1,35 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::future::Future;
 use std::marker::PhantomData;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use anyhow::{bail, format_err};
 use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use futures::Stream;
 use hyper::{Method, StatusCode};
 use indexmap::IndexMap;
 use oauth2::AccessToken;
-use reqwest::header::{CONTENT_TYPE, HeaderMap};
-use serde::{Deserialize, Serialize};
+use futures::future::Either;
+use futures::{Stream, StreamExt, TryStreamExt};
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_RANGE, CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue, LOCATION, RANGE, RETRY_AFTER};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde::de::DeserializeOwned;
-use tracing::info;
-use crate::repo::{Dir, Entry, FileSource, Repo};
+use tracing::{info, warn};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::query::Query;
+use crate::repo::{link_target, list_levels, relative_link, Capabilities, Checksum, RepoError, RepoResult, Entry, FileSource, Repo, EXPORTED, LINK};
+use crate::state::{key_path, PartialTransfer};
 
 /// ref: https://developers.google.com/drive/api/reference/rest/v3/drives#Drive
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Drive {
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    id: Option<String>,
+    pub id: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    name: Option<String>,
+    pub name: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     color_rgb: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -47,6 +54,215 @@ pub struct DriveList {
 }
 
 
+/// ref: https://developers.google.com/drive/api/reference/rest/v3/permissions
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Permission {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Who it's granted to: `user`, `group`, `domain` or `anyone`
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PermissionList {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_page_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    permissions: Vec<Permission>,
+}
+
+/// Access a permission grants
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Role {
+    #[default]
+    Reader,
+    Commenter,
+    Writer,
+}
+
+impl Role {
+    pub fn name(self) -> &'static str {
+        match self {
+            Role::Reader => "reader",
+            Role::Commenter => "commenter",
+            Role::Writer => "writer",
+        }
+    }
+}
+
+/// ref: https://developers.google.com/drive/api/reference/rest/v3/revisions
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Revision {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_time: Option<DateTime<Utc>>,
+    /// Drive deletes other revisions 30 days after they're replaced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_forever: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::serde_format::opt_string")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5_checksum: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_filename: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modifying_user: Option<User>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RevisionList {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_page_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    revisions: Vec<Revision>,
+}
+
+/// Label applied to a file, ref: https://developers.google.com/drive/api/reference/rest/v3/Label
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Label {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision_id: Option<String>,
+    /// Values of the label's fields, by field id
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub fields: IndexMap<String, LabelField>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelField {
+    /// Which of the value lists is set: `text`, `selection`, `integer`, `dateString` or `user`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub text: Vec<String>,
+    /// Ids of the chosen options
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub selection: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub integer: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub date_string: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user: Vec<User>,
+}
+
+impl LabelField {
+    /// Values of the field, whatever their type
+    pub fn values(&self) -> Vec<String> {
+        let users = self.user.iter().filter_map(|u| u.email_address.clone().or(u.display_name.clone()));
+        self.text.iter()
+            .chain(&self.selection)
+            .chain(&self.integer)
+            .chain(&self.date_string)
+            .cloned()
+            .chain(users)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelList {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_page_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<Label>,
+}
+
+/// Label to apply to a file, along with values of its text fields
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelModification {
+    label_id: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    field_modifications: Vec<FieldModification>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FieldModification {
+    field_id: String,
+    set_text_values: Vec<String>,
+}
+
+impl LabelModification {
+    /// Apply the label `label_id`, setting the text field `field_id` to `value` when given
+    pub fn new(label_id: &str, field: Option<(&str, &str)>) -> Self {
+        Self {
+            label_id: label_id.to_owned(),
+            field_modifications: field
+                .map(|(field_id, value)| FieldModification { field_id: field_id.to_owned(), set_text_values: vec![value.to_owned()] })
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModifyLabels {
+    label_modifications: Vec<LabelModification>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModifiedLabels {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    modified_labels: Vec<Label>,
+}
+
+/// ref: https://developers.google.com/drive/api/reference/rest/v3/about
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct About {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage_quota: Option<StorageQuota>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_address: Option<String>,
+}
+
+/// Storage used by an account, in bytes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageQuota {
+    /// Missing for accounts with unlimited storage
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::serde_format::opt_string")]
+    pub limit: Option<u64>,
+    /// Usage across Drive, Gmail and Photos
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::serde_format::opt_string")]
+    pub usage: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::serde_format::opt_string")]
+    pub usage_in_drive: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::serde_format::opt_string")]
+    pub usage_in_drive_trash: Option<u64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct FileList {
@@ -61,7 +277,7 @@ pub struct FileList {
 pub struct File {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     id: Option<String>,
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none", deserialize_with = "listed_name")]
     name: Option<String>,
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -95,6 +311,10 @@ pub struct File {
     thumbnail_link: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     icon_link: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    web_view_link: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    head_revision_id: Option<String>,
 
     #[serde(default, skip_serializing_if = "Option::is_none")]
     sha256_checksum: Option<String>,
@@ -103,25 +323,162 @@ pub struct File {
 
     #[serde(default, skip_serializing_if = "Option::is_none", with = "crate::serde_format::opt_string")]
     size: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    shortcut_details: Option<ShortcutDetails>,
+}
+
+/// ref: https://developers.google.com/drive/api/guides/shortcuts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShortcutDetails {
+    target_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_mime_type: Option<String>,
+}
+
+/// ref: https://developers.google.com/drive/api/reference/rest/v3/changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Change {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_id: Option<String>,
+    /// The file was deleted, or access to it was lost
+    #[serde(default)]
+    removed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file: Option<File>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeList {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_page_token: Option<String>,
+    /// Only set on the last page, where to continue from next time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    new_start_page_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    changes: Vec<Change>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartPageToken {
+    start_page_token: String,
+}
+
+/// Drive allows `/` in names, which can't be part of a path, so it's listed as its fullwidth form
+fn listed_name<'de, D: Deserializer<'de>>(de: D) -> Result<Option<String>, D::Error> {
+    Ok(Option::<String>::deserialize(de)?.map(|name| name.replace('/', "／")))
+}
+
+impl File {
+    /// Turn a listed file or folder into a repo entry
+    fn into_listed(self) -> Entry {
+        if self.mime_type.as_deref() == Some(FOLDER_MIME) {
+            Entry::Dir(crate::repo::Dir {
+                id: self.id.unwrap(),
+                name: self.name.unwrap(),
+            })
+        } else {
+            Entry::File(self.into_entry())
+        }
+    }
+
+    /// Checksum of the contents, computed by Drive or stored by dsync when it uploaded them. Stored
+    /// ones are only trusted while they agree with Drive's sha256, or when Drive has none.
+    fn checksum(&self, algorithm: Checksum) -> Option<String> {
+        let stored = |key: &str| self.app_properties.as_ref()?.get(key)?.as_str().map(str::to_owned);
+        let current = match &self.sha256_checksum {
+            Some(sha256) => stored(SHA256_PROPERTY).as_ref() == Some(sha256),
+            None => true,
+        };
+        match algorithm {
+            Checksum::Sha256 => self.sha256_checksum.clone().or_else(|| stored(SHA256_PROPERTY)),
+            Checksum::Md5 => self.md5_checksum.as_ref().map(|hex| algorithm.format(hex)),
+            Checksum::Blake3 => stored(BLAKE3_PROPERTY).filter(|_| current),
+        }
+    }
+
+    fn into_entry(self) -> crate::repo::File {
+        // Documents are listed as exports, which are different contents than what was uploaded
+        let shasum = match self.mime_type.as_deref().is_some_and(|m| m.starts_with(GOOGLE_APPS)) {
+            true => self.sha256_checksum.clone(),
+            false => self.checksum(Checksum::Sha256),
+        };
+        crate::repo::File {
+            id: self.id.unwrap(),
+            name: self.name.unwrap(),
+            shasum,
+            // Documents stored by Drive itself have no size
+            size: self.size.unwrap_or_default(),
+            modified: self.modified_time,
+            created: self.created_time,
+            metadata: self.properties
+                .into_iter()
+                .chain(self.app_properties)
+                .flatten()
+                .filter(|(key, _)| key != SHA256_PROPERTY && key != BLAKE3_PROPERTY)
+                .map(|(key, value)| match value {
+                    serde_json::Value::String(value) => (key, value),
+                    value => (key, value.to_string()),
+                })
+                .collect(),
+        }
+    }
 }
 
 pub struct RequestBuilder<API> {
+    base: &'static str,
     method: Method,
     path: String,
+    /// Where the request goes instead of `base` and `path`, for upload sessions
+    url: Option<String>,
+    /// Sending the request twice creates two entries, so it's only retried when Drive surely
+    /// didn't act on it
+    creates: bool,
     query: IndexMap<&'static str, serde_json::Value>,
     body: Option<serde_json::Value>,
+    /// File contents sent along with the metadata in `body`, as a multipart upload
+    content: Option<Vec<u8>>,
+    /// Mime type of `content`, when it's known
+    content_type: Option<&'static str>,
     headers: HeaderMap,
 
     _p: PhantomData<API>,
 }
 
+impl<API> Clone for RequestBuilder<API> {
+    fn clone(&self) -> Self {
+        Self {
+            base: self.base,
+            method: self.method.clone(),
+            path: self.path.clone(),
+            url: self.url.clone(),
+            creates: self.creates,
+            query: self.query.clone(),
+            body: self.body.clone(),
+            content: self.content.clone(),
+            content_type: self.content_type,
+            headers: self.headers.clone(),
+            _p: PhantomData,
+        }
+    }
+}
+
 impl<API> Default for RequestBuilder<API> {
     fn default() -> Self {
         Self {
+            base: API_BASE,
             method: Default::default(),
             path: "".to_string(),
+            url: None,
+            creates: false,
             query: Default::default(),
             body: None,
+            content: None,
+            content_type: None,
             headers: Default::default(),
             _p: Default::default(),
         }
@@ -137,55 +494,405 @@ pub trait APIListMethod {}
 pub struct NoMethod;
 
 const API_BASE: &str = "https://www.googleapis.com/drive/v3";
+const UPLOAD_BASE: &str = "https://www.googleapis.com/upload/drive/v3";
+
+/// Resumable upload chunks must be a multiple of 256 KiB
+pub const CHUNK_ALIGN: u64 = 256 * 1024;
+const UPLOAD_CHUNK_SIZE: usize = 32 * CHUNK_ALIGN as usize;
+
+/// Default size below which files are uploaded in a single request, sparing the extra request
+/// to start a resumable session
+const UPLOAD_CUTOFF: u64 = 8 << 20;
+
+/// Upper bound on concurrent transfers to a single drive, higher values mostly run into
+/// rate limiting
+const MAX_TRANSFERS: usize = 8;
+
+/// Prefix of the mime types of Docs, Sheets and other documents stored by Drive itself
+const GOOGLE_APPS: &str = "application/vnd.google-apps.";
+
+/// Formats Google documents can be exported in, as the kind of document, file extension and
+/// mime type
+const EXPORTS: &[(&str, &str, &str)] = &[
+    ("document", "docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+    ("document", "odt", "application/vnd.oasis.opendocument.text"),
+    ("document", "rtf", "application/rtf"),
+    ("document", "txt", "text/plain"),
+    ("document", "html", "text/html"),
+    ("document", "epub", "application/epub+zip"),
+    ("document", "pdf", "application/pdf"),
+    ("spreadsheet", "xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+    ("spreadsheet", "ods", "application/x-vnd.oasis.opendocument.spreadsheet"),
+    ("spreadsheet", "csv", "text/csv"),
+    ("spreadsheet", "tsv", "text/tab-separated-values"),
+    ("spreadsheet", "pdf", "application/pdf"),
+    ("presentation", "pptx", "application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+    ("presentation", "odp", "application/vnd.oasis.opendocument.presentation"),
+    ("presentation", "txt", "text/plain"),
+    ("presentation", "pdf", "application/pdf"),
+    ("drawing", "svg", "image/svg+xml"),
+    ("drawing", "png", "image/png"),
+    ("drawing", "jpg", "image/jpeg"),
+    ("drawing", "pdf", "application/pdf"),
+];
+
+/// Google documents left out of listings so far, because they can't be exported in any of the
+/// export formats
+static SKIPPED_DOCUMENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of Google documents left out of listings, for reporting after a run
+pub fn skipped_documents() -> usize {
+    SKIPPED_DOCUMENTS.load(Ordering::Relaxed)
+}
+
+/// Default age after which kept listings are replaced, in case changes were missed
+const LISTING_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const FOLDER_MIME: &str = "application/vnd.google-apps.folder";
+/// Pseudo-root holding the files and folders others shared with the account, `drive:@shared/`
+pub const SHARED: &str = "@shared";
+/// appProperties holding checksums of uploaded contents, for files Drive has no checksum of and
+/// algorithms Drive doesn't compute
+const SHA256_PROPERTY: &str = "dsync.sha256";
+const BLAKE3_PROPERTY: &str = "dsync.blake3";
+/// Folders whose contents are listed by a single query with `--fast-list`, more make queries
+/// too long
+const FOLDERS_PER_QUERY: usize = 50;
+/// Queries listing folders sent at once, the rate limit still applies
+const CONCURRENT_QUERIES: usize = 8;
+/// Hidden folder only dsync can see, holding its own files
+const APPDATA: &str = "appDataFolder";
+const SHORTCUT_MIME: &str = "application/vnd.google-apps.shortcut";
+/// Separates the file id from the revision in ids of kept versions
+const REVISION_SEPARATOR: char = '@';
+
+/// Fields needed to turn a [`File`] into a repo entry
+const FILE_FIELDS: &str = "id, name, size, sha256Checksum, mimeType, modifiedTime, createdTime, properties, appProperties, shortcutDetails, headRevisionId";
+/// Fields of uploaded files, with the checksums they're verified with
+const UPLOAD_FIELDS: &str = "id, name, size, sha256Checksum, md5Checksum, mimeType, modifiedTime, createdTime, properties, appProperties, shortcutDetails, headRevisionId";
+
+/// Requests per second sent to Drive unless configured otherwise
+pub const DEFAULT_RATE_LIMIT: f64 = 10.0;
+/// Times a rate limited or failed request is retried, waiting twice as long each time. Operations
+/// failing after that are retried as a whole up to `--retries` times.
+const MAX_BACKOFFS: u32 = 6;
+
+/// Spaces out requests to stay below a number per second, shared by every drive. Requests not
+/// made in the past second can be made at once.
+struct RateLimit {
+    /// Time between requests, none without a limit
+    interval: Option<Duration>,
+    /// When the next request may be sent
+    next: Instant,
+}
+
+static RATE_LIMIT: OnceLock<Mutex<RateLimit>> = OnceLock::new();
+
+fn rate_limit() -> &'static Mutex<RateLimit> {
+    RATE_LIMIT.get_or_init(|| Mutex::new(RateLimit {
+        interval: Some(Duration::from_secs_f64(1.0 / DEFAULT_RATE_LIMIT)),
+        next: Instant::now(),
+    }))
+}
+
+/// Send at most `qps` requests per second to Drive, 0 for no limit
+pub fn set_rate_limit(qps: f64) {
+    rate_limit().lock().unwrap().interval = (qps > 0.0).then(|| Duration::from_secs_f64(1.0 / qps));
+}
+
+/// Wait until the rate limit allows another request
+async fn throttle() {
+    let at = {
+        let mut limit = rate_limit().lock().unwrap();
+        let Some(interval) = limit.interval else {
+            return;
+        };
+        let now = Instant::now();
+        let at = limit.next.max(now.checked_sub(Duration::from_secs(1)).unwrap_or(now));
+        limit.next = at + interval;
+        at
+    };
+    tokio::time::sleep_until(at.into()).await;
+}
+
+/// How long to wait before retrying a request for the `attempt`th time, doubling each time with
+/// up to a second of jitter so that parallel requests don't retry in lockstep
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt) + Duration::from_millis(rand::random::<u64>() % 1000)
+}
+
+/// Failed attempt at a request
+enum Failure {
+    /// Unsuccessful response, along with how long Drive asked to wait before retrying
+    Api(ApiError, Option<Duration>),
+    /// The request didn't get a response
+    Connection(reqwest::Error),
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Api(e, _) => e.fmt(f),
+            Failure::Connection(e) => write!(f, "Request failed: {e}"),
+        }
+    }
+}
+
+impl Failure {
+    /// Whether the request may have been acted on despite failing
+    fn unconfirmed(&self) -> bool {
+        match self {
+            Failure::Api(e, _) => e.status.is_server_error(),
+            Failure::Connection(e) => !e.is_connect(),
+        }
+    }
+}
+
+impl From<Failure> for anyhow::Error {
+    fn from(failure: Failure) -> Self {
+        match failure {
+            Failure::Api(e, _) => e.into(),
+            Failure::Connection(e) => e.into(),
+        }
+    }
+}
+
+/// How to continue after a failed attempt
+enum Retry {
+    /// Refresh the access token first
+    Refresh,
+    /// Wait before trying again
+    Wait(Duration),
+}
+
+/// Retries of a single request: a rejected access token is refreshed once, transient errors and
+/// broken connections are backed off from exponentially or for as long as Drive asks.
+///
+/// Requests that create entries may have been acted on when they failed or timed out, they're
+/// only retried when Drive rejected them outright, otherwise they fail as [`RepoError::Unconfirmed`]
+/// so that the operation isn't retried as a whole either.
+#[derive(Default)]
+struct Retries {
+    creates: bool,
+    refreshed: bool,
+    backoffs: u32,
+}
+
+impl Retries {
+    /// How to continue after `failure`, `None` to give up
+    fn next(&mut self, failure: &Failure) -> Option<Retry> {
+        let retry_after = match failure {
+            Failure::Api(e, _) if e.status == StatusCode::UNAUTHORIZED => {
+                return (!std::mem::replace(&mut self.refreshed, true)).then_some(Retry::Refresh);
+            }
+            Failure::Api(e, retry_after) if self.creates => match (e.status, retry_after) {
+                (StatusCode::TOO_MANY_REQUESTS, _) | (StatusCode::SERVICE_UNAVAILABLE, Some(_)) => *retry_after,
+                _ => return None,
+            },
+            Failure::Api(e, retry_after) if e.transient() => *retry_after,
+            // Requests that couldn't connect were never sent
+            Failure::Connection(e) if e.is_connect() => None,
+            Failure::Connection(e) if e.is_timeout() && !self.creates => None,
+            _ => return None,
+        };
+        if self.backoffs == MAX_BACKOFFS {
+            return None;
+        }
+        let delay = retry_after.unwrap_or_else(|| backoff(self.backoffs));
+        self.backoffs += 1;
+        Some(Retry::Wait(delay))
+    }
+}
+
+/// Unsuccessful response of the Drive API
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub body: String,
+    /// Reason of the first error in Drive's error envelope, e.g. `userRateLimitExceeded`
+    pub reason: Option<String>,
+    /// Message of Drive's error envelope
+    pub message: Option<String>,
+}
+
+/// ref: https://developers.google.com/drive/api/guides/handle-errors
+#[derive(Deserialize)]
+struct ErrorEnvelope {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    errors: Vec<ErrorDetail>,
+    /// Canonical status like `RESOURCE_EXHAUSTED`, the reason of errors without details
+    #[serde(default)]
+    status: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetail {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, body: String) -> Self {
+        let error = serde_json::from_str::<ErrorEnvelope>(&body).ok().map(|e| e.error);
+        let reason = error.as_ref().and_then(|e| e.errors.iter().find_map(|d| d.reason.clone()).or_else(|| e.status.clone()));
+        let message = error.and_then(|e| e.message);
+        Self { status, body, reason, message }
+    }
+
+    /// Read the body of an unsuccessful response
+    pub async fn read(response: reqwest::Response) -> anyhow::Result<Self> {
+        let status = response.status();
+        Ok(Self::new(status, response.text().await?))
+    }
+
+    /// Server errors and rate limiting go away when retried later, anything else won't
+    pub fn transient(&self) -> bool {
+        self.status.is_server_error()
+            || self.status == StatusCode::TOO_MANY_REQUESTS
+            // Drive also rate limits with 403s
+            || matches!(self.reason.as_deref(), Some("rateLimitExceeded" | "userRateLimitExceeded"))
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.message, &self.reason) {
+            (Some(message), Some(reason)) => write!(f, "Request failed with {} ({reason}): {message}", self.status),
+            (Some(message), None) => write!(f, "Request failed with {}: {message}", self.status),
+            _ => write!(f, "Request failed with {}: {}", self.status, self.body),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// Drive computed a different checksum of uploaded contents than dsync did while sending them
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub path: PathBuf,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Upload of {:?} arrived corrupted, Drive has checksum {} instead of {}", self.path, self.actual, self.expected)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
 
 pub trait Authorizer {
     fn force_refresh(&self, client: &reqwest::Client) -> impl Future<Output=Result<AccessToken, anyhow::Error>>;
     fn token(&self, client: &reqwest::Client) -> impl Future<Output=Result<AccessToken, anyhow::Error>>;
 }
 
-impl<API: APIMethod> RequestBuilder<API> {
+impl<A: Authorizer> Authorizer for Arc<A> {
+    fn force_refresh(&self, client: &reqwest::Client) -> impl Future<Output=Result<AccessToken, anyhow::Error>> {
+        (**self).force_refresh(client)
+    }
+
+    fn token(&self, client: &reqwest::Client) -> impl Future<Output=Result<AccessToken, anyhow::Error>> {
+        (**self).token(client)
+    }
+}
+
+impl<API> RequestBuilder<API> {
     pub fn fields(mut self, fields: impl Into<String>) -> Self {
         self.query.insert("fields", fields.into().into());
         self
     }
 
-    pub async fn call<A: Authorizer>(mut self, client: &reqwest::Client, auth: &A) -> anyhow::Result<API::Response> {
-        let path = &self.path;
-        let mut force_refreshed = false;
+    pub fn header(mut self, name: HeaderName, value: impl ToString) -> Self {
+        self.headers.insert(name, HeaderValue::from_str(&value.to_string()).unwrap());
+        self
+    }
 
-        loop {
-            let mut token = auth.token(client).await?;
-            self.query.insert("access_token", token.secret().clone().into());
+    /// Declare the mime type of uploaded contents, Drive converts them into Google documents
+    /// when the file's own type is one
+    pub fn content_type(mut self, mime: &'static str) -> Self {
+        self.content_type = Some(mime);
+        self.header(HeaderName::from_static("x-upload-content-type"), mime)
+    }
 
-            let mut request = client
-                .request(self.method.clone(), format!("{API_BASE}/{path}"))
-                .headers(self.headers.clone())
-                .query(&self.query);
+    /// Perform the request. Each attempt waits for the rate limit and is authorized with the
+    /// current access token, failed ones go through [`Retries`] to decide whether to try again.
+    pub async fn send<A: Authorizer>(self, client: &reqwest::Client, auth: &A) -> anyhow::Result<reqwest::Response> {
+        let mut retries = Retries { creates: self.creates, ..Default::default() };
+        loop {
+            throttle().await;
+            let token = auth.token(client).await?;
+            let failure = match self.request(client, &token).send().await {
+                // Redirects are followed, those left are upload sessions expecting more data
+                Ok(response) if response.status().is_success() || response.status().is_redirection() => {
+                    info!("Response: {response:?}");
+                    return Ok(response);
+                }
+                Ok(response) => {
+                    let retry_after = response.headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok()?.parse().ok())
+                        .map(Duration::from_secs);
+                    Failure::Api(ApiError::read(response).await?, retry_after)
+                }
+                Err(e) => Failure::Connection(e),
+            };
+            match retries.next(&failure) {
+                Some(Retry::Refresh) => {
+                    auth.force_refresh(client).await?;
+                }
+                Some(Retry::Wait(delay)) => {
+                    warn!("{failure}, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                }
+                None if self.creates && failure.unconfirmed() => {
+                    return Err(RepoError::Unconfirmed(failure.into()).into());
+                }
+                None => return Err(failure.into()),
+            }
+        }
+    }
 
-            if let Some(body) = &self.body {
-                request = request
+    /// The request to send, authorized by `token`
+    fn request(&self, client: &reqwest::Client, token: &AccessToken) -> reqwest::RequestBuilder {
+        let url = self.url.clone().unwrap_or_else(|| format!("{}/{}", self.base, self.path));
+        let request = client
+            .request(self.method.clone(), url)
+            .headers(self.headers.clone())
+            .query(&self.query)
+            .query(&[("access_token", token.secret())]);
+
+        match (&self.body, &self.content) {
+            (Some(body), Some(content)) => {
+                let (content_type, data) = multipart(body, content, self.content_type.unwrap_or("application/octet-stream"));
+                request
+                    .body(data)
+                    .header(CONTENT_TYPE, content_type)
+            }
+            (Some(body), None) => {
+                request
                     .json(&body)
                     .header(CONTENT_TYPE, "application/json")
             }
-
-            let response = request
-                .send()
-                .await?;
-
-            info!("Response: {response:?}");
-
-            if response.status() == StatusCode::UNAUTHORIZED && !force_refreshed {
-                token = auth.force_refresh(client).await?;
-                force_refreshed = true;
-            } else if force_refreshed {
-                bail!("Probably invalid account, investigate")
-            } else {
-                return Ok(response.json().await?);
-            }
+            (None, Some(content)) => request.body(content.clone()),
+            (None, None) => request,
         }
     }
 }
 
+impl<API: APIMethod> RequestBuilder<API> {
+    pub async fn call<A: Authorizer>(self, client: &reqwest::Client, auth: &A) -> anyhow::Result<API::Response> {
+        Ok(self.send(client, auth).await?.json().await?)
+    }
+}
+
 
 impl<API: APIListMethod> RequestBuilder<API> {
     pub fn page_size(mut self, size: i32) -> Self {
@@ -204,9 +911,22 @@ impl<API: APIListMethod> RequestBuilder<API> {
     }
 }
 
-pub fn builder() -> RequestBuilder<NoMethod> {
-    RequestBuilder::default()
-}
+impl RequestBuilder<ListFiles> {
+    pub fn order_by(mut self, order: &str) -> Self {
+        self.query.insert("orderBy", order.into());
+        self
+    }
+
+    /// Spaces to list files from, `drive` unless set
+    pub fn spaces(mut self, spaces: &str) -> Self {
+        self.query.insert("spaces", spaces.into());
+        self
+    }
+}
+
+pub fn builder() -> RequestBuilder<NoMethod> {
+    RequestBuilder::default()
+}
 
 impl RequestBuilder<NoMethod> {
     pub fn files_get(self, id: impl Into<String>) -> RequestBuilder<GetFile> {
@@ -221,21 +941,106 @@ impl RequestBuilder<NoMethod> {
         RequestBuilder {
             method: Method::POST,
             path: "files".to_string(),
+            creates: true,
+            query: self.query,
+            body: Some(serde_json::to_value(file).unwrap()),
+            ..Default::default()
+        }
+    }
+    pub fn files_create_resumable(self, file: File, len: u64) -> RequestBuilder<StartUpload> {
+        RequestBuilder {
+            base: UPLOAD_BASE,
+            method: Method::POST,
+            path: "files".to_string(),
+            query: self.query,
+            body: Some(serde_json::to_value(file).unwrap()),
+            ..Default::default()
+        }
+        .upload_type("resumable")
+        .header(HeaderName::from_static("x-upload-content-length"), len)
+    }
+    pub fn files_update_resumable(self, id: String, file: File, len: u64) -> RequestBuilder<StartUpload> {
+        RequestBuilder {
+            base: UPLOAD_BASE,
+            method: Method::PATCH,
+            path: format!("files/{id}"),
+            query: self.query,
+            body: Some(serde_json::to_value(file).unwrap()),
+            ..Default::default()
+        }
+        .upload_type("resumable")
+        .header(HeaderName::from_static("x-upload-content-length"), len)
+    }
+    /// Create a file with its contents in a single request
+    pub fn files_create_multipart(self, file: File, content: Vec<u8>) -> RequestBuilder<CreateFile> {
+        let mut query = self.query;
+        query.insert("uploadType", "multipart".into());
+        RequestBuilder {
+            base: UPLOAD_BASE,
+            method: Method::POST,
+            path: "files".to_string(),
+            creates: true,
+            query,
+            body: Some(serde_json::to_value(file).unwrap()),
+            content: Some(content),
+            ..Default::default()
+        }
+    }
+    /// Replace the contents of a file in a single request
+    pub fn files_update_multipart(self, id: String, file: File, content: Vec<u8>) -> RequestBuilder<UpdateFile> {
+        let mut query = self.query;
+        query.insert("uploadType", "multipart".into());
+        RequestBuilder {
+            base: UPLOAD_BASE,
+            method: Method::PATCH,
+            path: format!("files/{id}"),
+            query,
+            body: Some(serde_json::to_value(file).unwrap()),
+            content: Some(content),
+            ..Default::default()
+        }
+    }
+    pub fn files_update(self, id: String, file: File) -> RequestBuilder<UpdateFile> {
+        RequestBuilder {
+            method: Method::PATCH,
+            path: format!("files/{id}"),
             query: self.query,
             body: Some(serde_json::to_value(file).unwrap()),
             ..Default::default()
         }
     }
+    pub fn files_download(self, id: String) -> RequestBuilder<DownloadFile> {
+        let mut query = self.query;
+        query.insert("alt", "media".into());
+        RequestBuilder {
+            method: Method::GET,
+            path: format!("files/{id}"),
+            query,
+            ..Default::default()
+        }
+    }
+    /// Download a Google document converted to `mime_type`
+    pub fn files_export(self, id: String, mime_type: &str) -> RequestBuilder<DownloadFile> {
+        let mut query = self.query;
+        query.insert("mimeType", mime_type.into());
+        RequestBuilder {
+            method: Method::GET,
+            path: format!("files/{id}/export"),
+            query,
+            ..Default::default()
+        }
+    }
     pub fn files_copy(self, id: String, to: File) -> RequestBuilder<CopyFile> {
         RequestBuilder {
             method: Method::POST,
             path: format!("files/{id}/copy"),
+            creates: true,
             query: self.query,
             body: Some(serde_json::to_value(to).unwrap()),
             ..Default::default()
         }
     }
-    pub fn files_delete(self, id: String) -> RequestBuilder<CopyFile> {
+    pub fn files_delete(self, id: String) -> RequestBuilder<DeleteFile> {
         RequestBuilder {
             method: Method::DELETE,
             path: format!("files/{id}"),
@@ -243,10 +1048,107 @@ impl RequestBuilder<NoMethod> {
             ..Default::default()
         }
     }
-    pub fn trash_empty(self) -> RequestBuilder<CopyFile> {
+
+    pub fn permissions_create(self, file_id: String, permission: Permission) -> RequestBuilder<CreatePermission> {
+        RequestBuilder {
+            method: Method::POST,
+            path: format!("files/{file_id}/permissions"),
+            creates: true,
+            query: self.query,
+            body: Some(serde_json::to_value(permission).unwrap()),
+            ..Default::default()
+        }
+    }
+    pub fn permissions_list(self, file_id: String) -> RequestBuilder<ListPermissions> {
+        RequestBuilder {
+            method: Method::GET,
+            path: format!("files/{file_id}/permissions"),
+            query: self.query,
+            ..Default::default()
+        }
+    }
+    pub fn permissions_delete(self, file_id: String, permission_id: &str) -> RequestBuilder<DeleteFile> {
         RequestBuilder {
             method: Method::DELETE,
-            path: "files/trash".to_string(),
+            path: format!("files/{file_id}/permissions/{permission_id}"),
+            query: self.query,
+            ..Default::default()
+        }
+    }
+
+    pub fn revisions_list(self, file_id: String) -> RequestBuilder<ListRevisions> {
+        RequestBuilder {
+            method: Method::GET,
+            path: format!("files/{file_id}/revisions"),
+            query: self.query,
+            ..Default::default()
+        }
+    }
+    pub fn revisions_get(self, file_id: String, revision_id: &str) -> RequestBuilder<GetRevision> {
+        RequestBuilder {
+            method: Method::GET,
+            path: format!("files/{file_id}/revisions/{revision_id}"),
+            query: self.query,
+            ..Default::default()
+        }
+    }
+    /// Send contents to the upload session at `location`, `range` is the `Content-Range` they
+    /// cover. Without contents, it asks how much the session received.
+    pub fn upload_chunk(self, location: &str, range: String, content: Vec<u8>) -> RequestBuilder<UploadChunk> {
+        RequestBuilder {
+            method: Method::PUT,
+            url: Some(location.to_owned()),
+            query: self.query,
+            content: Some(content),
+            ..Default::default()
+        }
+        .header(CONTENT_RANGE, range)
+    }
+    /// Download the contents of an older revision of a file
+    pub fn revisions_download(self, file_id: String, revision_id: &str) -> RequestBuilder<DownloadFile> {
+        let mut query = self.query;
+        query.insert("alt", "media".into());
+        RequestBuilder {
+            method: Method::GET,
+            path: format!("files/{file_id}/revisions/{revision_id}"),
+            query,
+            ..Default::default()
+        }
+    }
+    pub fn revisions_update(self, file_id: String, revision_id: &str, revision: Revision) -> RequestBuilder<UpdateRevision> {
+        RequestBuilder {
+            method: Method::PATCH,
+            path: format!("files/{file_id}/revisions/{revision_id}"),
+            query: self.query,
+            body: Some(serde_json::to_value(revision).unwrap()),
+            ..Default::default()
+        }
+    }
+
+    /// Labels applied to a file, along with the values of their fields
+    pub fn files_list_labels(self, id: String) -> RequestBuilder<ListLabels> {
+        RequestBuilder {
+            method: Method::GET,
+            path: format!("files/{id}/listLabels"),
+            query: self.query,
+            ..Default::default()
+        }
+    }
+    /// Apply, remove or change fields of labels on a file
+    pub fn files_modify_labels(self, id: String, labels: ModifyLabels) -> RequestBuilder<ModifyFileLabels> {
+        RequestBuilder {
+            method: Method::POST,
+            path: format!("files/{id}/modifyLabels"),
+            query: self.query,
+            body: Some(serde_json::to_value(labels).unwrap()),
+            ..Default::default()
+        }
+    }
+    /// Information about the account, only the requested `fields` are returned
+    pub fn about_get(self) -> RequestBuilder<GetAbout> {
+        RequestBuilder {
+            method: Method::GET,
+            path: "about".to_string(),
             query: self.query,
             ..Default::default()
         }
@@ -260,6 +1162,28 @@ impl RequestBuilder<NoMethod> {
             ..Default::default()
         }
     }
+    /// Create a shared drive, retrying with the same `request_id` doesn't create another one
+    pub fn drives_create(self, request_id: String, name: &str) -> RequestBuilder<CreateDrive> {
+        let drive = Drive { name: Some(name.to_owned()), ..Default::default() };
+        let mut query = self.query;
+        query.insert("requestId", request_id.into());
+        RequestBuilder {
+            method: Method::POST,
+            path: "drives".to_string(),
+            query,
+            body: Some(serde_json::to_value(drive).unwrap()),
+            ..Default::default()
+        }
+    }
+    /// Delete a shared drive, which has to be empty
+    pub fn drives_delete(self, id: &str) -> RequestBuilder<DeleteFile> {
+        RequestBuilder {
+            method: Method::DELETE,
+            path: format!("drives/{id}"),
+            query: self.query,
+            ..Default::default()
+        }
+    }
     pub fn files_list(self) -> RequestBuilder<ListFiles> {
         RequestBuilder {
             method: Method::GET,
@@ -268,6 +1192,24 @@ impl RequestBuilder<NoMethod> {
             ..Default::default()
         }
     }
+    /// Token of the current position in the changes feed, later changes are listed from it
+    pub fn changes_start_page_token(self) -> RequestBuilder<GetStartPageToken> {
+        RequestBuilder {
+            method: Method::GET,
+            path: "changes/startPageToken".to_string(),
+            query: self.query,
+            ..Default::default()
+        }
+    }
+    pub fn changes_list(self, page_token: impl Into<String>) -> RequestBuilder<ListChanges> {
+        RequestBuilder {
+            method: Method::GET,
+            path: "changes".to_string(),
+            query: self.query,
+            ..Default::default()
+        }
+        .page_token(page_token)
+    }
 }
 
 pub struct ListDrives;
@@ -278,6 +1220,12 @@ impl APIMethod for ListDrives {
 
 impl APIListMethod for ListDrives {}
 
+pub struct CreateDrive;
+
+impl APIMethod for CreateDrive {
+    type Response = Drive;
+}
+
 
 pub struct ListFiles;
 
@@ -287,6 +1235,74 @@ impl APIMethod for ListFiles {
 
 impl APIListMethod for ListFiles {}
 
+pub struct ListChanges;
+
+impl APIMethod for ListChanges {
+    type Response = ChangeList;
+}
+
+impl APIListMethod for ListChanges {}
+
+pub struct GetStartPageToken;
+
+impl APIMethod for GetStartPageToken {
+    type Response = StartPageToken;
+}
+
+pub struct CreatePermission;
+
+impl APIMethod for CreatePermission {
+    type Response = Permission;
+}
+
+pub struct ListPermissions;
+
+impl APIMethod for ListPermissions {
+    type Response = PermissionList;
+}
+
+impl APIListMethod for ListPermissions {}
+
+pub struct ListRevisions;
+
+impl APIMethod for ListRevisions {
+    type Response = RevisionList;
+}
+
+impl APIListMethod for ListRevisions {}
+
+pub struct GetRevision;
+
+impl APIMethod for GetRevision {
+    type Response = Revision;
+}
+
+pub struct UpdateRevision;
+
+impl APIMethod for UpdateRevision {
+    type Response = Revision;
+}
+
+pub struct ListLabels;
+
+impl APIMethod for ListLabels {
+    type Response = LabelList;
+}
+
+impl APIListMethod for ListLabels {}
+
+pub struct ModifyFileLabels;
+
+impl APIMethod for ModifyFileLabels {
+    type Response = ModifiedLabels;
+}
+
+pub struct GetAbout;
+
+impl APIMethod for GetAbout {
+    type Response = About;
+}
+
 pub struct GetFile;
 
 impl APIMethod for GetFile {
@@ -305,161 +1321,1564 @@ impl APIMethod for CopyFile {
     type Response = File;
 }
 
-pub struct GDriveRepo<A: Authorizer> {
-    auth: A,
-    root_id: String,
-    /// Directory tree
-    dirs: DashMap<PathBuf, String>,
-    fils: DashMap<PathBuf, Vec<String>>,
-    client: reqwest::Client,
-}
-
-impl<A: Authorizer> GDriveRepo<A> {
-    pub async fn new(client: &reqwest::Client, auth: A) -> anyhow::Result<Self> {
-        let root: File = builder()
-            .files_get("root")
-            .fields("id, name")
-            .call(&client, &auth)
-            .await?;
-
-        let root_id = root.id.as_deref().unwrap().to_owned();
-
-        let folders: FileList = builder()
-            .files_list()
-            .fields("files(id, size, name, parents)")
-            .query("mimeType = 'application/vnd.google-apps.folder'")
-            .call(&client, &auth)
-            .await?;
+pub struct UpdateFile;
 
-        let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
-        let mut parents: HashMap<&str, &str> = HashMap::new();
-        let mut names: HashMap<&str, &str> = HashMap::new();
+impl APIMethod for UpdateFile {
+    type Response = File;
+}
 
-        folders.files
-            .iter()
-            .for_each(|file| {
-                let par = file.parents.first().unwrap().as_str();
-                let chl = file.id.as_deref().unwrap();
+impl RequestBuilder<UpdateFile> {
+    /// Move the file from one parent folder to another
+    fn move_parents(mut self, from: &str, to: &str) -> Self {
+        self.query.insert("removeParents", from.into());
+        self.query.insert("addParents", to.into());
+        self
+    }
+}
 
-                children.entry(par)
-                    .or_default()
-                    .push(chl);
+/// Starts a resumable upload session, the session URI is returned in the `Location` header.
+pub struct StartUpload;
 
-                parents.insert(chl, par);
-                names.insert(file.id.as_deref().unwrap(), file.name.as_deref().unwrap());
-            });
+impl RequestBuilder<StartUpload> {
+    fn upload_type(mut self, kind: &str) -> Self {
+        self.query.insert("uploadType", kind.into());
+        self
+    }
+}
 
-        let mut paths = HashMap::<&str, PathBuf>::new();
+/// Contents sent to an upload session, responds with 308 until the last of them arrived.
+pub struct UploadChunk;
 
-        paths.insert(&root_id, PathBuf::from("/"));
+/// Raw file content, not JSON.
+pub struct DownloadFile;
 
-        fn add_child<'a>(
-            id: &'a str,
-            parent: &'a str,
-            names: &HashMap<&'a str, &'a str>,
-            children: &HashMap<&'a str, Vec<&'a str>>,
-            paths: &mut HashMap<&'a str, PathBuf>,
-        ) {
-            if paths.contains_key(id) {
-                return;
-            }
+/// Responds with an empty body.
+pub struct DeleteFile;
 
-            let par = paths.get(parent).cloned().unwrap();
-            paths.insert(id, par.join(names[id]));
+pub struct GDriveRepo<A: Authorizer> {
+    /// Shared with the downloads of files
+    auth: Arc<A>,
+    root_id: String,
+    /// Paths start at the root of the account, so the whole drive is listed at once
+    whole_drive: bool,
+    /// Folders resolved so far, keyed by absolute path
+    dirs: DashMap<PathBuf, String>,
+    /// Files seen while listing, keyed by absolute path
+    fils: DashMap<PathBuf, Vec<String>>,
+    /// Limits the number of uploads and downloads in flight
+    transfers: Arc<Semaphore>,
+    /// Extra condition files have to match to be listed, folders are always listed
+    query: Option<Query>,
+    /// Files smaller than this are uploaded in a single request instead of a resumable session
+    upload_cutoff: u64,
+    /// Size of the pieces resumable uploads are sent in, each is held in memory
+    chunk_size: usize,
+    /// Age after which the kept listing is replaced instead of updated with changes
+    listing_ttl: Duration,
+    /// Extensions of the formats Google documents are exported in, by preference. Documents
+    /// without one are left out.
+    export_formats: Vec<String>,
+    /// Extensions of uploaded files that are converted into Google documents
+    import_formats: Vec<String>,
+    /// Mime types uploaded files are given by their lowercase extension, instead of Drive's guess
+    mime_types: BTreeMap<String, String>,
+    /// List shortcuts as links to their targets, see [`LINK`]
+    shortcuts: bool,
+    /// What to do with entries sharing a name with a newer one in the same folder
+    duplicates: Duplicates,
+    /// Keep uploaded revisions forever
+    keep_forever: bool,
+    /// Labels applied to uploaded files
+    labels: Vec<LabelModification>,
+    /// Absolute paths of files starred once they're uploaded
+    starred: HashSet<PathBuf>,
+    /// Head revisions of files when they were listed, by id, so that updates notice files whose
+    /// contents changed since
+    revisions: DashMap<String, String>,
+    /// Duplicates listed under a suffix, keyed by the absolute path they're listed as
+    suffixed: DashMap<PathBuf, Duplicate>,
+    client: reqwest::Client,
+}
 
-            if let Some(cld) = children.get(id) {
-                for cld in cld {
-                    add_child(cld, id, names, children, paths)
+impl<A: Authorizer> GDriveRepo<A> {
+    /// Open a drive with paths starting at the folder with id `root`, or at the root of the
+    /// account
+    pub async fn new(client: &reqwest::Client, auth: A, root: Option<&str>) -> anyhow::Result<Self> {
+        let root_id = match root {
+            // Shared entries have no common parent, they're listed with a query of their own
+            Some(SHARED) => SHARED.to_string(),
+            root => {
+                let root: File = builder()
+                    .files_get(root.unwrap_or("root"))
+                    .fields("id, name, mimeType")
+                    .call(client, &auth)
+                    .await?;
+                if root.mime_type.as_deref() != Some(FOLDER_MIME) {
+                    bail!("Root {:?} of the drive isn't a folder", root.name.unwrap_or_default());
                 }
+                root.id.unwrap()
             }
-        }
-        if let Some(dirs) = children.get(root_id.as_str()) {
-            for dir in dirs {
-                add_child(dir, root_id.as_str(), &names, &children, &mut paths);
-            }
-        }
-        let dirs = paths
-            .into_iter()
-            .map(|(id, path)| (path, id.to_string()))
-            .collect();
+        };
+        // Other folders are looked up as paths reach them
+        let dirs = DashMap::new();
+        dirs.insert(PathBuf::from("/"), root_id.clone());
 
         Ok(Self {
-            auth,
+            auth: Arc::new(auth),
             root_id,
+            whole_drive: root.is_none(),
             dirs,
             fils: Default::default(),
+            transfers: Arc::new(Semaphore::new(MAX_TRANSFERS)),
+            query: None,
+            upload_cutoff: UPLOAD_CUTOFF,
+            chunk_size: UPLOAD_CHUNK_SIZE,
+            listing_ttl: LISTING_TTL,
+            export_formats: vec![],
+            import_formats: vec![],
+            mime_types: Default::default(),
+            shortcuts: false,
+            duplicates: Duplicates::default(),
+            keep_forever: false,
+            labels: vec![],
+            starred: Default::default(),
+            revisions: Default::default(),
+            suffixed: Default::default(),
             client: client.clone(),
         })
     }
-}
 
-impl<A: Authorizer> Repo for GDriveRepo<A> {
-    async fn list(&self, path: PathBuf) -> anyhow::Result<Vec<Entry>> {
-        let path = PathBuf::from("/").join(path);
-        let dir_id = self.dirs.get(&path)
-            .ok_or_else(|| format_err!("Missing dir: {path:?}"))?.clone();
+    /// Id of the folder paths start at
+    pub fn root_id(&self) -> &str {
+        &self.root_id
+    }
 
-        let mut page_token: Option<String> = None;
-        let mut files = vec![];
+    /// Start paths at the folder at `path` instead, which is created first with `create`
+    pub async fn descend(mut self, path: &Path, create: bool) -> anyhow::Result<Self> {
+        let path = path.strip_prefix("/").unwrap_or(path);
+        if path.as_os_str().is_empty() {
+            return Ok(self);
+        }
+        if create {
+            self.create_dir(path.to_owned()).await?;
+        }
+        let id = self.existing_dir(&abs(path)).await?;
+        self.root_id = id.clone();
+        self.whole_drive = false;
+        self.dirs.clear();
+        self.fils.clear();
+        self.dirs.insert(PathBuf::from("/"), id);
+        Ok(self)
+    }
 
-        loop {
-            let mut req = builder()
-                .files_list()
-                .page_size(1000);
+    /// Upload files smaller than `cutoff` in a single request, larger ones in resumable sessions
+    pub fn with_upload_cutoff(mut self, cutoff: u64) -> Self {
+        self.upload_cutoff = cutoff;
+        self
+    }
 
-            if let Some(page_token) = page_token {
-                req = req.page_token(page_token)
-            }
+    /// Send resumable uploads in pieces of `size` bytes, a multiple of [`CHUNK_ALIGN`]
+    pub fn with_chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = size;
+        self
+    }
 
-            let mut file_page: FileList = req
-                .query(format!("'{}' in parents and trashed = false", dir_id))
-                .fields("files(id, name, size, sha256Checksum, mimeType, trashed)")
-                .call(&self.client, &self.auth)
-                .await?;
+    /// List the whole drive again once the listing kept from previous runs is older than `ttl`
+    pub fn with_listing_ttl(mut self, ttl: Duration) -> Self {
+        self.listing_ttl = ttl;
+        self
+    }
 
-            page_token = file_page.next_page_token;
-            files.append(&mut file_page.files);
+    /// List Google documents as exports in the first of `formats` they support, by extension
+    pub fn with_export_formats(mut self, formats: Vec<String>) -> Self {
+        self.export_formats = formats;
+        self
+    }
 
-            if page_token.is_none() {
-                break;
+    /// Convert uploaded files with one of the extensions in `formats` into Google documents.
+    /// They're exported in the same formats, so that they're listed under their original names.
+    pub fn with_import_formats(mut self, formats: Vec<String>) -> Self {
+        for format in &formats {
+            if !self.export_formats.contains(format) {
+                self.export_formats.push(format.clone());
             }
         }
-
-        return Ok(files
-            .into_iter()
-            .map(|file| {
-                println!("{:#?}", file);
-                if file.mime_type.as_deref() == Some("application/vnd.google-apps.folder") {
-                    Entry::Dir(crate::repo::Dir {
-                        id: file.id.unwrap(),
-                        name: file.name.unwrap(),
-                    })
-                } else {
-                    Entry::File(crate::repo::File {
-                        id: file.id.unwrap(),
-                        name: file.name.unwrap(),
-                        shasum: file.sha256_checksum.unwrap(),
-                        size: file.size.unwrap(),
-                    })
-                }
-            })
-            .collect());
+        self.import_formats = formats;
+        self
     }
 
-    async fn create_dir(&self, path: PathBuf) -> anyhow::Result<()> {
-        if let Some(path) = self.dirs.get(&path) {
-            return Ok(());
-        };
-        let future = Box::pin(self.create_dir(path.parent().unwrap().to_owned()));
-        future.await?;
+    /// Upload files with these extensions as the given mime types, for types Drive misdetects
+    pub fn with_mime_types(mut self, mime_types: BTreeMap<String, String>) -> Self {
+        self.mime_types = mime_types.into_iter().map(|(ext, mime)| (ext.to_lowercase(), mime)).collect();
+        self
+    }
 
-        let parent = self.dirs.get(path.parent().unwrap()).unwrap().clone();
+    /// List shortcuts as links, and create shortcuts for links written to the drive
+    pub fn with_shortcuts(mut self, shortcuts: bool) -> Self {
+        self.shortcuts = shortcuts;
+        self
+    }
+
+    /// Keep the revisions of uploaded files forever, instead of letting Drive delete them 30 days
+    /// after they're replaced
+    pub fn with_keep_forever(mut self, keep: bool) -> Self {
+        self.keep_forever = keep;
+        self
+    }
+
+    /// Apply `labels` to uploaded files, fields of the same label are set together
+    pub fn with_labels(mut self, labels: Vec<LabelModification>) -> Self {
+        for label in labels {
+            match self.labels.iter_mut().find(|l| l.label_id == label.label_id) {
+                Some(existing) => existing.field_modifications.extend(label.field_modifications),
+                None => self.labels.push(label),
+            }
+        }
+        self
+    }
+
+    /// Star these files, by their path relative to the root, when they're uploaded
+    pub fn with_starred(mut self, paths: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.starred.extend(paths.into_iter().map(abs));
+        self
+    }
+
+    /// Set what to do with entries sharing a name with a newer one in the same folder
+    pub fn with_duplicates(mut self, duplicates: Duplicates) -> Self {
+        self.duplicates = duplicates;
+        self
+    }
+
+    /// Only list files matching a `files.list` query condition, on top of previous ones
+    pub fn restrict(mut self, condition: Query) -> Self {
+        self.query = Some(match self.query {
+            Some(query) => query.and(condition),
+            None => condition,
+        });
+        self
+    }
+}
+
+/// What to do with files and folders sharing a name with a newer one in the same Drive folder,
+/// which Drive allows but paths can't tell apart
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Duplicates {
+    /// Fail the listing
+    Error,
+    /// Only list the most recently modified one
+    #[default]
+    PickNewest,
+    /// List older ones with a suffix made from their id, `report (dup-a1b2c3d4).pdf`
+    Suffix,
+}
+
+/// Entry listed under a suffix, see [`Duplicates::Suffix`]
+#[derive(Debug, Clone)]
+pub struct Duplicate {
+    /// Path shared with the newer entry
+    pub path: PathBuf,
+    /// Path it's listed under instead
+    pub renamed: PathBuf,
+    pub folder: bool,
+}
+
+/// Name of a duplicate listed under a suffix, unique through the end of its id
+fn suffixed(name: &str, id: &str) -> String {
+    let short = &id[id.len().saturating_sub(8)..];
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem} (dup-{short}).{ext}"),
+        _ => format!("{name} (dup-{short})"),
+    }
+}
+
+/// Body of a multipart upload holding file metadata and contents, along with its content type
+fn multipart(metadata: &serde_json::Value, content: &[u8], content_type: &str) -> (String, Vec<u8>) {
+    let boundary = format!("dsync-{:016x}", rand::random::<u64>());
+    let mut body = format!("--{boundary}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{metadata}\r\n").into_bytes();
+    body.extend(format!("--{boundary}\r\nContent-Type: {content_type}\r\n\r\n").as_bytes());
+    body.extend(content);
+    body.extend(format!("\r\n--{boundary}--\r\n").as_bytes());
+    (format!("multipart/related; boundary={boundary}"), body)
+}
+
+/// Shared drives the account is a member of
+pub async fn shared_drives<A: Authorizer>(client: &reqwest::Client, auth: &A) -> anyhow::Result<Vec<Drive>> {
+    let mut drives = vec![];
+    let mut page_token = None;
+    loop {
+        let mut req = builder()
+            .drives_list()
+            .page_size(100)
+            .fields("nextPageToken, drives(id, name)");
+        if let Some(page_token) = page_token {
+            req = req.page_token(page_token);
+        }
+        let mut page = req.call(client, auth).await?;
+        drives.append(&mut page.drives);
+        page_token = match page.next_page_token {
+            Some(next) => Some(next),
+            None => return Ok(drives),
+        };
+    }
+}
+
+/// Every file of a drive as of a position in its changes feed, kept between runs so that later
+/// listings only have to fetch what changed since
+#[derive(Serialize, Deserialize)]
+struct Listing {
+    /// When the whole drive was last listed
+    listed: DateTime<Utc>,
+    token: String,
+    files: Vec<File>,
+}
+
+impl Listing {
+    fn load(root_id: &str) -> Option<Self> {
+        let data = std::fs::read(key_path("listing", root_id)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Store the listing for the next run, which has to list the whole drive if this fails
+    fn save(&self, root_id: &str) {
+        let path = key_path("listing", root_id);
+        let tmp = path.with_extension("tmp");
+        let saved = std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| std::fs::write(&tmp, serde_json::to_vec(self)?))
+            .and_then(|_| std::fs::rename(&tmp, &path));
+        if let Err(e) = saved {
+            warn!("Failed to store the listing of the drive: {e}");
+        }
+    }
+}
+
+/// Contents of a Drive file, downloaded while they're streamed
+pub struct DriveFile<A> {
+    /// Request for the whole file, or its export
+    request: RequestBuilder<DownloadFile>,
+    client: reqwest::Client,
+    auth: Arc<A>,
+    /// Unknown for exports, 0
+    len: u64,
+    /// Counts the download against the concurrent transfers until it's dropped
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<A: Authorizer> FileSource for DriveFile<A> {
+    async fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn stream(self, from: u64, chunks: usize) -> impl Stream<Item=anyhow::Result<Vec<u8>>> {
+        let DriveFile { mut request, client, auth, len, _permit: permit } = self;
+        if from > 0 {
+            // Ranges apply to the transferred body, so it can't be compressed
+            request = request
+                .header(RANGE, format!("bytes={from}-"))
+                .header(ACCEPT_ENCODING, "identity");
+        }
+        // Exports have no known length, they're always read from the start
+        let body = match from == 0 || from < len {
+            true => Either::Left(download(request, client, auth)),
+            false => Either::Right(futures::stream::empty()),
+        };
+
+        rechunk(body, chunks).map(move |chunk| {
+            let _ = &permit;
+            chunk
+        })
+    }
+
+    fn range(&self, from: u64, to: u64, chunks: usize) -> Option<impl Stream<Item=anyhow::Result<Vec<u8>>>> {
+        let request = self.request
+            .clone()
+            .header(RANGE, format!("bytes={from}-{}", to - 1))
+            .header(ACCEPT_ENCODING, "identity");
+        Some(rechunk(download(request, self.client.clone(), self.auth.clone()), chunks))
+    }
+}
+
+/// Body of the response to a download request
+fn download<A: Authorizer>(request: RequestBuilder<DownloadFile>, client: reqwest::Client, auth: Arc<A>) -> impl Stream<Item=anyhow::Result<impl AsRef<[u8]>>> {
+    futures::stream::once(async move {
+        let response = request.send(&client, &*auth).await?;
+        anyhow::Ok(response.bytes_stream().map_err(anyhow::Error::from))
+    }).try_flatten()
+}
+
+/// Regroup a stream of bytes into pieces of `size` bytes, except for the last one
+fn rechunk<B: AsRef<[u8]>>(stream: impl Stream<Item=anyhow::Result<B>>, size: usize) -> impl Stream<Item=anyhow::Result<Vec<u8>>> {
+    let size = size.max(1);
+    futures::stream::try_unfold((Box::pin(stream), vec![], false), move |(mut stream, mut buf, done)| async move {
+        if done {
+            return Ok(None);
+        }
+        while buf.len() < size {
+            match stream.try_next().await? {
+                Some(bytes) => buf.extend_from_slice(bytes.as_ref()),
+                None if buf.is_empty() => return Ok(None),
+                None => return Ok(Some((buf, (stream, vec![], true)))),
+            }
+        }
+        let rest = buf.split_off(size);
+        Ok(Some((buf, (stream, rest, false))))
+    })
+}
+
+/// Checksums of uploaded contents, computed while they're sent
+struct UploadHashes {
+    sha256: crate::repo::Hasher,
+    md5: crate::repo::Hasher,
+    blake3: crate::repo::Hasher,
+}
+
+impl UploadHashes {
+    fn new() -> Self {
+        Self { sha256: Checksum::Sha256.hasher(), md5: Checksum::Md5.hasher(), blake3: Checksum::Blake3.hasher() }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.sha256.update(data);
+        self.md5.update(data);
+        self.blake3.update(data);
+    }
+
+    fn finish(self) -> UploadDigests {
+        UploadDigests { sha256: self.sha256.finish(), md5: self.md5.finish(), blake3: self.blake3.finish() }
+    }
+}
+
+/// Checksums of uploaded contents, formatted like [`Checksum::format`]
+struct UploadDigests {
+    sha256: String,
+    md5: String,
+    blake3: String,
+}
+
+impl UploadDigests {
+    /// The appProperties to store the checksums in
+    fn properties(&self) -> IndexMap<String, serde_json::Value> {
+        IndexMap::from([
+            (SHA256_PROPERTY.to_string(), self.sha256.clone().into()),
+            (BLAKE3_PROPERTY.to_string(), self.blake3.clone().into()),
+        ])
+    }
+
+    /// Compare the checksum Drive computed of an uploaded file with ours. Documents converted
+    /// from uploads have none to compare.
+    fn verify(&self, path: &Path, file: &File) -> Result<(), ChecksumMismatch> {
+        let (expected, actual) = match (&file.sha256_checksum, &file.md5_checksum) {
+            (Some(sha256), _) => (&self.sha256, sha256.clone()),
+            (None, Some(md5)) => (&self.md5, Checksum::Md5.format(md5)),
+            (None, None) => return Ok(()),
+        };
+        match *expected == actual {
+            true => Ok(()),
+            false => Err(ChecksumMismatch { path: path.to_owned(), expected: expected.clone(), actual }),
+        }
+    }
+}
+
+enum UploadStatus {
+    /// Number of bytes the session already received
+    Received(u64),
+    Done(Box<File>),
+    Expired,
+}
+
+/// Move map entries at or below `from` to the same place below `to`
+fn move_keys<V>(map: &DashMap<PathBuf, V>, from: &Path, to: &Path) {
+    let moved: Vec<PathBuf> = map.iter()
+        .filter(|e| e.key().starts_with(from))
+        .map(|e| e.key().clone())
+        .collect();
+    for path in moved {
+        if let Some((_, value)) = map.remove(&path) {
+            map.insert(to.join(path.strip_prefix(from).unwrap()), value);
+        }
+    }
+}
+
+/// Query condition for entries directly within the folder `dir_id`
+fn children(dir_id: &str) -> Query {
+    match dir_id {
+        SHARED => Query::shared_with_me(),
+        _ => Query::parent(dir_id),
+    }
+}
+
+/// Drive paths are always absolute, repo paths are relative to the drive root
+fn abs(path: impl AsRef<Path>) -> PathBuf {
+    PathBuf::from("/").join(path)
+}
+
+impl<A: Authorizer> GDriveRepo<A> {
+    /// Upload a file in a single request, for files too small to benefit from resumable sessions
+    async fn upload_multipart(&self, path: &Path, data: impl FileSource) -> anyhow::Result<File> {
+        let modified_time = data.modified();
+        let content: Vec<Vec<u8>> = data.stream(0, UPLOAD_CHUNK_SIZE).try_collect().await?;
+        let content = content.concat();
+        let mut hashes = UploadHashes::new();
+        hashes.update(&content);
+        let digests = hashes.finish();
+        let app_properties = Some(digests.properties());
+
+        let (file, import) = self.imported(path);
+        let content_type = import.unwrap_or("application/octet-stream");
+
+        if let Some(id) = self.fils.get(path).and_then(|ids| ids.first().cloned()) {
+            self.check_unchanged(&id, path).await?;
+            let updated = builder()
+                .files_update_multipart(id, File { app_properties, modified_time, mime_type: self.mime_override(path), ..Default::default() }, content)
+                .content_type(content_type)
+                .fields(UPLOAD_FIELDS)
+                .call(&self.client, &self.auth)
+                .await?;
+            return self.verify_upload(path, updated, &digests, false).await;
+        }
+
+        let dir = path.parent().ok_or_else(|| format_err!("Invalid file path: {path:?}"))?;
+        let file = File { parents: vec![self.existing_dir(dir).await?], app_properties, modified_time, ..file };
+        let created = builder()
+            .files_create_multipart(file, content)
+            .content_type(content_type)
+            .fields(UPLOAD_FIELDS)
+            .call(&self.client, &self.auth)
+            .await?;
+        self.verify_upload(path, created, &digests, true).await
+    }
+
+    /// Fail with a conflict when the contents of file `id` changed since it was listed, instead of
+    /// overwriting someone else's edit
+    async fn check_unchanged(&self, id: &str, path: &Path) -> anyhow::Result<()> {
+        let Some(listed) = self.revisions.get(id).map(|r| r.clone()) else {
+            return Ok(());
+        };
+        let current: File = builder()
+            .files_get(id)
+            .fields("headRevisionId")
+            .call(&self.client, &self.auth)
+            .await?;
+        match current.head_revision_id {
+            Some(current) if current != listed => Err(RepoError::Conflict(format!("{path:?} was changed on Drive since it was listed")).into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check an uploaded file against the checksums of what was sent. Corrupted files that were
+    /// just created are deleted, so that retrying doesn't leave them behind as duplicates.
+    async fn verify_upload(&self, path: &Path, file: File, digests: &UploadDigests, created: bool) -> anyhow::Result<File> {
+        let Err(mismatch) = digests.verify(path, &file) else {
+            return Ok(file);
+        };
+        if let (true, Some(id)) = (created, &file.id) {
+            builder().files_delete(id.clone()).send(&self.client, &self.auth).await?;
+        }
+        Err(mismatch.into())
+    }
+
+    /// Start a resumable upload session, returning its URI
+    async fn start_upload(&self, path: &Path, len: u64, modified_time: Option<DateTime<Utc>>) -> anyhow::Result<String> {
+        let dir = path.parent().ok_or_else(|| format_err!("Invalid file path: {path:?}"))?;
+        let dir_id = self.existing_dir(dir).await?;
+        let (file, import) = self.imported(path);
+
+        let existing = self.fils.get(path).and_then(|ids| ids.first().cloned());
+
+        if let Some(id) = &existing {
+            self.check_unchanged(id, path).await?;
+        }
+        let session = match existing {
+            Some(id) => builder().files_update_resumable(id, File { modified_time, mime_type: self.mime_override(path), ..Default::default() }, len),
+            None => builder().files_create_resumable(File { parents: vec![dir_id], modified_time, ..file }, len),
+        };
+        let session = match import {
+            Some(mime) => session.content_type(mime),
+            None => session,
+        };
+
+        let session = session
+            .fields(FILE_FIELDS)
+            .send(&self.client, &self.auth)
+            .await?;
+
+        Ok(session.headers()
+            .get(LOCATION)
+            .ok_or_else(|| format_err!("Upload session without location"))?
+            .to_str()?
+            .to_string())
+    }
+
+    /// Ask an upload session how much data it has received
+    async fn upload_status(&self, location: &str, len: u64) -> anyhow::Result<UploadStatus> {
+        let response = match builder().upload_chunk(location, format!("bytes */{len}"), vec![]).send(&self.client, &self.auth).await {
+            Err(e) if e.downcast_ref::<ApiError>().is_some_and(|e| matches!(e.status, StatusCode::NOT_FOUND | StatusCode::GONE)) => {
+                return Ok(UploadStatus::Expired);
+            }
+            res => res?,
+        };
+
+        match response.status() {
+            StatusCode::PERMANENT_REDIRECT => {
+                // `Range: bytes=0-<last received byte>`, missing if nothing was received yet
+                let received = response.headers()
+                    .get(RANGE)
+                    .and_then(|r| r.to_str().ok())
+                    .and_then(|r| r.rsplit_once('-'))
+                    .and_then(|(_, end)| end.parse::<u64>().ok())
+                    .map_or(0, |end| end + 1);
+                Ok(UploadStatus::Received(received))
+            }
+            status if status.is_success() => Ok(UploadStatus::Done(Box::new(response.json().await?))),
+            status => bail!("Upload session failed with {status}: {}", response.text().await?),
+        }
+    }
+
+    async fn uploaded(&self, key: &str, path: PathBuf, uploaded: Option<File>) -> anyhow::Result<crate::repo::File> {
+        let uploaded = uploaded
+            .filter(|f| f.id.is_some())
+            .ok_or_else(|| format_err!("Upload of {path:?} did not complete"))?;
+        let uploaded = self.written(uploaded);
+
+        PartialTransfer::remove(key);
+
+        info!("Uploaded {path:?} as {}", uploaded.id);
+        // Google documents have no revisions of their own contents to keep
+        if self.keep_forever && !uploaded.metadata.contains_key(EXPORTED) {
+            self.keep_head_revision(&uploaded.id).await?;
+        }
+        if !self.labels.is_empty() {
+            builder()
+                .files_modify_labels(uploaded.id.clone(), ModifyLabels { label_modifications: self.labels.clone() })
+                .fields("modifiedLabels(id)")
+                .call(&self.client, &self.auth)
+                .await?;
+        }
+        if self.starred.contains(&path) {
+            self.update_starred(&uploaded.id, true).await?;
+        }
+        self.fils.insert(path, vec![uploaded.id.clone()]);
+
+        Ok(uploaded)
+    }
+
+    /// Keep the current revision of a file forever, instead of letting Drive delete it once it's
+    /// been replaced for a while
+    async fn keep_head_revision(&self, id: &str) -> anyhow::Result<()> {
+        let file: File = builder()
+            .files_get(id)
+            .fields("headRevisionId")
+            .call(&self.client, &self.auth)
+            .await?;
+        let Some(revision) = file.head_revision_id else {
+            return Ok(());
+        };
+        builder()
+            .revisions_update(id.to_owned(), &revision, Revision { keep_forever: Some(true), ..Default::default() })
+            .fields("id")
+            .call(&self.client, &self.auth)
+            .await?;
+        Ok(())
+    }
+
+    /// Star or unstar the file or folder at `path`
+    pub async fn set_starred(&self, path: &Path, starred: bool) -> anyhow::Result<()> {
+        let id = self.entry_id(&abs(path)).await?;
+        self.update_starred(&id, starred).await
+    }
+
+    async fn update_starred(&self, id: &str, starred: bool) -> anyhow::Result<()> {
+        let _: File = builder()
+            .files_update(id.to_owned(), File { starred: Some(starred), ..Default::default() })
+            .fields("id")
+            .call(&self.client, &self.auth)
+            .await?;
+        Ok(())
+    }
+
+    /// Labels applied to the file or folder at `path`
+    pub async fn labels(&self, path: &Path) -> anyhow::Result<Vec<Label>> {
+        let id = self.entry_id(&abs(path)).await?;
+        let mut out = vec![];
+        let mut page_token = None;
+        loop {
+            let mut req = builder().files_list_labels(id.clone());
+            if let Some(page_token) = page_token {
+                req = req.page_token(page_token);
+            }
+            let page = req.call(&self.client, &self.auth).await?;
+            out.extend(page.labels);
+            page_token = match page.next_page_token {
+                Some(next) => Some(next),
+                None => return Ok(out),
+            };
+        }
+    }
+
+    /// Files and folders matching a Drive query, along with their paths relative to the root.
+    /// Paths are `None` for results outside of the root, or that can't be synced.
+    pub async fn search(&self, query: Option<Query>) -> anyhow::Result<Vec<(Option<PathBuf>, Entry)>> {
+        let query = match query {
+            Some(query) => query.and(Query::not_trashed()),
+            None => Query::not_trashed(),
+        };
+        let mut files = vec![];
+        let mut page_token = None;
+        loop {
+            let mut req = builder()
+                .files_list()
+                .page_size(1000);
+            if let Some(page_token) = page_token {
+                req = req.page_token(page_token);
+            }
+            let mut page: FileList = req
+                .query(query.clone())
+                .fields(format!("nextPageToken, files({FILE_FIELDS}, parents)"))
+                .call(&self.client, &self.auth)
+                .await?;
+            files.append(&mut page.files);
+            page_token = match page.next_page_token {
+                Some(next) => Some(next),
+                None => break,
+            };
+        }
+
+        let mut out = vec![];
+        for file in files {
+            let parent = match file.parents.first() {
+                Some(parent) => self.path_of(parent).await?,
+                None => None,
+            };
+            let listed = self.listed(file.clone());
+            let path = parent.zip(listed.as_ref())
+                .map(|(dir, entry)| dir.join(entry.name()))
+                .and_then(|path| Some(path.strip_prefix("/").ok()?.to_owned()));
+            out.push((path, listed.unwrap_or_else(|| file.into_listed())));
+        }
+        Ok(out)
+    }
+
+    /// Copy the file with `id` to `dest`
+    async fn copy_id(&self, id: String, dest: PathBuf) -> anyhow::Result<crate::repo::File> {
+        let ddir = dest.parent().unwrap();
+        let ddir = self.existing_dir(ddir).await?;
+        let dname = dest.file_name().unwrap().to_string_lossy().to_string();
+
+        let target = File {
+            name: Some(dname),
+            parents: vec![ddir],
+            ..Default::default()
+        };
+
+        let copy = builder()
+            .files_copy(id, target)
+            .fields(FILE_FIELDS)
+            .call(&self.client, &self.auth)
+            .await?;
+        let copy = self.written(copy);
+
+        self.fils.insert(dest, vec![copy.id.clone()]);
+
+        Ok(copy)
+    }
+
+    /// Id of the file `name` in the appDataFolder
+    async fn app_data_id(&self, name: &str) -> anyhow::Result<Option<String>> {
+        let files: FileList = builder()
+            .files_list()
+            .spaces(APPDATA)
+            .fields("files(id)")
+            .query(Query::name(name).and(Query::parent(APPDATA)).and(Query::not_trashed()))
+            .order_by("modifiedTime desc")
+            .call(&self.client, &self.auth)
+            .await?;
+        Ok(files.files.into_iter().next().and_then(|f| f.id))
+    }
+
+    /// Contents of the file `name` in the appDataFolder, `None` when there is none
+    pub async fn read_app_data(&self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(id) = self.app_data_id(name).await? else {
+            return Ok(None);
+        };
+        let response = builder()
+            .files_download(id)
+            .send(&self.client, &self.auth)
+            .await?;
+        Ok(Some(response.bytes().await?.to_vec()))
+    }
+
+    /// Replace the file `name` in the appDataFolder with `content`
+    pub async fn write_app_data(&self, name: &str, content: Vec<u8>) -> anyhow::Result<()> {
+        match self.app_data_id(name).await? {
+            Some(id) => {
+                builder()
+                    .files_update_multipart(id, File::default(), content)
+                    .fields("id")
+                    .call(&self.client, &self.auth)
+                    .await?;
+            }
+            None => {
+                let file = File { name: Some(name.to_owned()), parents: vec![APPDATA.to_string()], ..Default::default() };
+                builder()
+                    .files_create_multipart(file, content)
+                    .fields("id")
+                    .call(&self.client, &self.auth)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the contents of the file at `path` as they were in an older revision, by its id
+    pub async fn read_revision(&self, path: &Path, revision: &str) -> anyhow::Result<DriveFile<A>> {
+        let id = self.file_id(&abs(path)).await?;
+        self.read_revision_of(id, revision).await
+    }
+
+    async fn read_revision_of(&self, id: String, revision: &str) -> anyhow::Result<DriveFile<A>> {
+        let found = builder()
+            .revisions_get(id.clone(), revision)
+            .fields("id, size")
+            .call(&self.client, &self.auth)
+            .await?;
+
+        let permit = self.transfers.clone().acquire_owned().await?;
+        Ok(DriveFile {
+            request: builder().revisions_download(id, revision),
+            client: self.client.clone(),
+            auth: self.auth.clone(),
+            len: found.size.unwrap_or_default(),
+            _permit: permit,
+        })
+    }
+
+    /// Every revision of the file at `path`, oldest first
+    pub async fn revisions(&self, path: &Path) -> anyhow::Result<Vec<Revision>> {
+        let id = self.file_id(&abs(path)).await?;
+        let mut out = vec![];
+        let mut page_token = None;
+        loop {
+            let mut req = builder()
+                .revisions_list(id.clone())
+                .page_size(1000)
+                .fields("nextPageToken, revisions(id, modifiedTime, size, keepForever, md5Checksum, originalFilename, lastModifyingUser(displayName, emailAddress))");
+            if let Some(page_token) = page_token {
+                req = req.page_token(page_token);
+            }
+            let page = req.call(&self.client, &self.auth).await?;
+            out.extend(page.revisions);
+            page_token = match page.next_page_token {
+                Some(next) => Some(next),
+                None => return Ok(out),
+            };
+        }
+    }
+
+    /// Add the restriction of this repo to a `files.list` condition
+    fn restricted(&self, condition: Query) -> Query {
+        match &self.query {
+            // Folders still have to be listed to sync what's inside of them
+            Some(query) => condition.and(Query::mime(FOLDER_MIME).or(query.clone())),
+            None => condition,
+        }
+    }
+
+    /// Fetch a single page of a directory listing, returning its entries and the token of the
+    /// next page. `seen` holds paths listed on previous pages.
+    async fn list_page(&self, path: &Path, page_token: Option<String>, seen: &mut HashSet<PathBuf>) -> anyhow::Result<(Vec<Entry>, Option<String>)> {
+        let dir_id = self.existing_dir(path).await?;
+
+        let mut req = builder()
+            .files_list()
+            .page_size(1000);
+
+        if let Some(page_token) = page_token {
+            req = req.page_token(page_token)
+        }
+
+        let file_page: FileList = req
+            .query(self.restricted(children(&dir_id).and(Query::not_trashed())))
+            .order_by("modifiedTime desc")
+            .fields(format!("nextPageToken, files({FILE_FIELDS}, trashed)"))
+            .call(&self.client, &self.auth)
+            .await?;
+
+        // Ids remembered from earlier listings are replaced, ones from earlier pages kept
+        let mut entries = vec![];
+        for file in file_page.files {
+            if file.id.is_none() || file.name.is_none() {
+                continue;
+            }
+            let Some(entry) = self.listed(file) else {
+                SKIPPED_DOCUMENTS.fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+            let path = path.join(entry.name());
+            let Some(entry) = self.resolve_link(&path, entry).await else {
+                continue;
+            };
+            let id = entry.id().to_owned();
+            // Entries are listed newest first, so the first one keeps the name
+            let (path, entry) = match seen.insert(path.clone()) {
+                true => (path, entry),
+                false => match self.duplicate(path.clone(), entry)? {
+                    Some(renamed) => renamed,
+                    None => {
+                        self.fils.entry(path).or_default().push(id);
+                        continue;
+                    }
+                },
+            };
+            if let Entry::Dir(..) = entry {
+                self.dirs.entry(path.clone()).or_insert_with(|| id.clone());
+            }
+            self.fils.insert(path, vec![id]);
+            entries.push(entry);
+        }
+        Ok((entries, file_page.next_page_token))
+    }
+
+    /// Resolve the id of a file, using ids remembered from listing when possible.
+    async fn file_id(&self, path: &Path) -> anyhow::Result<String> {
+        if let Some(id) = self.fils.get(path).and_then(|ids| ids.first().cloned()) {
+            return Ok(id);
+        }
+
+        let dir = path.parent().ok_or_else(|| format_err!("Invalid file path: {path:?}"))?;
+        let dir_id = self.existing_dir(dir).await?;
+        let name = path.file_name().unwrap().to_string_lossy();
+
+        let files: FileList = builder()
+            .files_list()
+            .fields("files(id, name, mimeType)")
+            .query(self.name_condition(&name).and(children(&dir_id)).and(Query::not_trashed()))
+            .order_by("modifiedTime desc")
+            .call(&self.client, &self.auth)
+            .await?;
+
+        let ids: Vec<String> = files.files
+            .into_iter()
+            .filter_map(|f| self.listed(f))
+            .filter(|e| e.name() == name)
+            .map(|e| e.id().to_owned())
+            .collect();
+        // Suffixed duplicates only get their names from listing the folder
+        if ids.is_empty() && self.duplicates == Duplicates::Suffix && name.contains(" (dup-") {
+            self.list(dir.to_owned()).await?;
+            if let Some(id) = self.fils.get(path).and_then(|ids| ids.first().cloned()) {
+                return Ok(id);
+            }
+        }
+        let id = ids.first().cloned().ok_or_else(|| RepoError::NotFound(format!("{path:?}")))?;
+        self.fils.insert(path.to_owned(), ids);
+
+        Ok(id)
+    }
+
+    /// Extension and mime type a Google document of type `mime` is exported in, `None` for other
+    /// files and documents not supporting any of the export formats
+    fn export_format(&self, mime: &str) -> Option<(&'static str, &'static str)> {
+        let kind = mime.strip_prefix(GOOGLE_APPS)?;
+        self.export_formats.iter().find_map(|format| {
+            EXPORTS.iter()
+                .find(|(k, ext, _)| *k == kind && ext == format)
+                .map(|(_, ext, export)| (*ext, *export))
+        })
+    }
+
+    /// Turn a listed file into a repo entry. Google documents are named after their export and
+    /// left out when they can't be exported. Shortcuts hold the id of their target under
+    /// [`LINK`] until it's resolved by [`Self::resolve_link`].
+    fn listed(&self, mut file: File) -> Option<Entry> {
+        if let (Some(id), Some(revision)) = (&file.id, &file.head_revision_id) {
+            self.revisions.insert(id.clone(), revision.clone());
+        }
+        let mime = file.mime_type.as_deref().unwrap_or_default();
+        if mime == FOLDER_MIME || !mime.starts_with(GOOGLE_APPS) {
+            return Some(file.into_listed());
+        }
+        if mime == SHORTCUT_MIME && self.shortcuts {
+            let target = file.shortcut_details.take()?.target_id;
+            let mut entry = file.into_entry();
+            entry.metadata.insert(LINK.to_string(), target);
+            return Some(Entry::File(entry));
+        }
+        let Some((ext, export)) = self.export_format(mime) else {
+            info!("Skipping {:?}, it can't be exported in any of the export formats", file.name.unwrap_or_default());
+            return None;
+        };
+        file.name = file.name.map(|name| format!("{name}.{ext}"));
+        let mut entry = file.into_entry();
+        entry.metadata.insert(EXPORTED.to_string(), export.to_string());
+        Some(Entry::File(entry))
+    }
+
+    /// Metadata of a new file uploaded to `path`, along with the mime type of its contents when
+    /// they're converted into a Google document
+    fn imported(&self, path: &Path) -> (File, Option<&'static str>) {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let import = name.rsplit_once('.')
+            .filter(|(_, ext)| self.import_formats.iter().any(|format| format == ext))
+            .and_then(|(stem, ext)| {
+                let (kind, _, mime) = EXPORTS.iter().find(|(_, e, _)| *e == ext)?;
+                Some((stem, kind, *mime))
+            });
+        match import {
+            Some((stem, kind, mime)) => (File {
+                name: Some(stem.to_owned()),
+                mime_type: Some(format!("{GOOGLE_APPS}{kind}")),
+                ..Default::default()
+            }, Some(mime)),
+            None => (File { mime_type: self.mime_override(path), name: Some(name), ..Default::default() }, None),
+        }
+    }
+
+    /// Mime type configured for the extension of `path`, if any
+    fn mime_override(&self, path: &Path) -> Option<String> {
+        let ext = path.extension()?.to_string_lossy().to_lowercase();
+        self.mime_types.get(&ext).cloned()
+    }
+
+    /// Turn a file returned by a change into a repo file, named the way it's listed
+    fn written(&self, file: File) -> crate::repo::File {
+        match self.listed(file.clone()) {
+            Some(Entry::File(file)) => file,
+            _ => file.into_entry(),
+        }
+    }
+
+    /// Query condition for entries listed as `name`, including documents exported under it
+    fn name_condition(&self, name: &str) -> Query {
+        let exported = name.rsplit_once('.')
+            .filter(|(_, ext)| self.export_formats.iter().any(|format| format == ext));
+        match exported {
+            Some((stem, _)) => Query::name(name).or(Query::name(stem).and(Query::mime_contains(GOOGLE_APPS))),
+            None => Query::name(name),
+        }
+    }
+
+    /// Apply the duplicate policy to an entry listed at `path` after a newer one with the same
+    /// name, returning the path and entry it's listed as instead, if at all
+    fn duplicate(&self, path: PathBuf, mut entry: Entry) -> anyhow::Result<Option<(PathBuf, Entry)>> {
+        match self.duplicates {
+            Duplicates::Error => Err(RepoError::Conflict(format!(
+                "{path:?} exists more than once on Drive, resolve it with `dsync dedupe` or pass --duplicates"
+            )).into()),
+            Duplicates::PickNewest => {
+                info!("Skipping {path:?} ({}), a newer entry has the same name", entry.id());
+                Ok(None)
+            }
+            Duplicates::Suffix => {
+                let name = suffixed(entry.name(), entry.id());
+                let renamed = path.with_file_name(&name);
+                let folder = matches!(entry, Entry::Dir(..));
+                match &mut entry {
+                    Entry::Dir(dir) => dir.name = name,
+                    Entry::File(file) => file.name = name,
+                }
+                info!("Listing {path:?} ({}) as {renamed:?}, a newer entry has the same name", entry.id());
+                self.suffixed.insert(renamed.clone(), Duplicate { path, renamed: renamed.clone(), folder });
+                Ok(Some((renamed, entry)))
+            }
+        }
+    }
+
+    /// Entries listed under a suffix so far, sorted by path
+    pub fn duplicates(&self) -> Vec<Duplicate> {
+        let mut duplicates: Vec<Duplicate> = self.suffixed.iter()
+            .map(|dup| Duplicate {
+                path: dup.path.strip_prefix("/").unwrap().to_owned(),
+                renamed: dup.renamed.strip_prefix("/").unwrap().to_owned(),
+                folder: dup.folder,
+            })
+            .collect();
+        duplicates.sort_by(|a, b| a.renamed.cmp(&b.renamed));
+        duplicates
+    }
+
+    /// Grant access to the file or folder at `path`, returning the granted permission along
+    /// with the link to open the entry
+    pub async fn share(&self, path: &Path, permission: Permission) -> anyhow::Result<(Permission, Option<String>)> {
+        let id = self.entry_id(&abs(path)).await?;
+        let permission = builder()
+            .permissions_create(id.clone(), permission)
+            .fields("id, type, role, emailAddress, domain, displayName")
+            .call(&self.client, &self.auth)
+            .await?;
+        let file = builder()
+            .files_get(id)
+            .fields("webViewLink")
+            .call(&self.client, &self.auth)
+            .await?;
+        Ok((permission, file.web_view_link))
+    }
+
+    /// Everyone having access to the file or folder at `path`
+    pub async fn permissions(&self, path: &Path) -> anyhow::Result<Vec<Permission>> {
+        let id = self.entry_id(&abs(path)).await?;
+        let mut out = vec![];
+        let mut page_token = None;
+        loop {
+            let mut req = builder()
+                .permissions_list(id.clone())
+                .page_size(100)
+                .fields("nextPageToken, permissions(id, type, role, emailAddress, domain, displayName)");
+            if let Some(page_token) = page_token {
+                req = req.page_token(page_token);
+            }
+            let page = req.call(&self.client, &self.auth).await?;
+            out.extend(page.permissions);
+            page_token = match page.next_page_token {
+                Some(next) => Some(next),
+                None => return Ok(out),
+            };
+        }
+    }
+
+    /// Revoke a permission of the file or folder at `path`, by its id
+    pub async fn unshare(&self, path: &Path, permission_id: &str) -> anyhow::Result<()> {
+        let id = self.entry_id(&abs(path)).await?;
+        builder()
+            .permissions_delete(id, permission_id)
+            .send(&self.client, &self.auth)
+            .await?;
+        Ok(())
+    }
+
+    /// Absolute path of the entry with `id`, `None` when it isn't within the drive
+    async fn path_of(&self, id: &str) -> anyhow::Result<Option<PathBuf>> {
+        let known = self.dirs.iter()
+            .find(|dir| dir.value() == id)
+            .map(|dir| dir.key().clone())
+            .or_else(|| self.fils.iter().find(|f| f.value().iter().any(|i| i == id)).map(|f| f.key().clone()));
+        if known.is_some() {
+            return Ok(known);
+        }
+
+        let file: File = builder()
+            .files_get(id)
+            .fields(format!("{FILE_FIELDS}, parents"))
+            .call(&self.client, &self.auth)
+            .await?;
+        let Some(parent) = file.parents.first().cloned() else {
+            return Ok(None);
+        };
+        let is_folder = file.mime_type.as_deref() == Some(FOLDER_MIME);
+        let Some(dir) = Box::pin(self.path_of(&parent)).await? else {
+            return Ok(None);
+        };
+        let path = self.listed(file).map(|entry| dir.join(entry.name()));
+        if let Some(path) = &path {
+            if is_folder {
+                self.dirs.insert(path.clone(), id.to_owned());
+            }
+        }
+        Ok(path)
+    }
+
+    /// Replace the target id of a shortcut listed at `path` with the path of its target,
+    /// relative to the shortcut. `None` when the target can't be found.
+    async fn resolve_link(&self, path: &Path, mut entry: Entry) -> Option<Entry> {
+        let Entry::File(file) = &mut entry else {
+            return Some(entry);
+        };
+        let Some(id) = file.metadata.get(LINK) else {
+            return Some(entry);
+        };
+        let target = match self.path_of(id).await {
+            Ok(Some(target)) => target,
+            Ok(None) => {
+                info!("Skipping shortcut {path:?}, its target isn't within the drive");
+                return None;
+            }
+            Err(e) => {
+                warn!("Skipping shortcut {path:?}, failed to resolve its target: {e:#}");
+                return None;
+            }
+        };
+        let dir = path.parent().unwrap().strip_prefix("/").unwrap();
+        let target = relative_link(dir, target.strip_prefix("/").unwrap());
+        file.metadata.insert(LINK.to_string(), target.to_string_lossy().into_owned());
+        Some(entry)
+    }
+
+    /// Resolve the id of a file or folder
+    async fn entry_id(&self, path: &Path) -> anyhow::Result<String> {
+        match self.dir_id(path).await? {
+            Some(id) => Ok(id),
+            None => self.file_id(path).await,
+        }
+    }
+
+    /// Resolve the id of a folder, looking up each part of its path that wasn't seen yet.
+    /// `None` when there's no such folder.
+    async fn dir_id(&self, path: &Path) -> anyhow::Result<Option<String>> {
+        if let Some(id) = self.dirs.get(path) {
+            return Ok(Some(id.clone()));
+        }
+        let Some(dir) = path.parent() else {
+            return Ok(None);
+        };
+        let Some(dir_id) = Box::pin(self.dir_id(dir)).await? else {
+            return Ok(None);
+        };
+        let name = path.file_name().unwrap().to_string_lossy();
+
+        let folders: FileList = builder()
+            .files_list()
+            .fields("files(id)")
+            .query(Query::name(&name).and(children(&dir_id)).and(Query::mime(FOLDER_MIME)).and(Query::not_trashed()))
+            .order_by("modifiedTime desc")
+            .call(&self.client, &self.auth)
+            .await?;
+
+        let Some(id) = folders.files.into_iter().find_map(|f| f.id) else {
+            return Ok(None);
+        };
+        self.dirs.insert(path.to_owned(), id.clone());
+        Ok(Some(id))
+    }
+
+    /// Resolve the id of a folder that has to exist
+    async fn existing_dir(&self, path: &Path) -> anyhow::Result<String> {
+        self.dir_id(path).await?.ok_or_else(|| RepoError::NotFound(format!("{path:?}")).into())
+    }
+
+    /// Every file and folder in the drive, updated with the changes since the previous listing
+    /// when there is one
+    async fn all_files(&self) -> anyhow::Result<Vec<File>> {
+        // Changes can't be filtered by a query, so restricted listings are always complete
+        if self.query.is_some() {
+            return self.list_all().await;
+        }
+        let kept = Listing::load(&self.root_id)
+            .filter(|listing| (Utc::now() - listing.listed).to_std().is_ok_and(|age| age < self.listing_ttl));
+        if let Some(listing) = kept {
+            match self.apply_changes(listing).await {
+                Ok(files) => return Ok(files),
+                Err(e) => warn!("Listing the whole drive, changes since the previous listing are unavailable: {e:#}"),
+            }
+        }
+
+        // Taken before listing, so that changes made while listing are seen next time
+        let listed = Utc::now();
+        let token: StartPageToken = builder()
+            .changes_start_page_token()
+            .call(&self.client, &self.auth)
+            .await?;
+        let listing = Listing { listed, token: token.start_page_token, files: self.list_all().await? };
+        listing.save(&self.root_id);
+        Ok(listing.files)
+    }
+
+    /// List every file and folder in the drive, matching the query if there's one
+    async fn list_all(&self) -> anyhow::Result<Vec<File>> {
+        let mut page_token: Option<String> = None;
+        let mut files = vec![];
+        loop {
+            let mut req = builder()
+                .files_list()
+                .page_size(1000);
+            if let Some(page_token) = page_token {
+                req = req.page_token(page_token)
+            }
+            let mut file_page: FileList = req
+                .query(self.restricted(Query::not_trashed()))
+                .fields(format!("nextPageToken, files({FILE_FIELDS}, parents)"))
+                .call(&self.client, &self.auth)
+                .await?;
+
+            page_token = file_page.next_page_token;
+            files.append(&mut file_page.files);
+            if page_token.is_none() {
+                return Ok(files);
+            }
+        }
+    }
+
+    /// Every file and folder below the folder `root_id`, a level of the tree at a time. Folders of
+    /// each level are listed together, a batch of them per query with batches listed concurrently.
+    async fn list_tree(&self, root_id: &str) -> anyhow::Result<Vec<File>> {
+        let mut files = vec![];
+        let mut level = vec![root_id.to_owned()];
+        while !level.is_empty() {
+            let listed: Vec<Vec<File>> = futures::stream::iter(level.chunks(FOLDERS_PER_QUERY))
+                .map(|batch| self.list_children(batch))
+                .buffer_unordered(CONCURRENT_QUERIES)
+                .try_collect()
+                .await?;
+            level = listed.iter()
+                .flatten()
+                .filter(|file| file.mime_type.as_deref() == Some(FOLDER_MIME))
+                .filter_map(|file| file.id.clone())
+                .collect();
+            files.extend(listed.into_iter().flatten());
+        }
+        Ok(files)
+    }
+
+    /// Every file and folder directly within any of the folders `dir_ids`
+    async fn list_children(&self, dir_ids: &[String]) -> anyhow::Result<Vec<File>> {
+        let parents = Query::any(dir_ids.iter().map(|id| children(id))).unwrap();
+        let query = self.restricted(parents.and(Query::not_trashed()));
+        let mut files = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut req = builder()
+                .files_list()
+                .page_size(1000);
+            if let Some(page_token) = page_token {
+                req = req.page_token(page_token)
+            }
+            let mut page: FileList = req
+                .query(query.clone())
+                .fields(format!("nextPageToken, files({FILE_FIELDS}, parents)"))
+                .call(&self.client, &self.auth)
+                .await?;
+
+            files.append(&mut page.files);
+            page_token = page.next_page_token;
+            if page_token.is_none() {
+                return Ok(files);
+            }
+        }
+    }
+
+    /// Bring a previous listing up to date with the changes feed
+    async fn apply_changes(&self, listing: Listing) -> anyhow::Result<Vec<File>> {
+        let mut files: HashMap<String, File> = listing.files
+            .into_iter()
+            .filter_map(|file| Some((file.id.clone()?, file)))
+            .collect();
+
+        let mut page_token = listing.token;
+        let mut count = 0;
+        let token = loop {
+            let page: ChangeList = builder()
+                .changes_list(page_token)
+                .page_size(1000)
+                .fields(format!("nextPageToken, newStartPageToken, changes(fileId, removed, file({FILE_FIELDS}, parents, trashed))"))
+                .call(&self.client, &self.auth)
+                .await?;
+
+            count += page.changes.len();
+            for change in page.changes {
+                let Some(id) = change.file_id else {
+                    continue;
+                };
+                match change.file {
+                    Some(file) if !change.removed && file.trashed != Some(true) => {
+                        files.insert(id, file);
+                    }
+                    _ => {
+                        files.remove(&id);
+                    }
+                }
+            }
+            match (page.next_page_token, page.new_start_page_token) {
+                (Some(next), _) => page_token = next,
+                (None, Some(token)) => break token,
+                (None, None) => bail!("Changes feed ended without a token to continue from"),
+            }
+        };
+        info!("Listed {count} change(s) since the previous listing");
+
+        let listing = Listing { listed: listing.listed, token, files: files.into_values().collect() };
+        listing.save(&self.root_id);
+        Ok(listing.files)
+    }
+
+    /// Drop remembered ids of a removed entry, along with its whole subtree for folders
+    fn forget(&self, path: &Path) {
+        self.dirs.retain(|p, _| !p.starts_with(path));
+        self.fils.retain(|p, _| !p.starts_with(path));
+    }
+}
+
+impl<A: Authorizer> Repo for GDriveRepo<A> {
+    type Source = DriveFile<A>;
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            server_copy: true,
+            server_move: true,
+            checksums: &[Checksum::Sha256, Checksum::Md5],
+            set_modified: true,
+            // Upload sessions are started with the length of the file
+            unknown_length: false,
+        }
+    }
+
+    async fn list(&self, path: PathBuf) -> RepoResult<Vec<Entry>> {
+        self.list_stream(path).try_collect().await
+    }
+
+    fn list_stream(&self, path: PathBuf) -> impl Stream<Item=RepoResult<Entry>> {
+        let path = abs(path);
+        // Next page to fetch along with paths already seen, `None` after the last page
+        let start = Some((None, HashSet::new()));
+        futures::stream::try_unfold(start, move |state| {
+            let path = path.clone();
+            async move {
+                let Some((page_token, mut seen)) = state else {
+                    return anyhow::Ok(None);
+                };
+                let (entries, next) = self.list_page(&path, page_token, &mut seen).await?;
+                let state = next.map(|next| (Some(next), seen));
+                Ok(Some((futures::stream::iter(entries.into_iter().map(Ok)), state)))
+            }
+        }).try_flatten()
+    }
+
+    /// Lists every file of the drive and keeps ones within `path`, fewer requests than
+    /// listing each directory on its own
+    async fn list_recursive(&self, path: PathBuf) -> RepoResult<Vec<(PathBuf, Entry)>> {
+        // Parents of shared entries aren't within the drive, so they're listed one folder at a time
+        if self.root_id == SHARED {
+            return list_levels(self, path).await;
+        }
+        let root = abs(path);
+        // Folders within a drive are better off listed on their own than along with everything else
+        let mut files = match self.whole_drive && root == Path::new("/") {
+            true => self.all_files().await?,
+            false => self.list_tree(&self.existing_dir(&root).await?).await?,
+        };
+        // Newest first, so that it's the one keeping the name among duplicates
+        files.sort_by_key(|f| std::cmp::Reverse(f.modified_time));
+
+        // Folders are resolved from the ones already known, parents first
+        let mut paths: HashMap<String, PathBuf> = self.dirs.iter()
+            .map(|dir| (dir.value().clone(), dir.key().clone()))
+            .collect();
+        let mut taken: HashMap<PathBuf, String> = paths.iter().map(|(id, path)| (path.clone(), id.clone())).collect();
+        let (mut folders, files): (Vec<_>, Vec<_>) = files.into_iter()
+            .filter(|f| f.id.is_some() && f.name.is_some())
+            .partition(|f| f.mime_type.as_deref() == Some(FOLDER_MIME));
+        let mut failed = None;
+        loop {
+            let before = folders.len();
+            folders.retain(|folder| {
+                let Some(parent) = folder.parents.first().and_then(|p| paths.get(p)).cloned() else {
+                    return true;
+                };
+                let id = folder.id.clone().unwrap();
+                let mut path = parent.join(folder.name.as_deref().unwrap());
+                if taken.get(&path).is_some_and(|other| *other != id) {
+                    // Duplicates outside of the listed tree don't matter
+                    if !path.starts_with(&root) {
+                        return false;
+                    }
+                    let dir = Entry::Dir(crate::repo::Dir { id: id.clone(), name: folder.name.clone().unwrap() });
+                    match self.duplicate(path, dir) {
+                        Ok(Some((renamed, _))) => path = renamed,
+                        Ok(None) => return false,
+                        Err(e) => {
+                            failed = Some(e);
+                            return false;
+                        }
+                    }
+                }
+                // Only folders within the listed tree are kept for later
+                if path.starts_with(&root) {
+                    self.dirs.insert(path.clone(), id.clone());
+                }
+                taken.insert(path.clone(), id.clone());
+                paths.insert(id, path);
+                false
+            });
+            if folders.len() == before {
+                break;
+            }
+        }
+        if let Some(e) = failed {
+            return Err(e.into());
+        }
+
+        let mut fils = HashMap::<PathBuf, Vec<String>>::new();
+        let mut out = vec![];
+        for (id, path) in &paths {
+            if path != &root && path.starts_with(&root) {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                out.push((path.clone(), Entry::Dir(crate::repo::Dir { id: id.clone(), name })));
+            }
+        }
+        for file in files {
+            let Some(parent) = file.parents.first().and_then(|p| paths.get(p)).cloned() else {
+                continue;
+            };
+            let Some(entry) = self.listed(file) else {
+                SKIPPED_DOCUMENTS.fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+            let path = parent.join(entry.name());
+            if !path.starts_with(&root) {
+                continue;
+            }
+            let (path, entry) = match fils.contains_key(&path) || taken.contains_key(&path) {
+                false => (path, entry),
+                true => match self.duplicate(path.clone(), entry.clone())? {
+                    Some(renamed) => renamed,
+                    None => {
+                        fils.entry(path).or_default().push(entry.id().to_owned());
+                        continue;
+                    }
+                },
+            };
+            fils.entry(path.clone()).or_default().push(entry.id().to_owned());
+            out.push((path, entry));
+        }
+        for (path, ids) in fils {
+            self.fils.insert(path, ids);
+        }
+
+        let mut resolved = vec![];
+        for (path, entry) in out {
+            if let Some(entry) = self.resolve_link(&path, entry).await {
+                resolved.push((path.strip_prefix("/").unwrap().to_owned(), entry));
+            }
+        }
+        Ok(resolved)
+    }
+
+    async fn stat(&self, path: PathBuf) -> RepoResult<Option<Entry>> {
+        let path = abs(path);
+        let Some(dir) = path.parent() else {
+            return Ok(Some(Entry::Dir(crate::repo::Dir { id: self.root_id.clone(), name: String::new() })));
+        };
+        let Some(dir_id) = self.dir_id(dir).await? else {
+            return Ok(None);
+        };
+        let name = path.file_name().unwrap().to_string_lossy();
+
+        let files: FileList = builder()
+            .files_list()
+            .fields(format!("files({FILE_FIELDS})"))
+            .query(self.name_condition(&name).and(children(&dir_id)).and(Query::not_trashed()))
+            .order_by("modifiedTime desc")
+            .call(&self.client, &self.auth)
+            .await?;
+
+        match files.files.into_iter().filter_map(|f| self.listed(f)).find(|e| e.name() == name) {
+            Some(entry) => Ok(self.resolve_link(&path, entry).await),
+            None => Ok(None),
+        }
+    }
+
+    async fn create_dir(&self, path: PathBuf) -> RepoResult<()> {
+        let path = abs(path);
+        if self.dir_id(&path).await?.is_some() {
+            return Ok(());
+        };
+        let future = Box::pin(self.create_dir(path.parent().unwrap().to_owned()));
+        future.await?;
+
+        let parent = self.existing_dir(path.parent().unwrap()).await?;
 
         let name = path.file_name().unwrap().to_string_lossy().to_string();
 
         let file = File {
             name: Some(name),
-            mime_type: Some("application/vnd.google-apps.folder".to_string()),
+            mime_type: Some(FOLDER_MIME.to_string()),
             parents: vec![parent],
             ..Default::default()
         };
@@ -474,52 +2893,425 @@ impl<A: Authorizer> Repo for GDriveRepo<A> {
 
         info!("DIRS: {:#?}", self.dirs);
 
-        return Ok(());
+        Ok(())
+    }
+
+    async fn read_file(&self, path: PathBuf) -> RepoResult<DriveFile<A>> {
+        let path = abs(path);
+        let id = self.file_id(&path).await?;
+
+        let file: File = builder()
+            .files_get(id.clone())
+            .fields("size, mimeType")
+            .call(&self.client, &self.auth)
+            .await?;
+
+        let permit = self.transfers.clone().acquire_owned().await?;
+        let request = match file.mime_type.as_deref().and_then(|mime| self.export_format(mime)) {
+            Some((_, export)) => builder().files_export(id, export),
+            None => builder().files_download(id),
+        };
+        Ok(DriveFile {
+            request,
+            client: self.client.clone(),
+            auth: self.auth.clone(),
+            len: file.size.unwrap_or_default(),
+            _permit: permit,
+        })
     }
 
-    async fn write_file(&self, path: PathBuf, data: impl FileSource) -> anyhow::Result<()> {
+    async fn write_file(&self, path: PathBuf, data: impl FileSource) -> RepoResult<crate::repo::File> {
+        let path = abs(path);
         let len = data.len().await;
+        let _permit = self.transfers.acquire().await?;
+
+        // Upload sessions stay valid for a week, continue the previous one if we were interrupted
+        let key = format!("{}:{}", self.root_id, path.display());
+        if len < self.upload_cutoff {
+            let uploaded = self.upload_multipart(&path, data).await?;
+            return Ok(self.uploaded(&key, path, Some(uploaded)).await?);
+        }
+        let mut resumed = None;
+        if let Some(partial) = PartialTransfer::load(&key).filter(|p| p.len == len) {
+            match self.upload_status(&partial.location, len).await? {
+                UploadStatus::Received(offset) => {
+                    info!("Resuming upload of {path:?} at {offset} of {len} bytes");
+                    resumed = Some((partial.location, offset));
+                }
+                UploadStatus::Done(file) => return Ok(self.uploaded(&key, path, Some(*file)).await?),
+                UploadStatus::Expired => {}
+            }
+        }
+
+        let (location, mut offset) = match resumed {
+            Some(resumed) => resumed,
+            None => {
+                let location = self.start_upload(&path, len, data.modified()).await?;
+                PartialTransfer { location: location.clone(), len }.save(&key)?;
+                (location, 0)
+            }
+        };
+
+        let stream = data.stream(offset, self.chunk_size);
+        futures::pin_mut!(stream);
+
+        let mut uploaded = None;
+        let created = self.fils.get(&path).is_none();
+        // Contents sent by an earlier run aren't seen, so resumed uploads aren't hashed
+        let mut hashes = (offset == 0).then(UploadHashes::new);
+
+        while let Some(chunk) = stream.try_next().await? {
+            let end = offset + chunk.len() as u64;
+            if let Some(hashes) = &mut hashes {
+                hashes.update(&chunk);
+            }
+
+            let response = builder()
+                .upload_chunk(&location, format!("bytes {}-{}/{}", offset, end - 1, len), chunk)
+                .send(&self.client, &self.auth)
+                .await?;
+            if response.status().is_success() {
+                uploaded = Some(response.json::<File>().await?);
+            }
+
+            offset = end;
+        }
+
+        if len == 0 {
+            if let UploadStatus::Done(file) = self.upload_status(&location, len).await? {
+                uploaded = Some(*file);
+            }
+        }
+        if let (Some(hashes), Some(id)) = (hashes, uploaded.as_ref().and_then(|f| f.id.clone())) {
+            let digests = hashes.finish();
+            let updated = builder()
+                .files_update(id, File { app_properties: Some(digests.properties()), ..Default::default() })
+                .fields(UPLOAD_FIELDS)
+                .call(&self.client, &self.auth)
+                .await?;
+            // The session is done, a retry has to start a new one
+            let verified = self.verify_upload(&path, updated, &digests, created).await;
+            if verified.is_err() {
+                PartialTransfer::remove(&key);
+            }
+            uploaded = Some(verified?);
+        }
 
-        // let file = File {
-        //
-        // }
-        todo!()
+        Ok(self.uploaded(&key, path, uploaded).await?)
     }
 
-    async fn copy_file(&self, source: PathBuf, dest: PathBuf) -> anyhow::Result<()> {
-        let sdir = source.parent().unwrap();
-        let sdir = self.dirs.get(sdir).unwrap().clone();
-        let sname = source.file_name().unwrap();
+    async fn checksum(&self, path: PathBuf, algorithm: Checksum) -> RepoResult<String> {
+        let id = self.file_id(&abs(path)).await?;
 
-        let ddir = dest.parent().unwrap();
-        let ddir = self.dirs.get(ddir).unwrap().clone();
-        let dname = dest.file_name().unwrap().to_string_lossy().to_string();
+        let file: File = builder()
+            .files_get(id)
+            .fields("sha256Checksum, md5Checksum, appProperties")
+            .call(&self.client, &self.auth)
+            .await?;
 
-        let mut files: FileList = builder()
-            .files_list()
-            .fields("files(id, name, size, sha256Checksum)")
-            .query(format!("name = '{}' and '{}' in parents", sname.to_string_lossy(), sdir))
+        file.checksum(algorithm)
+            .ok_or_else(|| RepoError::Other(format_err!("Drive did not provide a {algorithm:?} checksum")))
+    }
+
+    async fn copy_file(&self, source: PathBuf, dest: PathBuf) -> RepoResult<crate::repo::File> {
+        let id = self.file_id(&abs(source)).await?;
+        Ok(self.copy_id(id, abs(dest)).await?)
+    }
+
+    /// Copies are new files, the file they replace is trashed once they're made
+    async fn copy_from(&self, id: &str, dest: PathBuf) -> RepoResult<crate::repo::File> {
+        let dest = abs(dest);
+        let replaced = self.fils.get(&dest).and_then(|ids| ids.first().cloned());
+        if let Some(replaced) = &replaced {
+            self.check_unchanged(replaced, &dest).await?;
+        }
+        let copy = self.copy_id(id.to_owned(), dest).await?;
+        if let Some(replaced) = replaced {
+            builder()
+                .files_update(replaced, File { trashed: Some(true), ..Default::default() })
+                .fields("id")
+                .call(&self.client, &self.auth)
+                .await?;
+        }
+        Ok(copy)
+    }
+
+    async fn trash(&self, path: PathBuf) -> RepoResult<String> {
+        let path = abs(path);
+        let id = self.entry_id(&path).await?;
+
+        builder()
+            .files_update(id.clone(), File { trashed: Some(true), ..Default::default() })
+            .fields("id")
             .call(&self.client, &self.auth)
             .await?;
 
-        let target = File {
-            name: Some(dname),
-            parents: vec![ddir],
-            ..Default::default()
+        self.forget(&path);
+        Ok(id)
+    }
+
+    /// Overwriting keeps the previous contents as a revision, which is kept forever so that
+    /// it can be restored later
+    async fn keep_version(&self, path: PathBuf) -> RepoResult<String> {
+        let id = self.file_id(&abs(&path)).await?;
+        let file: File = builder()
+            .files_get(id.clone())
+            .fields("headRevisionId")
+            .call(&self.client, &self.auth)
+            .await?;
+        // Google documents have no revisions of their contents
+        let Some(revision) = file.head_revision_id else {
+            return self.trash(path).await;
         };
+        builder()
+            .revisions_update(id.clone(), &revision, Revision { keep_forever: Some(true), ..Default::default() })
+            .fields("id")
+            .call(&self.client, &self.auth)
+            .await?;
+        Ok(format!("{id}{REVISION_SEPARATOR}{revision}"))
+    }
+
+    async fn restore(&self, id: &str, path: PathBuf) -> RepoResult<()> {
+        if let Some((id, revision)) = id.split_once(REVISION_SEPARATOR) {
+            let data = self.read_revision_of(id.to_owned(), revision).await?;
+            self.write_file(path, data).await?;
+            return Ok(());
+        }
+        match self.stat(path.clone()).await? {
+            Some(existing) if existing.id() != id => drop(self.trash(path.clone()).await?),
+            _ => {}
+        }
+        let abs = abs(path);
+        let parent = self.existing_dir(abs.parent().unwrap_or(Path::new("/"))).await?;
+        let file: File = builder()
+            .files_get(id)
+            .fields("parents")
+            .call(&self.client, &self.auth)
+            .await?;
+        let mut req = builder().files_update(id.to_owned(), File { trashed: Some(false), ..Default::default() });
+        // The folder it was in may have been recreated since
+        if let Some(old) = file.parents.first().filter(|old| **old != parent) {
+            req = req.move_parents(old, &parent);
+        }
+        req.fields("id").call(&self.client, &self.auth).await?;
+        self.forget(&abs);
+        Ok(())
+    }
 
-        let first = files.files.remove(0);
+    async fn delete_permanent(&self, path: PathBuf) -> RepoResult<()> {
+        let path = abs(path);
+        let id = self.entry_id(&path).await?;
 
         builder()
-            .files_copy(first.id.unwrap(), target)
+            .files_delete(id)
+            .send(&self.client, &self.auth)
+            .await?;
+
+        self.forget(&path);
+        Ok(())
+    }
+
+    async fn rename(&self, from: PathBuf, to: PathBuf) -> RepoResult<Entry> {
+        let from = abs(from);
+        let to = abs(to);
+
+        let id = self.entry_id(&from).await?;
+        let old_parent = self.existing_dir(from.parent().unwrap()).await?;
+        let new_parent = self.existing_dir(to.parent().unwrap()).await?;
+
+        let name = to.file_name().unwrap().to_string_lossy().to_string();
+        let mut request = builder().files_update(id.clone(), File { name: Some(name), ..Default::default() });
+        if old_parent != new_parent {
+            request = request.move_parents(&old_parent, &new_parent);
+        }
+        let entry = request
+            .fields(FILE_FIELDS)
+            .call(&self.client, &self.auth)
+            .await?
+            .into_listed();
+
+        match &entry {
+            Entry::Dir(..) => {
+                move_keys(&self.dirs, &from, &to);
+                move_keys(&self.fils, &from, &to);
+            }
+            Entry::File(..) => {
+                // Other files with the same name stay where they are
+                self.fils.remove_if_mut(&from, |_, ids| {
+                    ids.retain(|other| *other != id);
+                    ids.is_empty()
+                });
+                self.fils.insert(to, vec![id]);
+            }
+        }
+        Ok(entry)
+    }
+
+    async fn set_modified(&self, path: PathBuf, modified: DateTime<Utc>) -> RepoResult<crate::repo::File> {
+        let id = self.file_id(&abs(path)).await?;
+        let file = builder()
+            .files_update(id, File { modified_time: Some(modified), ..Default::default() })
+            .fields(FILE_FIELDS)
             .call(&self.client, &self.auth)
             .await?;
+        Ok(self.written(file))
+    }
+
+    async fn set_metadata(&self, path: PathBuf, metadata: &BTreeMap<String, String>) -> RepoResult<()> {
+        let path = abs(path);
+        let properties: IndexMap<String, serde_json::Value> = metadata.iter()
+            .filter(|(key, value)| {
+                // Drive limits each property to 124 bytes of key and value together
+                let fits = key.len() + value.len() <= 124;
+                if !fits {
+                    warn!("Not storing {key} of {path:?}, it's too large for a Drive property");
+                }
+                fits
+            })
+            .map(|(key, value)| (key.clone(), value.clone().into()))
+            .collect();
+        if properties.is_empty() {
+            return Ok(());
+        }
 
+        let id = self.file_id(&path).await?;
+        builder()
+            .files_update(id, File { app_properties: Some(properties), ..Default::default() })
+            .fields("id")
+            .call(&self.client, &self.auth)
+            .await?;
         Ok(())
     }
 
-    async fn delete(&self, path: PathBuf) -> anyhow::Result<()> {
+    /// Links are created as shortcuts, which need their target to exist already
+    async fn create_link(&self, path: PathBuf, target: PathBuf) -> RepoResult<crate::repo::File> {
+        let dir = path.parent().unwrap_or(Path::new("")).to_owned();
+        let resolved = link_target(&dir, &target).ok_or_else(|| RepoError::NotFound(format!("{target:?}")))?;
+        let target_id = self.entry_id(&abs(resolved)).await?;
+        if self.stat(path.clone()).await?.is_some() {
+            drop(self.trash(path.clone()).await?);
+        }
+
+        let path = abs(path);
+        let file = File {
+            name: Some(path.file_name().unwrap().to_string_lossy().into_owned()),
+            mime_type: Some(SHORTCUT_MIME.to_string()),
+            parents: vec![self.existing_dir(&abs(dir)).await?],
+            shortcut_details: Some(ShortcutDetails { target_id, target_mime_type: None }),
+            ..Default::default()
+        };
+        let file: File = builder()
+            .files_create(file)
+            .fields(FILE_FIELDS)
+            .call(&self.client, &self.auth)
+            .await?;
+
+        let mut file = file.into_entry();
+        self.fils.insert(path, vec![file.id.clone()]);
+        file.metadata.insert(LINK.to_string(), target.to_string_lossy().into_owned());
+        Ok(file)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api(status: u16, body: &str) -> Failure {
+        Failure::Api(ApiError::new(StatusCode::from_u16(status).unwrap(), body.to_owned()), None)
+    }
+
+    fn rate_limited(reason: &str) -> String {
+        format!(r#"{{"error": {{"code": 403, "message": "Slow down", "errors": [{{"reason": "{reason}"}}]}}}}"#)
+    }
+
+    #[test]
+    fn classifies_api_errors() {
+        let error = ApiError::new(StatusCode::FORBIDDEN, rate_limited("userRateLimitExceeded"));
+        assert_eq!(error.reason.as_deref(), Some("userRateLimitExceeded"));
+        assert_eq!(error.message.as_deref(), Some("Slow down"));
+        assert!(error.transient());
+
+        assert!(!ApiError::new(StatusCode::FORBIDDEN, rate_limited("insufficientFilePermissions")).transient());
+        assert!(ApiError::new(StatusCode::TOO_MANY_REQUESTS, String::new()).transient());
+        assert!(ApiError::new(StatusCode::BAD_GATEWAY, "<html>".into()).transient());
+        assert!(!ApiError::new(StatusCode::NOT_FOUND, "{}".into()).transient());
+
+        let error = ApiError::new(StatusCode::TOO_MANY_REQUESTS, r#"{"error": {"status": "RESOURCE_EXHAUSTED"}}"#.into());
+        assert_eq!(error.reason.as_deref(), Some("RESOURCE_EXHAUSTED"));
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let mut retries = Retries::default();
+        for _ in 0..MAX_BACKOFFS {
+            assert!(matches!(retries.next(&api(500, "")), Some(Retry::Wait(..))));
+        }
+        assert!(retries.next(&api(500, "")).is_none());
+
+        let mut retries = Retries::default();
+        assert!(retries.next(&api(400, "")).is_none());
+        assert!(matches!(retries.next(&api(401, "")), Some(Retry::Refresh)));
+        assert!(retries.next(&api(401, "")).is_none());
+
+        let delay = Duration::from_secs(42);
+        let failure = Failure::Api(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, String::new()), Some(delay));
+        assert!(matches!(Retries::default().next(&failure), Some(Retry::Wait(d)) if d == delay));
+    }
+
+    #[test]
+    fn retries_creates_only_when_rejected() {
+        let mut retries = Retries { creates: true, ..Default::default() };
+        assert!(retries.next(&api(500, "")).is_none());
+        assert!(retries.next(&api(503, "")).is_none());
+        assert!(retries.next(&api(429, "")).is_some());
+        let failure = Failure::Api(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, String::new()), Some(Duration::from_secs(1)));
+        assert!(retries.next(&failure).is_some());
+        assert!(api(500, "").unconfirmed());
+        assert!(!api(429, "").unconfirmed());
+    }
+
+    #[tokio::test]
+    async fn retries_unsent_requests() {
+        // Nothing listens on the discard port, so the connection is refused
+        let error = reqwest::Client::new().get("http://127.0.0.1:9").send().await.unwrap_err();
+        assert!(error.is_connect());
+        let failure = Failure::Connection(error);
+        assert!(!failure.unconfirmed());
+        assert!(Retries { creates: true, ..Default::default() }.next(&failure).is_some());
+    }
+
+    #[test]
+    fn suffixes_duplicates() {
+        assert_eq!(suffixed("report.pdf", "1a2b3c4d5e6f7g8h"), "report (dup-5e6f7g8h).pdf");
+        assert_eq!(suffixed("notes", "abc"), "notes (dup-abc)");
+        assert_eq!(suffixed(".bashrc", "12345678"), ".bashrc (dup-12345678)");
+        assert_eq!(suffixed("a.tar.gz", "12345678"), "a.tar (dup-12345678).gz");
+    }
+
+    #[tokio::test]
+    async fn rechunks_streams() {
+        let pieces: Vec<anyhow::Result<Vec<u8>>> = vec![Ok(vec![1, 2]), Ok(vec![3]), Ok(vec![]), Ok(vec![4, 5, 6, 7, 8])];
+        let chunks: Vec<Vec<u8>> = rechunk(futures::stream::iter(pieces), 3).try_collect().await.unwrap();
+        assert_eq!(chunks, [vec![1, 2, 3], vec![4, 5, 6], vec![7, 8]]);
 
-        todo!()
+        let empty: Vec<anyhow::Result<Vec<u8>>> = vec![];
+        let chunks: Vec<Vec<u8>> = rechunk(futures::stream::iter(empty), 3).try_collect().await.unwrap();
+        assert!(chunks.is_empty());
+
+        let exact: Vec<anyhow::Result<&[u8]>> = vec![Ok(&[1, 2, 3, 4])];
+        let chunks: Vec<Vec<u8>> = rechunk(futures::stream::iter(exact), 2).try_collect().await.unwrap();
+        assert_eq!(chunks, [vec![1, 2], vec![3, 4]]);
+    }
+
+    #[test]
+    fn builds_multipart_bodies() {
+        let metadata = serde_json::json!({"name": "a.txt"});
+        let (content_type, body) = multipart(&metadata, b"hello", "text/plain");
+        let boundary = content_type.strip_prefix("multipart/related; boundary=").unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert_eq!(body, format!(
+            "--{boundary}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{{\"name\":\"a.txt\"}}\r\n\
+             --{boundary}\r\nContent-Type: text/plain\r\n\r\nhello\r\n--{boundary}--\r\n"
+        ));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Settings of the HTTP client used for Drive requests and signing in, kept in the config file
+/// under [`crate::HTTP`]. Unset ones keep the defaults of reqwest.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HttpConfig {
+    /// Idle connections kept open per host, for parallel transfers to reuse
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_max_idle: Option<usize>,
+    /// Seconds idle connections are kept open for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_idle_timeout: Option<u64>,
+    /// Whether HTTP/2 is negotiated, which multiplexes requests over a single connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http2: Option<bool>,
+    /// Seconds between TCP keepalive probes of open connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_keepalive: Option<u64>,
+    /// Proxy all connections go through, `http://`, `https://` or `socks5://`. Without one,
+    /// `HTTPS_PROXY`, `HTTP_PROXY` and `ALL_PROXY` from the environment are used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// PEM file with root certificates trusted besides the built in ones, for proxies that
+    /// intercept TLS with their own certificate authority
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<PathBuf>,
+    /// Accept any certificate. Only set for a single run by `--insecure`, never stored
+    #[serde(skip)]
+    pub insecure: bool,
+}
+
+impl HttpConfig {
+    pub fn client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::ClientBuilder::new().gzip(true);
+        if let Some(idle) = self.pool_max_idle {
+            builder = builder.pool_max_idle_per_host(idle);
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(Duration::from_secs(timeout));
+        }
+        if self.http2 == Some(false) {
+            builder = builder.http1_only();
+        }
+        if let Some(interval) = self.tcp_keepalive {
+            builder = builder.tcp_keepalive(Duration::from_secs(interval));
+        }
+        if let Some(url) = &self.proxy {
+            let proxy = reqwest::Proxy::all(url).with_context(|| format!("Invalid proxy {url:?}"))?;
+            builder = builder.proxy(proxy.no_proxy(reqwest::NoProxy::from_env()));
+        }
+        if let Some(path) = &self.ca_cert {
+            let pem = std::fs::read(path).with_context(|| format!("Reading CA certificates from {path:?}"))?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem).with_context(|| format!("Invalid CA certificates in {path:?}"))?;
+            if certs.is_empty() {
+                bail!("No CA certificates in {path:?}");
+            }
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if self.insecure {
+            warn!("TLS certificates aren't verified, anyone between here and Google can read and change the traffic, including credentials");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder.build()?)
+    }
+}
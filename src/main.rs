@@ -1,27 +1,42 @@
 mod auth;
 mod gdrive;
+mod http;
+mod memory;
 mod serde_format;
 mod cli;
+mod filter;
+mod normalize;
+mod query;
 mod repo;
+mod sanitize;
+mod state;
+mod sync;
+mod trash;
 
-use crate::gdrive::{Authorizer, builder, GDriveRepo};
+use crate::gdrive::{Authorizer, Duplicates, GDriveRepo};
 use clap::Parser;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::future::{Future, ready, Ready};
-use std::io::{Seek, SeekFrom};
-use std::ops::Add;
-use std::path::{Path, PathBuf};
+use std::future::Future;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
 use std::sync::Mutex;
 use std::time::SystemTime;
-use anyhow::{bail, Error};
-use futures::Stream;
+use anyhow::{bail, format_err, Context, Error};
 use indexmap::IndexMap;
 use oauth2::{AccessToken, RefreshToken, Scope, TokenResponse};
-use tracing::warn;
-use crate::cli::Args;
-use crate::repo::{LocalRepo, Repo, sync};
+use futures::future::join_all;
+use futures::TryStreamExt;
+use tracing::{error, warn};
+use crate::cli::{ApplyArgs, Args, PlanFile, PrefixedPath, RepoOptions};
+use crate::http::HttpConfig;
+use crate::memory::MemoryRepo;
+use crate::normalize::Normalized;
+use crate::query::Query;
+use crate::repo::{AnyRepo, CachedRepo, FileSource, Links, LocalRepo, Repo};
+use crate::state::{HashCache, Run, SyncState, UndoLog};
+use crate::sync::{Interrupted, LimitReached, OutOfStorage, SyncFailed, SyncOptions};
 
 static LOCK: Mutex<()> = Mutex::new(());
 
@@ -33,6 +48,7 @@ pub fn get<T: Serialize + DeserializeOwned>(name: &str) -> Option<T> {
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(path)
         .unwrap();
 
@@ -71,6 +87,7 @@ pub fn with<T: Default + Serialize + DeserializeOwned, R>(name: &str, fun: impl
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .open(path)
         .unwrap();
 
@@ -87,20 +104,27 @@ pub fn with<T: Default + Serialize + DeserializeOwned, R>(name: &str, fun: impl
     file.seek(SeekFrom::Start(0)).unwrap();
     serde_json::to_writer_pretty(file, &cfg).unwrap();
     drop(_lck);
-    return out;
+    out
 }
 
 pub const DRIVES: &str = "drives";
+pub const HTTP: &str = "http";
 
 pub type Drives = IndexMap<String, DriveInfo>;
 
 #[derive(Debug, Serialize, Deserialize)]
-struct DriveInfo {
+pub struct DriveInfo {
     access_token: AccessToken,
     access_until: SystemTime,
 
     refresh_token: RefreshToken,
     scopes: Vec<Scope>,
+    /// Id of the folder paths of the drive start at, the root of the account when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root_folder: Option<String>,
+    /// Mime types files are uploaded as by their extension, overriding what Drive detects
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    mime_types: BTreeMap<String, String>,
 }
 
 struct GDriveAuthorizer {
@@ -109,31 +133,29 @@ struct GDriveAuthorizer {
 }
 
 impl Authorizer for GDriveAuthorizer {
-    fn force_refresh(&self, client: &reqwest::Client) -> impl Future<Output=Result<AccessToken, Error>> {
-        async move {
-            let lock = self.lock.lock().await;
+    async fn force_refresh(&self, client: &reqwest::Client) -> Result<AccessToken, Error> {
+        let lock = self.lock.lock().await;
 
-            let mut drives = get::<Drives>(DRIVES).unwrap_or_default();
-            let mut drive = drives.get_mut(&self.name)
-                .unwrap();
+        let mut drives = get::<Drives>(DRIVES).unwrap_or_default();
+        let drive = drives.get_mut(&self.name)
+            .unwrap();
 
-            let (valid_until, response) = crate::auth::refresh(client, &drive.refresh_token).await?;
+        let (valid_until, response) = crate::auth::refresh(client, &drive.refresh_token).await?;
 
-            drive.access_token = response.access_token().clone();
-            drive.access_until = valid_until;
+        drive.access_token = response.access_token().clone();
+        drive.access_until = valid_until;
 
-            set(DRIVES, &drives);
+        set(DRIVES, &drives);
 
-            drop(lock);
+        drop(lock);
 
-            Ok(response.access_token().clone())
-        }
+        Ok(response.access_token().clone())
     }
 
     fn token(&self, client: &reqwest::Client) -> impl Future<Output=Result<AccessToken, Error>> {
         let mut drives = get::<Drives>(DRIVES).unwrap_or_default();
         async move {
-            let mut drive = drives.get_mut(&self.name)
+            let drive = drives.get_mut(&self.name)
                 .unwrap();
 
             return if drive.access_until > SystemTime::now() {
@@ -146,17 +168,328 @@ impl Authorizer for GDriveAuthorizer {
     }
 }
 
+/// Canonicalize the two sides of a sync, either can be local or on Drive
+fn locations(src: PrefixedPath, dst: PrefixedPath) -> anyhow::Result<(PrefixedPath, PrefixedPath)> {
+    Ok((src.canonicalize()?, dst.canonicalize()?))
+}
+
+/// Whether files can be copied by Drive itself instead of being transferred
+fn server_side(src: &PrefixedPath, dst: &PrefixedPath, sandbox: bool) -> bool {
+    !sandbox && src.prefix.is_some() && src.prefix == dst.prefix
+}
+
+/// Open the drive of a Drive location at the folder its path starts from, along with the rest
+/// of the path
+async fn open_drive(client: &reqwest::Client, location: &PrefixedPath) -> anyhow::Result<(GDriveRepo<GDriveAuthorizer>, PathBuf)> {
+    let Some(drive) = &location.prefix else {
+        bail!("{location} isn't on Drive, e.g. drive:/path");
+    };
+    let (folder, path) = location.drive_folder();
+    let info = get::<Drives>(DRIVES).and_then(|mut drives| drives.shift_remove(drive));
+    let root = folder.or_else(|| info.as_ref()?.root_folder.clone());
+    let mime_types = info.map(|info| info.mime_types).unwrap_or_default();
+    let auth = GDriveAuthorizer { name: drive.clone(), lock: Default::default() };
+    let path = path.strip_prefix("/").unwrap_or(&path).to_owned();
+    Ok((GDriveRepo::new(client, auth, root.as_deref()).await?.with_mime_types(mime_types), path))
+}
+
+/// Lists files of a local source to star on Drive, with `--star-marked`
+const STARRED_LIST: &str = ".dsync-starred";
+
+/// Paths listed in the [`STARRED_LIST`] of a local source, none when it's missing or not enabled
+fn starred(src: &PrefixedPath, enabled: bool) -> anyhow::Result<Vec<PathBuf>> {
+    if !enabled || src.prefix.is_some() {
+        return Ok(vec![]);
+    }
+    let list = match std::fs::read_to_string(src.path.join(STARRED_LIST)) {
+        Ok(list) => list,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| PathBuf::from(line.trim_start_matches('/')))
+        .collect())
+}
+
+/// Authorizer for requests made with the account of a connected drive
+fn account(name: &str) -> anyhow::Result<GDriveAuthorizer> {
+    if !get::<Drives>(DRIVES).unwrap_or_default().contains_key(name) {
+        bail!("Drive not found: {name}");
+    }
+    Ok(GDriveAuthorizer { name: name.to_owned(), lock: Default::default() })
+}
+
+/// Show or change the stored [`HttpConfig`], `proxy` is the one given with `--proxy`
+fn http_settings(command: cli::Http, proxy: Option<String>) -> anyhow::Result<()> {
+    match command {
+        cli::Http::Show => {
+            let config = get::<HttpConfig>(HTTP).unwrap_or_default();
+            println!("{}", serde_json::to_string_pretty(&config)?);
+        }
+        cli::Http::Set { pool_max_idle, pool_idle_timeout, http2, tcp_keepalive, ca_cert } => {
+            let mut config = get::<HttpConfig>(HTTP).unwrap_or_default();
+            config.pool_max_idle = pool_max_idle.or(config.pool_max_idle);
+            config.pool_idle_timeout = pool_idle_timeout.map(|t| t.as_secs()).or(config.pool_idle_timeout);
+            config.http2 = http2.or(config.http2);
+            config.tcp_keepalive = tcp_keepalive.map(|t| t.as_secs()).or(config.tcp_keepalive);
+            config.proxy = proxy.or(config.proxy);
+            if let Some(path) = ca_cert {
+                config.ca_cert = Some(std::fs::canonicalize(&path).with_context(|| format!("Reading {path:?}"))?);
+            }
+            config.client()?;
+            set(HTTP, &config);
+            println!("{}", serde_json::to_string_pretty(&config)?);
+        }
+        cli::Http::Reset => {
+            set(HTTP, &HttpConfig::default());
+        }
+    }
+    Ok(())
+}
+
+/// Drive holding the state shared by syncs between `src` and `dst`, along with its name there
+async fn shared_state(client: &reqwest::Client, src: &PrefixedPath, dst: &PrefixedPath) -> anyhow::Result<(GDriveRepo<GDriveAuthorizer>, String)> {
+    let (remote, is_src) = match (&src.prefix, &dst.prefix) {
+        (Some(_), _) => (src, true),
+        (None, Some(_)) => (dst, false),
+        (None, None) => bail!("--shared-state needs one of the locations to be on Drive"),
+    };
+    let drive = remote.prefix.as_deref().unwrap();
+    let drives = get::<Drives>(DRIVES).unwrap_or_default();
+    if drives.get(drive).is_some_and(|d| !d.scopes.iter().any(|s| s.as_str() == auth::APPDATA_SCOPE)) {
+        bail!("{drive} can't store shared state, connect it again with `dsync drive rm {drive}` and `dsync drive add {drive}`");
+    }
+    let (repo, path) = open_drive(client, remote).await?;
+    let repo = repo.descend(&path, false).await?;
+    let name = SyncState::shared_name(repo.root_id(), is_src);
+    Ok((repo, name))
+}
+
+/// Load the snapshot of the last sync between `src` and `dst`, from the drive when it's `shared`
+async fn load_state(client: &reqwest::Client, src: &PrefixedPath, dst: &PrefixedPath, shared: bool) -> anyhow::Result<SyncState> {
+    let path = SyncState::path_for(&src.to_string(), &dst.to_string());
+    if !shared {
+        return SyncState::load(&path);
+    }
+    let (repo, name) = shared_state(client, src, dst).await?;
+    match repo.read_app_data(&name).await? {
+        Some(data) => SyncState::read(&data[..], &name),
+        // Not shared yet, start from what this machine knows
+        None => SyncState::load(&path),
+    }
+}
+
+/// Save the snapshot of a sync locally, and to the drive when it's `shared`
+async fn save_state(client: &reqwest::Client, src: &PrefixedPath, dst: &PrefixedPath, state: &SyncState, shared: bool) -> anyhow::Result<()> {
+    state.save(&SyncState::path_for(&src.to_string(), &dst.to_string()))?;
+    if shared {
+        let (repo, name) = shared_state(client, src, dst).await?;
+        let mut data = vec![];
+        state.write(&mut data)?;
+        repo.write_app_data(&name, data).await?;
+    }
+    Ok(())
+}
+
+/// Open the contents of a Drive file, or of one of its revisions
+async fn open_file(client: &reqwest::Client, location: &PrefixedPath, revision: Option<&str>) -> anyhow::Result<gdrive::DriveFile<GDriveAuthorizer>> {
+    let (repo, path) = open_drive(client, location).await?;
+    match revision {
+        Some(revision) => repo.read_revision(&path, revision).await,
+        None => Ok(repo.read_file(path).await?),
+    }
+}
+
+/// Write the whole contents of a file to `out`
+async fn download(data: impl FileSource, out: &mut impl Write) -> anyhow::Result<()> {
+    let stream = data.stream(0, 1 << 20);
+    futures::pin_mut!(stream);
+    while let Some(chunk) = stream.try_next().await? {
+        out.write_all(&chunk)?;
+    }
+    Ok(out.flush()?)
+}
+
+/// Open a location, Drive paths start at the folder it points to. With `create`, missing Drive
+/// folders are created.
+async fn open_repo(client: &reqwest::Client, location: &PrefixedPath, create: bool) -> anyhow::Result<AnyRepo<GDriveAuthorizer>> {
+    Ok(match &location.prefix {
+        Some(_) => {
+            let (repo, path) = open_drive(client, location).await?;
+            AnyRepo::Drive(repo.descend(&path, create).await?)
+        }
+        None => AnyRepo::Local(LocalRepo { path: location.path.clone(), links: Links::default(), xattrs: false, sanitize: false, streams: 1, stream_cutoff: 0 }),
+    })
+}
+
+/// Open the destination of a sync, with `--sandbox` it's replaced by sample files held in memory.
+/// Local destinations can't be sandboxed, their sync would have nothing to simulate.
+async fn open_target(client: &reqwest::Client, location: &PrefixedPath, sandbox: bool) -> anyhow::Result<AnyRepo<GDriveAuthorizer>> {
+    match (sandbox, &location.prefix) {
+        (true, Some(_)) => Ok(AnyRepo::Memory(MemoryRepo::sample())),
+        (true, None) => bail!("--sandbox only simulates syncs to Drive, {location} is local"),
+        (false, _) => open_repo(client, location, true).await,
+    }
+}
+
+/// Open the source of a sync, restricting Drive listings to `query`
+async fn open_source(client: &reqwest::Client, location: &PrefixedPath, query: Option<Query>, sandbox: bool) -> anyhow::Result<AnyRepo<GDriveAuthorizer>> {
+    let repo = match sandbox && location.prefix.is_some() {
+        true => AnyRepo::Memory(MemoryRepo::sample()),
+        false => open_repo(client, location, false).await?,
+    };
+    Ok(match (repo, query) {
+        (AnyRepo::Drive(repo), Some(query)) => AnyRepo::Drive(repo.restrict(query)),
+        (AnyRepo::Memory(repo), _) => AnyRepo::Memory(repo),
+        (AnyRepo::Local(_), Some(_)) => bail!("--include-mime, --exclude-mime and --owned-by-me are only supported for Drive sources"),
+        (repo, None) => repo,
+    })
+}
+
+/// Remember listings of a repo, listing the whole tree upfront with `--fast-list`
+async fn cached<R: Repo>(repo: R, fast_list: bool) -> anyhow::Result<CachedRepo<R>> {
+    let repo = CachedRepo::new(repo);
+    if fast_list {
+        repo.prefetch(PathBuf::new()).await?;
+    }
+    Ok(repo)
+}
+
+/// Either side of a sync, as it's compared
+type SyncRepo = CachedRepo<Normalized<AnyRepo<GDriveAuthorizer>>>;
+
+/// Open the source of a sync with the options it's listed with, restricting Drive listings to
+/// `query`
+async fn sync_source(client: &reqwest::Client, src: &PrefixedPath, query: Option<Query>, repos: &RepoOptions, apply: &ApplyArgs) -> anyhow::Result<SyncRepo> {
+    let repo = open_source(client, src, query, repos.sandbox).await?
+        .with_links(repos.links)
+        .with_sanitize(repos.sanitize_names)
+        .with_xattrs(apply.metadata)
+        .with_listing_ttl(repos.listing_ttl)
+        .with_export_formats(repos.export_formats.clone())
+        .with_duplicates(repos.duplicates);
+    cached(Normalized::new(repo, repos.unicode_normalization), repos.fast_list).await
+}
+
+/// Open the destination of a sync with the options it's listed and written with. Unless it's
+/// going to be written, missing Drive folders aren't created.
+async fn sync_target(client: &reqwest::Client, src: &PrefixedPath, dst: &PrefixedPath, repos: &RepoOptions, apply: &ApplyArgs, write: bool) -> anyhow::Result<SyncRepo> {
+    let repo = match write {
+        true => open_target(client, dst, repos.sandbox).await?
+            .with_upload_cutoff(apply.upload_cutoff)
+            .with_chunk_size(apply.drive_chunk_size)
+            .with_streams(apply.multi_thread_streams, apply.multi_thread_cutoff)
+            .with_import_formats(apply.import_formats.clone())
+            .with_keep_forever(apply.keep_forever)
+            .with_labels(apply.apply_label.clone())
+            .with_starred(starred(src, apply.star_marked)?),
+        false => open_source(client, dst, None, repos.sandbox).await?,
+    };
+    let repo = repo
+        .with_links(repos.links)
+        .with_sanitize(repos.sanitize_names)
+        .with_xattrs(apply.metadata)
+        .with_listing_ttl(repos.listing_ttl)
+        .with_duplicates(repos.duplicates);
+    cached(Normalized::new(repo, repos.unicode_normalization), repos.fast_list).await
+}
+
+/// Exit status of a run stopped by `--max-transfer` or `--max-duration`
+const EXIT_LIMIT_REACHED: i32 = 3;
+/// Exit status of a run stopped by SIGINT or SIGTERM, as shells report it
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Resolves on Ctrl-C, or SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = term.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// SIGUSR1 pauses running syncs, SIGUSR2 resumes them
+#[cfg(unix)]
+async fn pause_signals() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut pause = signal(SignalKind::user_defined1()).unwrap();
+    let mut resume = signal(SignalKind::user_defined2()).unwrap();
+    loop {
+        tokio::select! {
+            _ = pause.recv() => {
+                warn!("Paused, send SIGUSR2 to resume");
+                sync::pause();
+            }
+            _ = resume.recv() => {
+                warn!("Resumed");
+                sync::resume();
+            }
+        }
+    }
+}
+
+/// Stop syncs gracefully on the first interrupt, and abort them on the second. Only installed by
+/// commands applying operations, others are stopped right away.
+fn handle_interrupts() {
+    tokio::spawn(async {
+        shutdown_signal().await;
+        warn!("Interrupted, finishing operations in progress. Interrupt again to abort them");
+        sync::interrupt();
+
+        // Progress is journaled after every operation, aborted transfers are resumed later
+        shutdown_signal().await;
+        if let Err(e) = HashCache::save() {
+            warn!("Failed to save hash cache: {e}");
+        }
+        std::process::exit(EXIT_INTERRUPTED);
+    });
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = cli::Args::parse();
+    #[cfg(unix)]
+    tokio::spawn(pause_signals());
+
+    let result = run().await;
+    if let Err(e) = HashCache::save() {
+        warn!("Failed to save hash cache: {e}");
+    }
+
+    match result {
+        Err(e) if e.is::<LimitReached>() => {
+            warn!("{e}");
+            std::process::exit(EXIT_LIMIT_REACHED);
+        }
+        Err(e) if e.is::<Interrupted>() => {
+            warn!("{e}");
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        res => res,
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
+    let cli = cli::Cli::parse();
     std::env::set_var("RUST_LOG", "trace");
-    tracing_subscriber::fmt().init();
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
 
-    let client = reqwest::ClientBuilder::new()
-        .gzip(true)
-        .build()?;
+    // Settings are changed without a client, so that broken ones can still be fixed
+    if let Args::Http(command) = cli.command {
+        return http_settings(command, cli.proxy);
+    }
+    let mut http = get::<HttpConfig>(HTTP).unwrap_or_default();
+    http.proxy = cli.proxy.or(http.proxy);
+    http.insecure = cli.insecure;
+    let client = http.client()?;
 
-    match args {
+    match cli.command {
         Args::Drive(cli::Drive::List) => {
             let drives = get::<Drives>(DRIVES).unwrap();
             println!("These are the drives you have: ");
@@ -175,7 +508,7 @@ async fn main() -> anyhow::Result<()> {
             }
             return Ok(());
         }
-        Args::Drive(cli::Drive::Add { name, code }) => {
+        Args::Drive(cli::Drive::Add { name, code: _, root_folder }) => {
             let mut old = get::<IndexMap<String, DriveInfo>>(DRIVES).unwrap_or_default();
             if let Some(old) = old.get(&name) {
                 bail!("Drive already exists: {old:?}");
@@ -185,92 +518,474 @@ async fn main() -> anyhow::Result<()> {
                 access_token: response.access_token().clone(),
                 access_until: valid_until,
                 refresh_token: response.refresh_token().unwrap().clone(),
-                scopes: response.scopes().map(Clone::clone).unwrap_or_default(),
+                scopes: response.scopes().cloned().unwrap_or_default(),
+                root_folder,
+                mime_types: Default::default(),
             };
             old.insert(name, drive);
             set(DRIVES, &old);
             return Ok(());
         }
+        Args::Drive(cli::Drive::Root { name, folder }) => {
+            let mut drives = get::<Drives>(DRIVES).unwrap_or_default();
+            let Some(drive) = drives.get_mut(&name) else {
+                bail!("Drive not found: {name}");
+            };
+            match &folder {
+                Some(folder) => println!("{name}: now starts at folder {folder}"),
+                None => println!("{name}: now starts at the root of the account"),
+            }
+            drive.root_folder = folder;
+            set(DRIVES, &drives);
+            return Ok(());
+        }
+        Args::Drive(cli::Drive::Mime { name, ext, mime }) => {
+            let mut drives = get::<Drives>(DRIVES).unwrap_or_default();
+            let Some(drive) = drives.get_mut(&name) else {
+                bail!("Drive not found: {name}");
+            };
+            let ext = ext.trim_start_matches('.').to_lowercase();
+            match mime {
+                Some(mime) => {
+                    println!("{name}: uploads .{ext} files as {mime}");
+                    drive.mime_types.insert(ext, mime);
+                }
+                None => {
+                    println!("{name}: leaves the type of .{ext} files to Drive");
+                    drive.mime_types.remove(&ext);
+                }
+            }
+            set(DRIVES, &drives);
+            return Ok(());
+        }
         Args::Drive(cli::Drive::Rm { name }) => {
             let mut old = get::<IndexMap<String, DriveInfo>>(DRIVES).unwrap_or_default();
             old.shift_remove(&name);
             set(DRIVES, &old);
             return Ok(());
         }
-        Args::Sync(cli::Sync { src, dst }) => {
-            println!("{src:?} to {dst:?}");
+        Args::Sync(args) => {
+            handle_interrupts();
+            let opts = args.options()?;
+            gdrive::set_rate_limit(args.apply.drive_qps);
+            let confirm = args.apply.confirm();
+            let repos = args.repo_options();
+            let query = args.drive_query();
+            let src = args.src.canonicalize()?;
+            let srepo = sync_source(&client, &src, query, &repos, &args.apply).await?;
+
+            let mut targets = vec![];
+            for dst in args.dst {
+                let (src, dst) = locations(src.clone(), dst)?;
+                println!("{src} to {dst}");
+
+                // Sandboxed runs are kept apart, so that they never resume or finish real ones
+                let run = match args.sandbox {
+                    true => Run::new(&format!("{src} (sandbox)"), &dst.to_string()),
+                    false => Run::new(&src.to_string(), &dst.to_string()),
+                };
+                let state = match args.sandbox {
+                    true => SyncState::default(),
+                    false => load_state(&client, &src, &dst, args.apply.shared_state).await?,
+                };
+                let drepo = sync_target(&client, &src, &dst, &repos, &args.apply, true).await?;
+                let opts = SyncOptions { server_side: server_side(&src, &dst, args.sandbox), ..opts.clone() };
+                targets.push((src, dst, drepo, run, state, opts));
+            }
+
+            let results = join_all(targets.iter_mut().map(|(_, _, drepo, run, state, opts)| {
+                sync::sync(&srepo, drepo, opts, state, run, confirm)
+            })).await;
+
+            let mut failed = 0;
+            let mut stopped = None;
+            for ((src, dst, _, run, state, _), result) in targets.iter().zip(results) {
+                if args.sandbox {
+                    // Nothing was written, so there is nothing to undo or resume
+                    let _ = UndoLog::remove(&run.id);
+                    let _ = std::fs::remove_file(run.journal_path());
+                }
+                match result {
+                    Ok(()) if args.sandbox => println!("Simulated sync to {dst}"),
+                    Ok(()) => {
+                        save_state(&client, src, dst, state, args.apply.shared_state).await?;
+                        println!("Synced {dst}, undo with `dsync undo {}`", run.id);
+                    }
+                    Err(e) if e.is::<SyncFailed>() && args.sandbox => {
+                        error!("Simulated sync to {dst} incomplete\n{e}");
+                        failed += 1;
+                    }
+                    Err(e) if e.is::<SyncFailed>() => {
+                        // Everything else was synced, keep track of it
+                        save_state(&client, src, dst, state, args.apply.shared_state).await?;
+                        error!("Sync to {dst} incomplete, undo with `dsync undo {}`\n{e}", run.id);
+                        failed += 1;
+                    }
+                    Err(e) if e.is::<LimitReached>() || e.is::<Interrupted>() || e.is::<OutOfStorage>() => {
+                        warn!("Sync to {dst} stopped: {e}");
+                        stopped = Some(e);
+                    }
+                    Err(e) => {
+                        error!("Sync to {dst} failed: {e:?}");
+                        failed += 1;
+                    }
+                }
+            }
+            let skipped = gdrive::skipped_documents();
+            if skipped > 0 {
+                println!("Skipped {skipped} Google document(s) that aren't exported");
+            }
+            if failed > 0 {
+                bail!("Sync failed for {failed} destination(s)");
+            }
+            if let Some(e) = stopped {
+                return Err(e);
+            }
+        }
+        Args::Plan(cli::Plan { sync: mut args, output }) => {
+            let opts = args.options()?;
+            gdrive::set_rate_limit(args.apply.drive_qps);
+            let repos = args.repo_options();
+            let query = args.drive_query();
+            if args.dst.len() != 1 {
+                bail!("Plans can only be created for a single destination");
+            }
+            let (src, dst) = locations(args.src, args.dst.remove(0))?;
 
-            match (src.prefix, dst.prefix) {
-                (Some(drive), None) => {
-                    let auth = GDriveAuthorizer { name: drive, lock: Default::default() };
+            let state = match args.sandbox {
+                true => SyncState::default(),
+                false => load_state(&client, &src, &dst, args.apply.shared_state).await?,
+            };
 
-                    let srepo = GDriveRepo::new(&client, auth).await?;
-                    let drepo = LocalRepo { path: dst.path.canonicalize().unwrap() };
+            let srepo = sync_source(&client, &src, query, &repos, &args.apply).await?;
+            let drepo = sync_target(&client, &src, &dst, &repos, &args.apply, false).await?;
 
+            let plan = PlanFile {
+                src: src.to_string(),
+                dst: dst.to_string(),
+                plan: sync::plan(&srepo, &drepo, &opts, &state).await?,
+                repos,
+            };
 
-                    sync(srepo, drepo).await?
+            match output {
+                Some(output) => serde_json::to_writer_pretty(std::fs::File::create(output)?, &plan)?,
+                None => println!("{}", serde_json::to_string_pretty(&plan)?),
+            }
+        }
+        Args::Apply(cli::Apply { plan, apply }) => {
+            handle_interrupts();
+            gdrive::set_rate_limit(apply.drive_qps);
+            let PlanFile { src, dst, repos, plan } = serde_json::from_reader(std::fs::File::open(plan)?)?;
+            let (src, dst) = locations(src.parse()?, dst.parse()?)?;
+            let opts = SyncOptions { server_side: server_side(&src, &dst, repos.sandbox), ..apply.options() };
+
+            let srepo = sync_source(&client, &src, None, &repos, &apply).await?;
+            let drepo = sync_target(&client, &src, &dst, &repos, &apply, true).await?;
+
+            if repos.sandbox {
+                // Kept apart from real runs, and nothing was written, so there is nothing to undo
+                let run = Run::new(&format!("{src} (sandbox)"), &dst.to_string());
+                let result = sync::apply(&srepo, &drepo, plan, &opts, &mut SyncState::default(), &run, apply.confirm()).await;
+                let _ = UndoLog::remove(&run.id);
+                let _ = std::fs::remove_file(run.journal_path());
+                result?;
+                println!("Simulated sync to {dst}");
+                return Ok(());
+            }
+
+            let run = Run::new(&src.to_string(), &dst.to_string());
+            let mut state = load_state(&client, &src, &dst, apply.shared_state).await?;
+            let result = sync::apply(&srepo, &drepo, plan, &opts, &mut state, &run, apply.confirm()).await;
+            if result.is_ok() || result.as_ref().is_err_and(|e| e.is::<SyncFailed>()) {
+                save_state(&client, &src, &dst, &state, apply.shared_state).await?;
+            }
+            result?;
+            println!("Synced {dst}, undo with `dsync undo {}`", run.id);
+        }
+        Args::Diff(cli::Diff { sync: mut args, identical }) => {
+            let opts = args.options()?;
+            gdrive::set_rate_limit(args.apply.drive_qps);
+            let repos = args.repo_options();
+            let query = args.drive_query();
+            if args.dst.len() != 1 {
+                bail!("Diffs can only be made against a single destination");
+            }
+            let (src, dst) = locations(args.src, args.dst.remove(0))?;
+
+            let state = match args.sandbox {
+                true => SyncState::default(),
+                false => load_state(&client, &src, &dst, args.apply.shared_state).await?,
+            };
+
+            let srepo = sync_source(&client, &src, query, &repos, &args.apply).await?;
+            let drepo = sync_target(&client, &src, &dst, &repos, &args.apply, false).await?;
+
+            let diff = sync::diff(&srepo, &drepo, &opts, &state).await?;
+            for path in &diff.only_src {
+                println!("+ {}", path.display());
+            }
+            for path in &diff.only_dst {
+                println!("- {}", path.display());
+            }
+            for path in &diff.differ {
+                println!("~ {}", path.display());
+            }
+            if identical {
+                for path in &diff.identical {
+                    println!("= {}", path.display());
                 }
-                (None, Some(drive)) => {
-                    let auth = GDriveAuthorizer { name: drive, lock: Default::default() };
+            }
+            println!(
+                "{} only in {src}, {} only in {dst}, {} differ, {} identical",
+                diff.only_src.len(),
+                diff.only_dst.len(),
+                diff.differ.len(),
+                diff.identical.len(),
+            );
+        }
+        Args::Check(args) => {
+            let opts = SyncOptions { download: args.download, checkers: args.checkers, hash: args.hash, ..Default::default() };
+            let (src, dst) = locations(args.src, args.dst)?;
 
-                    let srepo = LocalRepo { path: src.path.canonicalize().unwrap() };
-                    let drepo = GDriveRepo::new(&client, auth).await?;
+            let srepo = open_repo(&client, &src, false).await?;
+            let drepo = open_repo(&client, &dst, false).await?;
 
+            let diff = sync::check(&srepo, &drepo, &opts).await?;
+            for path in &diff.only_src {
+                println!("missing on {dst}: {}", path.display());
+            }
+            for path in &diff.only_dst {
+                println!("missing on {src}: {}", path.display());
+            }
+            for path in &diff.differ {
+                println!("mismatch: {}", path.display());
+            }
+            let problems = diff.only_src.len() + diff.only_dst.len() + diff.differ.len();
+            if problems > 0 {
+                bail!("{problems} difference(s) found, {} file(s) match", diff.identical.len());
+            }
+            println!("{} file(s) match", diff.identical.len());
+        }
+        Args::Undo(cli::Undo { run: None }) => {
+            for (id, run) in UndoLog::list()? {
+                println!("{id}: {} to {}", run.src, run.dst);
+            }
+        }
+        Args::Undo(cli::Undo { run: Some(id) }) => {
+            let (run, entries) = UndoLog::load(&id)?;
+            let dst: PrefixedPath = run.dst.parse()?;
+            let drepo = open_repo(&client, &dst, false).await?;
 
-                    drepo.list(PathBuf::from("/media/2020-04-30")).await.unwrap();
-                    drepo.create_dir(PathBuf::from("/aaa")).await.unwrap();
+            sync::undo(&drepo, entries).await?;
+            UndoLog::remove(&id)?;
+        }
+        Args::Dedupe(args) => {
+            let AnyRepo::Drive(repo) = open_repo(&client, &args.path, false).await? else {
+                bail!("Only Drive locations can have duplicates, e.g. drive:/");
+            };
+            let repo = repo.with_duplicates(Duplicates::Suffix);
+            repo.list_recursive(PathBuf::new()).await?;
+
+            let duplicates = repo.duplicates();
+            for dup in &duplicates {
+                // Trashing a folder would take its contents along, they're left to merge by hand
+                let rename = dup.folder || args.rename;
+                match rename {
+                    true => println!("rename {} to {}", dup.path.display(), dup.renamed.display()),
+                    false => println!("trash {} ({})", dup.path.display(), dup.renamed.display()),
+                }
+                if args.dry_run {
+                    continue;
+                }
+                match rename {
+                    true => drop(repo.rename(dup.renamed.clone(), dup.renamed.clone()).await?),
+                    false => drop(repo.trash(dup.renamed.clone()).await?),
+                }
+            }
+            println!("{} duplicate(s) found", duplicates.len());
+        }
+        Args::Share(cli::Share { command: Some(cli::ShareCommand::List { path }), .. }) => {
+            let (repo, path) = open_drive(&client, &path).await?;
+            for permission in repo.permissions(&path).await? {
+                let who = permission.email_address.or(permission.domain).or(permission.display_name).unwrap_or_default();
+                println!(
+                    "{}\t{}\t{} {who}",
+                    permission.id.unwrap_or_default(),
+                    permission.role.unwrap_or_default(),
+                    permission.kind.unwrap_or_default(),
+                );
+            }
+        }
+        Args::Share(cli::Share { command: Some(cli::ShareCommand::Rm { path, permission }), .. }) => {
+            let (repo, path) = open_drive(&client, &path).await?;
+            repo.unshare(&path, &permission).await?;
+            println!("Revoked {permission}");
+        }
+        Args::Share(cli::Share { command: None, path, anyone: _, user, role }) => {
+            let (repo, path) = open_drive(&client, &path.unwrap()).await?;
+            let permission = gdrive::Permission {
+                kind: Some(if user.is_some() { "user" } else { "anyone" }.to_string()),
+                role: Some(role.name().to_string()),
+                email_address: user,
+                ..Default::default()
+            };
+            let (permission, link) = repo.share(&path, permission).await?;
+            println!("Shared as permission {}", permission.id.unwrap_or_default());
+            if let Some(link) = link {
+                println!("{link}");
+            }
+        }
+        Args::Revisions(args) => {
+            let (repo, path) = open_drive(&client, &args.path).await?;
+            for revision in repo.revisions(&path).await? {
+                let modified = revision.modified_time.map(|t| t.to_rfc3339()).unwrap_or_default();
+                let size = revision.size.map(cli::format_size).unwrap_or_else(|| "-".to_string());
+                let by = revision.last_modifying_user.and_then(|u| u.email_address.or(u.display_name)).unwrap_or_default();
+                let kept = if revision.keep_forever == Some(true) { "kept forever" } else { "" };
+                println!("{}\t{modified}\t{size}\t{by}\t{kept}", revision.id.unwrap_or_default());
+            }
+        }
+        Args::Star(args) => {
+            let (repo, path) = open_drive(&client, &args.path).await?;
+            repo.set_starred(&path, !args.unstar).await?;
+            match args.unstar {
+                true => println!("Unstarred {}", args.path),
+                false => println!("Starred {}", args.path),
+            }
+        }
+        Args::Labels(args) => {
+            let (repo, path) = open_drive(&client, &args.path).await?;
+            let labels = repo.labels(&path).await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&labels)?);
+                return Ok(());
+            }
+            for label in labels {
+                println!("{}", label.id.unwrap_or_default());
+                for (field, value) in label.fields {
+                    println!("  {field}: {}", value.values().join(", "));
+                }
+            }
+        }
+        Args::Search(args) => {
+            let (repo, path) = open_drive(&client, &args.location).await?;
+            let location = args.location.to_string();
+            let location = location.trim_end_matches('/');
+            for (found, entry) in repo.search(args.drive_query()).await? {
+                if args.name.as_deref().is_some_and(|name| !crate::filter::glob_match(name, entry.name())) {
+                    continue;
+                }
+                match found.as_deref().map(|found| found.strip_prefix(&path)) {
+                    Some(Ok(found)) => println!("{location}/{}\t{}", found.display(), entry.id()),
+                    // Results without a path are only shown when searching the whole drive
+                    None if path.as_os_str().is_empty() => println!("{} (outside of {location})\t{}", entry.name(), entry.id()),
+                    _ => {}
+                }
+            }
+        }
+        Args::Cat(args) => {
+            let data = open_file(&client, &args.path, args.revision.as_deref()).await?;
+            download(data, &mut std::io::stdout().lock()).await?;
+        }
+        Args::Cp(args) => {
+            let data = open_file(&client, &args.src, args.revision.as_deref()).await?;
+            let dst = match args.dst.is_dir() {
+                true => args.dst.join(args.src.path.file_name().ok_or_else(|| format_err!("{} isn't a file", args.src))?),
+                false => args.dst,
+            };
+            download(data, &mut std::io::BufWriter::new(std::fs::File::create(&dst)?)).await?;
+            println!("Downloaded {} to {}", args.src, dst.display());
+        }
+        Args::Http(_) => unreachable!("handled before the client is built"),
+        Args::Drives(cli::SharedDrives::List { drive }) => {
+            let auth = account(&drive)?;
+            for drive in gdrive::shared_drives(&client, &auth).await? {
+                println!("{}\t{}", drive.id.unwrap_or_default(), drive.name.unwrap_or_default());
+            }
+        }
+        Args::Drives(cli::SharedDrives::Create { drive, name }) => {
+            let auth = account(&drive)?;
+            let request_id = format!("dsync-{:016x}", rand::random::<u64>());
+            let created = gdrive::builder()
+                .drives_create(request_id, &name)
+                .fields("id, name")
+                .call(&client, &auth)
+                .await?;
+            println!("Created shared drive {name}: {}", created.id.unwrap_or_default());
+        }
+        Args::Drives(cli::SharedDrives::Rm { drive, id }) => {
+            let auth = account(&drive)?;
+            gdrive::builder().drives_delete(&id).send(&client, &auth).await?;
+            println!("Deleted shared drive {id}");
+        }
+        Args::About(args) => {
+            let drives = get::<Drives>(DRIVES).unwrap_or_default();
+            let names: Vec<String> = match args.name {
+                Some(name) if !drives.contains_key(&name) => bail!("Drive not found: {name}"),
+                Some(name) => vec![name],
+                None => drives.keys().cloned().collect(),
+            };
+            let mut abouts = IndexMap::new();
+            for name in names {
+                let auth = GDriveAuthorizer { name: name.clone(), lock: Default::default() };
+                let about = gdrive::builder()
+                    .about_get()
+                    .fields("user(displayName, emailAddress), storageQuota")
+                    .call(&client, &auth)
+                    .await?;
+                abouts.insert(name, about);
+            }
 
-                    sync(srepo, drepo).await?
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&abouts)?);
+                return Ok(());
+            }
+            for (name, about) in abouts {
+                let user = about.user.and_then(|u| u.email_address.or(u.display_name)).unwrap_or_default();
+                let Some(quota) = about.storage_quota else {
+                    println!("{name} ({user}): quota unknown");
+                    continue;
+                };
+                let usage = cli::format_size(quota.usage.unwrap_or_default());
+                let limit = quota.limit.map(cli::format_size).unwrap_or_else(|| "unlimited".to_string());
+                println!("{name} ({user}): {usage} of {limit} used");
+                println!("  in Drive: {}", cli::format_size(quota.usage_in_drive.unwrap_or_default()));
+                println!("  in trash: {}", cli::format_size(quota.usage_in_drive_trash.unwrap_or_default()));
+                if let Some(limit) = quota.limit {
+                    println!("  free:     {}", cli::format_size(limit.saturating_sub(quota.usage.unwrap_or_default())));
                 }
-                _ => {
-                    panic!("Exactly one location must have <drive>: prefix")
+            }
+        }
+        Args::Filter(cli::Filter::Test { paths, filter }) => {
+            let filter = filter.filter()?.unwrap_or_default();
+            let paths = match paths.is_empty() {
+                true => cli::read_paths(std::io::stdin().lock())?,
+                false => paths,
+            };
+            for path in paths {
+                let is_dir = path.to_string_lossy().ends_with('/');
+                let components: Vec<_> = path.components().collect();
+
+                // Entries of excluded directories are never looked at, so check parents first
+                let mut current = PathBuf::new();
+                for (idx, component) in components.iter().enumerate() {
+                    current.push(component);
+                    let last = idx + 1 == components.len();
+                    let decision = filter.decide(&current, is_dir || !last);
+                    if decision.included && !last {
+                        continue;
+                    }
+                    let sign = if decision.included { '+' } else { '-' };
+                    let rule = decision.rule.unwrap_or("no rule matches");
+                    match last {
+                        true => println!("{sign} {}\t{rule}", path.display()),
+                        false => println!("{sign} {}\t{rule}, on {}/", path.display(), current.display()),
+                    }
+                    break;
                 }
             }
-            return Ok(());
         }
     }
 
-    // let root = builder()
-    //     .file_get("root")
-    //     .fields("id, name")
-    //     // .query("mimeType = 'application/vnd.google-apps.folder' and 'root' in parents")
-    //     .access_token(token.access_token().secret())
-    //     .call(&client)
-    //     .await?;
-
-    // let folders = builder()
-    //     .files_list()
-    //     .fields("files(id, size, name, sha256Checksum, parents)")
-    //     .query("mimeType = 'application/vnd.google-apps.folder' and 'root' in parents")
-    //     .access_token(token.access_token().secret())
-    //     .call(&client)
-    //     .await?;
-    //
-    // let files = builder()
-    //     .files_list()
-    //     .fields("files(id, size, name, sha256Checksum, parents)")
-    //     .access_token(token.access_token().secret())
-    //     .call(&client)
-    //     .await?;
-
-    // println!("{:#?}", root);
-
-    // let out = client.request(Method::POST, format!("https://www.googleapis.com/upload/drive/v3/files?uploadType=resumable"))
-    //     .query(&json!({"fields": "maxUploadSize"}))
-    //     .header(AUTHORIZATION, format!("Bearer {}", token.access_token().secret()))
-    //     .send()
-    //     .await
-    //     .unwrap()
-    //     .json::<serde_json::Value>()
-    //     .await
-    //     .unwrap();
-
     Ok(())
-
-    // let client = reqwest::ClientBuilder::new().build()?;
-    // match args {
-    //     Args::Copy(Copy { src, dst }) => {}
-    // }
-    //
-    // Ok(())
 }
@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::TryStreamExt;
+use sha2::Digest;
+use crate::repo::{Capabilities, Checksum, Dir, RepoError, RepoResult, Entry, File, FileSource, MemoryFile, Repo, LINK};
+
+/// Repo held entirely in memory, for trying out syncs without touching real storage
+#[derive(Default)]
+pub struct MemoryRepo {
+    /// Every entry except for the root, by path
+    entries: Mutex<BTreeMap<PathBuf, Node>>,
+    /// Trashed entries, their index is the id they're restored by
+    trash: Mutex<Vec<Option<Subtree>>>,
+}
+
+/// Entries under some path, relative to it
+type Subtree = Vec<(PathBuf, Node)>;
+
+enum Node {
+    Dir,
+    File {
+        data: Vec<u8>,
+        modified: DateTime<Utc>,
+        metadata: BTreeMap<String, String>,
+    },
+}
+
+impl MemoryRepo {
+    /// A small tree of made up files
+    pub fn sample() -> Self {
+        let repo = Self::default();
+        let day = TimeDelta::days(1);
+        repo.insert("README.md", b"# Sample files\n".to_vec(), Utc::now() - day * 30);
+        repo.insert("notes/todo.txt", b"- sync everything\n".to_vec(), Utc::now() - day * 2);
+        repo.insert("notes/ideas.txt", b"- more syncing\n".to_vec(), Utc::now() - day * 7);
+        repo.insert("photos/2023/beach.jpg", vec![0xff; 64 << 10], Utc::now() - day * 400);
+        repo.insert("photos/2024/mountains.jpg", vec![0xd8; 128 << 10], Utc::now() - day * 90);
+        repo
+    }
+
+    /// Add a file, along with its parent directories
+    pub fn insert(&self, path: impl Into<PathBuf>, data: Vec<u8>, modified: DateTime<Utc>) {
+        let path = path.into();
+        let mut entries = self.entries.lock().unwrap();
+        for dir in path.ancestors().skip(1).filter(|p| !p.as_os_str().is_empty()) {
+            entries.insert(dir.to_owned(), Node::Dir);
+        }
+        entries.insert(path, Node::File { data, modified, metadata: Default::default() });
+    }
+
+    fn entry(path: &Path, node: &Node) -> Entry {
+        let id = path.to_string_lossy().into_owned();
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        match node {
+            Node::Dir => Entry::Dir(Dir { id, name }),
+            Node::File { data, modified, metadata } => Entry::File(File {
+                id,
+                name,
+                shasum: Some(hex::encode(sha2::Sha256::digest(data))),
+                size: data.len() as u64,
+                modified: Some(*modified),
+                created: None,
+                metadata: metadata.clone(),
+            }),
+        }
+    }
+
+    fn file(&self, path: &Path) -> RepoResult<File> {
+        match self.entries.lock().unwrap().get(path) {
+            Some(node @ Node::File { .. }) => match Self::entry(path, node) {
+                Entry::File(file) => Ok(file),
+                Entry::Dir(..) => unreachable!(),
+            },
+            _ => Err(RepoError::NotFound(format!("{path:?}"))),
+        }
+    }
+
+    /// Remove `path` and everything under it
+    fn take(entries: &mut BTreeMap<PathBuf, Node>, path: &Path) -> RepoResult<Subtree> {
+        let taken: Vec<PathBuf> = entries.keys().filter(|p| p.starts_with(path)).cloned().collect();
+        if taken.is_empty() {
+            return Err(RepoError::NotFound(format!("{path:?}")));
+        }
+        Ok(taken.into_iter()
+            .map(|p| {
+                let node = entries.remove(&p).unwrap();
+                (p.strip_prefix(path).unwrap().to_owned(), node)
+            })
+            .collect())
+    }
+
+    /// Make sure the parent of `path` exists and is a directory
+    fn check_parent(entries: &BTreeMap<PathBuf, Node>, path: &Path) -> RepoResult<()> {
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() && !matches!(entries.get(dir), Some(Node::Dir)) => {
+                Err(RepoError::NotFound(format!("{dir:?}")))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Repo for MemoryRepo {
+    type Source = MemoryFile;
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            server_copy: true,
+            server_move: true,
+            checksums: &[Checksum::Sha256, Checksum::Md5, Checksum::Blake3],
+            set_modified: true,
+            unknown_length: true,
+        }
+    }
+
+    async fn list(&self, path: PathBuf) -> RepoResult<Vec<Entry>> {
+        let entries = self.entries.lock().unwrap();
+        if !path.as_os_str().is_empty() && !matches!(entries.get(&path), Some(Node::Dir)) {
+            return Err(RepoError::NotFound(format!("{path:?}")));
+        }
+        Ok(entries.iter()
+            .filter(|(p, _)| p.parent() == Some(&path))
+            .map(|(p, node)| Self::entry(p, node))
+            .collect())
+    }
+
+    async fn stat(&self, path: PathBuf) -> RepoResult<Option<Entry>> {
+        if path.as_os_str().is_empty() {
+            return Ok(Some(Self::entry(&path, &Node::Dir)));
+        }
+        Ok(self.entries.lock().unwrap().get(&path).map(|node| Self::entry(&path, node)))
+    }
+
+    async fn create_dir(&self, path: PathBuf) -> RepoResult<()> {
+        let mut entries = self.entries.lock().unwrap();
+        for dir in path.ancestors().filter(|p| !p.as_os_str().is_empty()) {
+            match entries.get(dir) {
+                Some(Node::File { .. }) => return Err(RepoError::Conflict(format!("Cannot create {path:?}, {dir:?} is a file"))),
+                Some(Node::Dir) => break,
+                None => {
+                    entries.insert(dir.to_owned(), Node::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_file(&self, path: PathBuf) -> RepoResult<MemoryFile> {
+        match self.entries.lock().unwrap().get(&path) {
+            Some(Node::File { data, .. }) => Ok(MemoryFile(data.clone())),
+            _ => Err(RepoError::NotFound(format!("{path:?}"))),
+        }
+    }
+
+    async fn write_file(&self, path: PathBuf, data: impl FileSource) -> RepoResult<File> {
+        let modified = data.modified().unwrap_or_else(Utc::now);
+        let data: Vec<Vec<u8>> = data.stream(0, 1 << 20).try_collect().await?;
+        {
+            let mut entries = self.entries.lock().unwrap();
+            Self::check_parent(&entries, &path)?;
+            if let Some(Node::Dir) = entries.get(&path) {
+                return Err(RepoError::Conflict(format!("Cannot write {path:?}, it is a directory")));
+            }
+            entries.insert(path.clone(), Node::File { data: data.concat(), modified, metadata: Default::default() });
+        }
+        self.file(&path)
+    }
+
+    async fn checksum(&self, path: PathBuf, algorithm: Checksum) -> RepoResult<String> {
+        match self.entries.lock().unwrap().get(&path) {
+            Some(Node::File { data, .. }) => {
+                let mut hasher = algorithm.hasher();
+                hasher.update(data);
+                Ok(hasher.finish())
+            }
+            _ => Err(RepoError::NotFound(format!("{path:?}"))),
+        }
+    }
+
+    async fn copy_file(&self, source: PathBuf, dest: PathBuf) -> RepoResult<File> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            Self::check_parent(&entries, &dest)?;
+            let data = match entries.get(&source) {
+                Some(Node::File { data, .. }) => data.clone(),
+                _ => return Err(RepoError::NotFound(format!("{source:?}"))),
+            };
+            entries.insert(dest.clone(), Node::File { data, modified: Utc::now(), metadata: Default::default() });
+        }
+        self.file(&dest)
+    }
+
+    /// The trash lives as long as the repo does
+    async fn trash(&self, path: PathBuf) -> RepoResult<String> {
+        let taken = Self::take(&mut self.entries.lock().unwrap(), &path)?;
+        let mut trash = self.trash.lock().unwrap();
+        trash.push(Some(taken));
+        Ok((trash.len() - 1).to_string())
+    }
+
+    async fn restore(&self, id: &str, path: PathBuf) -> RepoResult<()> {
+        let taken = id.parse().ok()
+            .and_then(|id: usize| self.trash.lock().unwrap().get_mut(id)?.take())
+            .ok_or_else(|| RepoError::NotFound(format!("Trashed entry {id}")))?;
+        if self.stat(path.clone()).await?.is_some() {
+            self.trash(path.clone()).await?;
+        }
+        if let Some(dir) = path.parent() {
+            self.create_dir(dir.to_owned()).await?;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        for (rel, node) in taken {
+            entries.insert(if rel.as_os_str().is_empty() { path.clone() } else { path.join(rel) }, node);
+        }
+        Ok(())
+    }
+
+    async fn delete_permanent(&self, path: PathBuf) -> RepoResult<()> {
+        Self::take(&mut self.entries.lock().unwrap(), &path).map(drop)
+    }
+
+    async fn rename(&self, from: PathBuf, to: PathBuf) -> RepoResult<Entry> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            Self::check_parent(&entries, &to)?;
+            if entries.contains_key(&to) {
+                return Err(RepoError::Conflict(format!("Cannot move {from:?}, {to:?} exists")));
+            }
+            let moved: Vec<PathBuf> = entries.keys().filter(|p| p.starts_with(&from)).cloned().collect();
+            if moved.is_empty() {
+                return Err(RepoError::NotFound(format!("{from:?}")));
+            }
+            for path in moved {
+                let node = entries.remove(&path).unwrap();
+                entries.insert(to.join(path.strip_prefix(&from).unwrap()), node);
+            }
+        }
+        Ok(self.stat(to.clone()).await?.unwrap())
+    }
+
+    async fn set_modified(&self, path: PathBuf, modified: DateTime<Utc>) -> RepoResult<File> {
+        match self.entries.lock().unwrap().get_mut(&path) {
+            Some(Node::File { modified: current, .. }) => *current = modified,
+            _ => return Err(RepoError::NotFound(format!("{path:?}"))),
+        }
+        self.file(&path)
+    }
+
+    async fn set_metadata(&self, path: PathBuf, metadata: &BTreeMap<String, String>) -> RepoResult<()> {
+        match self.entries.lock().unwrap().get_mut(&path) {
+            Some(Node::File { metadata: current, .. }) => current.extend(metadata.clone()),
+            _ => return Err(RepoError::NotFound(format!("{path:?}"))),
+        }
+        Ok(())
+    }
+
+    /// Links are files without contents, pointing elsewhere through their metadata
+    async fn create_link(&self, path: PathBuf, target: PathBuf) -> RepoResult<File> {
+        {
+            let mut entries = self.entries.lock().unwrap();
+            Self::check_parent(&entries, &path)?;
+            if let Some(Node::Dir) = entries.get(&path) {
+                return Err(RepoError::Conflict(format!("Cannot link {path:?}, it is a directory")));
+            }
+            let metadata = BTreeMap::from([(LINK.to_string(), target.to_string_lossy().into_owned())]);
+            entries.insert(path.clone(), Node::File { data: vec![], modified: Utc::now(), metadata });
+        }
+        self.file(&path)
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use unicode_normalization::UnicodeNormalization;
+use crate::repo::{Capabilities, Checksum, RepoError, RepoResult, Entry, File, FileSource, Repo};
+
+/// Unicode form names are compared in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Normalization {
+    /// Compare names as they are
+    #[default]
+    None,
+    /// Composed form, used by most Linux and Windows tools and usually by Drive
+    Nfc,
+    /// Decomposed form, used by macOS
+    Nfd,
+}
+
+impl Normalization {
+    pub fn apply(self, name: &str) -> String {
+        match self {
+            Normalization::None => name.to_owned(),
+            Normalization::Nfc => name.nfc().collect(),
+            Normalization::Nfd => name.nfd().collect(),
+        }
+    }
+}
+
+/// Presents names of the wrapped repo in a single unicode form, so that names differing only
+/// by normalization match across repos. Paths are mapped back to the names they were listed
+/// with, new entries are created with normalized names.
+pub struct Normalized<R> {
+    inner: R,
+    form: Normalization,
+    /// Listed names that aren't normalized, keyed by their normalized path
+    names: Mutex<HashMap<PathBuf, String>>,
+    /// Directories whose names are known, because they were listed
+    listed: Mutex<HashSet<PathBuf>>,
+}
+
+impl<R: Repo> Normalized<R> {
+    pub fn new(inner: R, form: Normalization) -> Self {
+        Self { inner, form, names: Default::default(), listed: Default::default() }
+    }
+
+    /// The path of an entry in the wrapped repo, listing the directories leading to it first
+    /// when they weren't yet, like when applying a saved plan
+    async fn resolve(&self, path: &Path) -> RepoResult<PathBuf> {
+        if self.form != Normalization::None {
+            let dirs: Vec<&Path> = path.ancestors().skip(1).collect();
+            for dir in dirs.into_iter().rev() {
+                if self.listed.lock().unwrap().contains(dir) {
+                    continue;
+                }
+                match self.list(dir.to_owned()).await {
+                    // Entries below are about to be created
+                    Err(RepoError::NotFound(..)) => break,
+                    res => drop(res?),
+                }
+            }
+        }
+        Ok(self.real(path))
+    }
+
+    /// The path of an entry in the wrapped repo
+    fn real(&self, path: &Path) -> PathBuf {
+        let names = self.names.lock().unwrap();
+        let mut normalized = PathBuf::new();
+        let mut real = PathBuf::new();
+        for part in path.components() {
+            normalized.push(part);
+            match names.get(&normalized) {
+                Some(name) => real.push(name),
+                None => real.push(part),
+            }
+        }
+        real
+    }
+
+    /// Normalize the name of an entry listed in `dir`, `None` when another entry already has
+    /// the same normalized path
+    fn listed(&self, dir: &Path, mut entry: Entry, seen: &mut HashSet<PathBuf>) -> Option<Entry> {
+        let name = self.form.apply(entry.name());
+        let path = dir.join(&name);
+        if !seen.insert(path.clone()) {
+            warn!("Skipping {:?}, its name only differs by unicode normalization from another entry", dir.join(entry.name()));
+            return None;
+        }
+        if name != entry.name() {
+            info!("{:?} differs from {path:?} only by unicode normalization", dir.join(entry.name()));
+            self.names.lock().unwrap().insert(path, entry.name().to_owned());
+            match &mut entry {
+                Entry::Dir(dir) => dir.name = name,
+                Entry::File(file) => file.name = name,
+            }
+        }
+        Some(entry)
+    }
+
+    fn renamed(&self, mut file: File, path: &Path) -> File {
+        file.name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        file
+    }
+}
+
+impl<R: Repo> Repo for Normalized<R> {
+    type Source = R::Source;
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    async fn list(&self, path: PathBuf) -> RepoResult<Vec<Entry>> {
+        let entries = self.inner.list(self.real(&path)).await?;
+        if self.form == Normalization::None {
+            return Ok(entries);
+        }
+        let mut seen = HashSet::new();
+        let entries = entries.into_iter().filter_map(|e| self.listed(&path, e, &mut seen)).collect();
+        self.listed.lock().unwrap().insert(path);
+        Ok(entries)
+    }
+
+    async fn list_recursive(&self, path: PathBuf) -> RepoResult<Vec<(PathBuf, Entry)>> {
+        let entries = self.inner.list_recursive(self.real(&path)).await?;
+        if self.form == Normalization::None {
+            return Ok(entries);
+        }
+        let mut seen = HashSet::new();
+        let entries: Vec<(PathBuf, Entry)> = entries.into_iter()
+            .filter_map(|(path, entry)| {
+                let dir: PathBuf = path.parent()
+                    .unwrap_or(Path::new(""))
+                    .iter()
+                    .map(|part| self.form.apply(&part.to_string_lossy()))
+                    .collect();
+                let entry = self.listed(&dir, entry, &mut seen)?;
+                Some((dir.join(entry.name()), entry))
+            })
+            .collect();
+        let mut listed = self.listed.lock().unwrap();
+        listed.insert(path);
+        listed.extend(entries.iter().filter(|(_, e)| matches!(e, Entry::Dir(..))).map(|(path, _)| path.clone()));
+        drop(listed);
+        Ok(entries)
+    }
+
+    async fn stat(&self, path: PathBuf) -> RepoResult<Option<Entry>> {
+        let entry = self.inner.stat(self.resolve(&path).await?).await?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        Ok(entry.and_then(|e| self.listed(dir, e, &mut HashSet::new())))
+    }
+
+    async fn create_dir(&self, path: PathBuf) -> RepoResult<()> {
+        self.inner.create_dir(self.resolve(&path).await?).await
+    }
+
+    async fn read_file(&self, path: PathBuf) -> RepoResult<Self::Source> {
+        self.inner.read_file(self.resolve(&path).await?).await
+    }
+
+    async fn write_file(&self, path: PathBuf, data: impl FileSource) -> RepoResult<File> {
+        let file = self.inner.write_file(self.resolve(&path).await?, data).await?;
+        Ok(self.renamed(file, &path))
+    }
+
+    async fn checksum(&self, path: PathBuf, algorithm: Checksum) -> RepoResult<String> {
+        self.inner.checksum(self.resolve(&path).await?, algorithm).await
+    }
+
+    async fn copy_file(&self, source: PathBuf, dest: PathBuf) -> RepoResult<File> {
+        let file = self.inner.copy_file(self.resolve(&source).await?, self.resolve(&dest).await?).await?;
+        Ok(self.renamed(file, &dest))
+    }
+
+    async fn trash(&self, path: PathBuf) -> RepoResult<String> {
+        self.inner.trash(self.resolve(&path).await?).await
+    }
+
+    async fn keep_version(&self, path: PathBuf) -> RepoResult<String> {
+        self.inner.keep_version(self.resolve(&path).await?).await
+    }
+
+    async fn restore(&self, id: &str, path: PathBuf) -> RepoResult<()> {
+        self.inner.restore(id, self.resolve(&path).await?).await
+    }
+
+    async fn delete_permanent(&self, path: PathBuf) -> RepoResult<()> {
+        self.inner.delete_permanent(self.resolve(&path).await?).await
+    }
+
+    async fn rename(&self, from: PathBuf, to: PathBuf) -> RepoResult<Entry> {
+        let mut entry = self.inner.rename(self.resolve(&from).await?, self.resolve(&to).await?).await?;
+        let name = to.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        match &mut entry {
+            Entry::Dir(dir) => dir.name = name,
+            Entry::File(file) => file.name = name,
+        }
+        Ok(entry)
+    }
+
+    async fn set_modified(&self, path: PathBuf, modified: DateTime<Utc>) -> RepoResult<File> {
+        let file = self.inner.set_modified(self.resolve(&path).await?, modified).await?;
+        Ok(self.renamed(file, &path))
+    }
+
+    async fn set_metadata(&self, path: PathBuf, metadata: &BTreeMap<String, String>) -> RepoResult<()> {
+        self.inner.set_metadata(self.resolve(&path).await?, metadata).await
+    }
+
+    async fn create_link(&self, path: PathBuf, target: PathBuf) -> RepoResult<File> {
+        let file = self.inner.create_link(self.resolve(&path).await?, target).await?;
+        Ok(self.renamed(file, &path))
+    }
+
+    async fn copy_from(&self, id: &str, dest: PathBuf) -> RepoResult<File> {
+        let file = self.inner.copy_from(id, self.resolve(&dest).await?).await?;
+        Ok(self.renamed(file, &dest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryRepo;
+    use crate::repo::MemoryFile;
+
+    const NFC: &str = "caf\u{e9}";
+    const NFD: &str = "cafe\u{301}";
+
+    fn repo(paths: &[&str]) -> Normalized<MemoryRepo> {
+        let inner = MemoryRepo::default();
+        for path in paths {
+            inner.insert(*path, path.as_bytes().to_vec(), Utc::now());
+        }
+        Normalized::new(inner, Normalization::Nfc)
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name()).collect()
+    }
+
+    #[test]
+    fn applies_forms() {
+        assert_eq!(Normalization::Nfc.apply(NFD), NFC);
+        assert_eq!(Normalization::Nfd.apply(NFC), NFD);
+        assert_eq!(Normalization::None.apply(NFD), NFD);
+    }
+
+    #[tokio::test]
+    async fn maps_listed_names_back() {
+        let repo = repo(&[&format!("{NFD}/{NFD}.txt")]);
+        assert_eq!(names(&repo.list("".into()).await.unwrap()), [NFC]);
+        let dir = PathBuf::from(NFC);
+        assert_eq!(names(&repo.list(dir.clone()).await.unwrap()), [format!("{NFC}.txt")]);
+
+        let path = dir.join(format!("{NFC}.txt"));
+        assert_eq!(repo.read_file(path.clone()).await.unwrap().0, format!("{NFD}/{NFD}.txt").into_bytes());
+        let written = repo.write_file(path, MemoryFile(b"new".to_vec())).await.unwrap();
+        assert_eq!(written.name, format!("{NFC}.txt"));
+        assert_eq!(repo.inner.read_file(format!("{NFD}/{NFD}.txt").into()).await.unwrap().0, b"new");
+    }
+
+    #[tokio::test]
+    async fn resolves_paths_without_listing() {
+        let repo = repo(&[&format!("{NFD}/a.txt")]);
+        let path = PathBuf::from(NFC).join("a.txt");
+        assert!(repo.stat(path.clone()).await.unwrap().is_some());
+
+        // New entries keep the normalized name
+        repo.create_dir("new".into()).await.unwrap();
+        repo.write_file(PathBuf::from("new").join(NFC), MemoryFile(b"x".to_vec())).await.unwrap();
+        assert!(repo.inner.stat(PathBuf::from("new").join(NFC)).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn skips_names_differing_only_by_form() {
+        let repo = repo(&[NFC, NFD]);
+        assert_eq!(repo.list("".into()).await.unwrap().len(), 1);
+        let recursive = repo.list_recursive("".into()).await.unwrap();
+        assert_eq!(recursive.len(), 1);
+        assert_eq!(recursive[0].0, Path::new(NFC));
+    }
+}
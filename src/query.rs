@@ -0,0 +1,221 @@
+use std::fmt::{Display, Formatter};
+use std::ops::Not;
+use chrono::{DateTime, SecondsFormat, Utc};
+
+/// Condition of a `files.list` query. Values are quoted and escaped when the query is written
+/// out, so names containing quotes or backslashes match as they are instead of breaking it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    /// `field op 'value'`
+    Compare { field: &'static str, op: &'static str, value: String },
+    /// `'value' in field`, for collections like parents and owners
+    In { value: String, field: &'static str },
+    /// `field = true`, for boolean fields
+    Flag { field: &'static str, value: bool },
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    /// Condition given by the user, used as it is
+    Raw(String),
+}
+
+impl Query {
+    pub fn name(name: &str) -> Self {
+        Self::Compare { field: "name", op: "=", value: name.to_owned() }
+    }
+
+    /// Names starting with `prefix`, or containing a word starting with it
+    pub fn name_contains(prefix: &str) -> Self {
+        Self::Compare { field: "name", op: "contains", value: prefix.to_owned() }
+    }
+
+    pub fn mime(mime: &str) -> Self {
+        Self::Compare { field: "mimeType", op: "=", value: mime.to_owned() }
+    }
+
+    pub fn mime_contains(part: &str) -> Self {
+        Self::Compare { field: "mimeType", op: "contains", value: part.to_owned() }
+    }
+
+    /// Entries directly within the folder `id`
+    pub fn parent(id: &str) -> Self {
+        Self::In { value: id.to_owned(), field: "parents" }
+    }
+
+    pub fn owned_by_me() -> Self {
+        Self::In { value: "me".to_owned(), field: "owners" }
+    }
+
+    pub fn shared_with_me() -> Self {
+        Self::Flag { field: "sharedWithMe", value: true }
+    }
+
+    pub fn not_trashed() -> Self {
+        Self::Flag { field: "trashed", value: false }
+    }
+
+    pub fn modified_after(time: DateTime<Utc>) -> Self {
+        Self::Compare { field: "modifiedTime", op: ">", value: time.to_rfc3339_opts(SecondsFormat::Secs, true) }
+    }
+
+    pub fn modified_before(time: DateTime<Utc>) -> Self {
+        Self::Compare { field: "modifiedTime", op: "<", value: time.to_rfc3339_opts(SecondsFormat::Secs, true) }
+    }
+
+    /// Condition narrowing results to names that can match a glob pattern, names are still
+    /// matched against the pattern itself. `None` when the pattern starts with a wildcard.
+    pub fn name_glob(pattern: &str) -> Option<Self> {
+        match pattern.find(['*', '?', '[']) {
+            None => Some(Self::name(pattern)),
+            Some(0) => None,
+            Some(idx) => Some(Self::name_contains(&pattern[..idx])),
+        }
+    }
+
+    /// Condition for `--include-mime` and `--exclude-mime` patterns, a trailing `*` matches any
+    /// mime type with the given prefix
+    pub fn mime_patterns(include: &[String], exclude: &[String]) -> Option<Self> {
+        fn condition(pattern: &str) -> Query {
+            match pattern.strip_suffix('*') {
+                Some(prefix) => Query::mime_contains(prefix),
+                None => Query::mime(pattern),
+            }
+        }
+
+        let include = Self::any(include.iter().map(|p| condition(p)));
+        Self::all(include.into_iter().chain(exclude.iter().map(|p| !condition(p))))
+    }
+
+    /// Both conditions
+    pub fn and(self, other: Query) -> Self {
+        match self {
+            Self::And(mut all) => {
+                all.push(other);
+                Self::And(all)
+            }
+            this => Self::And(vec![this, other]),
+        }
+    }
+
+    /// Either of the conditions
+    pub fn or(self, other: Query) -> Self {
+        match self {
+            Self::Or(mut any) => {
+                any.push(other);
+                Self::Or(any)
+            }
+            this => Self::Or(vec![this, other]),
+        }
+    }
+
+    /// All of the conditions, `None` when there are none
+    pub fn all(conditions: impl IntoIterator<Item = Query>) -> Option<Self> {
+        conditions.into_iter().reduce(Self::and)
+    }
+
+    /// Any of the conditions, `None` when there are none
+    pub fn any(conditions: impl IntoIterator<Item = Query>) -> Option<Self> {
+        conditions.into_iter().reduce(Self::or)
+    }
+
+    /// Whether the condition needs parentheses when combined with others
+    fn compound(&self) -> bool {
+        matches!(self, Self::And(..) | Self::Or(..) | Self::Raw(..))
+    }
+
+    fn operand(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.compound() {
+            true => write!(f, "({self})"),
+            false => write!(f, "{self}"),
+        }
+    }
+}
+
+impl Not for Query {
+    type Output = Query;
+
+    fn not(self) -> Query {
+        match self {
+            Self::Not(inner) => *inner,
+            Self::Flag { field, value } => Self::Flag { field, value: !value },
+            this => Self::Not(Box::new(this)),
+        }
+    }
+}
+
+/// Quote a value, escaping quotes and backslashes within it
+fn quoted(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Compare { field, op, value } => write!(f, "{field} {op} {}", quoted(value)),
+            Self::In { value, field } => write!(f, "{} in {field}", quoted(value)),
+            Self::Flag { field, value } => write!(f, "{field} = {value}"),
+            Self::And(all) | Self::Or(all) => {
+                let sep = match self {
+                    Self::And(..) => " and ",
+                    _ => " or ",
+                };
+                for (idx, condition) in all.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(sep)?;
+                    }
+                    condition.operand(f)?;
+                }
+                Ok(())
+            }
+            // Drive only allows `not` in front of some conditions, equality has an operator instead
+            Self::Not(inner) => match &**inner {
+                Self::Compare { field, op: "=", value } => write!(f, "{field} != {}", quoted(value)),
+                inner => {
+                    f.write_str("not ")?;
+                    inner.operand(f)
+                }
+            },
+            Self::Raw(query) => f.write_str(query),
+        }
+    }
+}
+
+impl From<Query> for String {
+    fn from(query: Query) -> String {
+        query.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_values() {
+        assert_eq!(Query::name("it's").to_string(), r"name = 'it\'s'");
+        assert_eq!(Query::name(r"a\b").to_string(), r"name = 'a\\b'");
+        assert_eq!(Query::name(r"\'").to_string(), r"name = '\\\''");
+        assert_eq!(Query::parent("x'y").to_string(), r"'x\'y' in parents");
+    }
+
+    #[test]
+    fn combines_conditions() {
+        let query = Query::name("a").or(Query::name("b")).and(Query::not_trashed());
+        assert_eq!(query.to_string(), "(name = 'a' or name = 'b') and trashed = false");
+        assert_eq!((!Query::name("a")).to_string(), "name != 'a'");
+        assert_eq!((!Query::not_trashed()).to_string(), "trashed = true");
+        assert_eq!((!Query::mime_contains("image/")).to_string(), "not mimeType contains 'image/'");
+        assert_eq!(Query::Raw("a or b".into()).and(Query::owned_by_me()).to_string(), "(a or b) and 'me' in owners");
+    }
+
+    #[test]
+    fn globs_and_mime_patterns() {
+        assert_eq!(Query::name_glob("a.txt"), Some(Query::name("a.txt")));
+        assert_eq!(Query::name_glob("rep*.pdf"), Some(Query::name_contains("rep")));
+        assert_eq!(Query::name_glob("*.pdf"), None);
+
+        let query = Query::mime_patterns(&["image/*".into(), "text/plain".into()], &["image/gif".into()]).unwrap();
+        assert_eq!(query.to_string(), "(mimeType contains 'image/' or mimeType = 'text/plain') and mimeType != 'image/gif'");
+        assert_eq!(Query::mime_patterns(&[], &[]), None);
+    }
+}
@@ -1,28 +1,62 @@
-use std::collections::HashMap;
 use std::future::Future;
-use std::path::{Path, PathBuf};
-use futures::Stream;
+use std::io::{Seek, SeekFrom, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use futures::future::Either;
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use anyhow::{bail, format_err};
+use hyper::StatusCode;
 use sha2::Digest;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+use crate::gdrive::{ApiError, Authorizer, ChecksumMismatch, Duplicates, GDriveRepo, LabelModification};
+use crate::memory::MemoryRepo;
+use crate::sanitize;
+use crate::trash;
+use crate::state::{HashCache, PartialTransfer};
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Dir {
     pub id: String,
     pub name: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct File {
     pub id: String,
     pub name: String,
-    pub shasum: String,
+    /// Not every backend knows the checksum upfront, see [`Repo::shasum`]
+    pub shasum: Option<String>,
     pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTime<Utc>>,
+    /// Backend specific properties, custom file properties on Drive
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
 }
 
+#[derive(Debug, Clone)]
 pub enum Entry {
     Dir(Dir),
     File(File),
 }
 
 impl Entry {
-    fn name(&self) -> &str {
+    pub fn id(&self) -> &str {
+        match self {
+            Entry::Dir(Dir { id, .. }) => id,
+            Entry::File(File { id, .. }) => id,
+        }
+    }
+
+    pub fn name(&self) -> &str {
         match self {
             Entry::Dir(Dir { name, .. }) => name,
             Entry::File(File { name, .. }) => name,
@@ -30,119 +64,1400 @@ impl Entry {
     }
 }
 
+/// Failure of a repo operation, classified so that callers can react to each kind differently
+#[derive(Debug)]
+pub enum RepoError {
+    NotFound(String),
+    PermissionDenied(String),
+    /// The destination is out of storage
+    QuotaExceeded(String),
+    /// Too many requests, retrying later helps
+    RateLimited(String),
+    /// The entry exists, or changed in a way the operation didn't expect
+    Conflict(String),
+    Io(std::io::Error),
+    /// Unsuccessful API response that doesn't fit any of the other kinds
+    Api { status: StatusCode, body: String },
+    /// A request creating an entry got no definite answer, the entry may exist anyway. Not
+    /// retryable since trying again could create it twice.
+    Unconfirmed(anyhow::Error),
+    Other(anyhow::Error),
+}
+
+pub type RepoResult<T> = Result<T, RepoError>;
+
+impl RepoError {
+    /// Whether retrying the operation later may help: rate limiting, server errors, broken
+    /// connections and corrupted uploads. Rejected access tokens are already refreshed when sending requests.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            RepoError::RateLimited(..) => true,
+            RepoError::Api { status, .. } => status.is_server_error(),
+            RepoError::Io(e) => retryable(e),
+            RepoError::Other(e) => e.chain().any(retryable),
+            _ => false,
+        }
+    }
+
+    /// Whether no other operation on the same repo can succeed either, so the run should stop
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, RepoError::QuotaExceeded(..))
+    }
+}
+
+/// Whether retrying an operation that failed with `cause`, or any error wrapping it, may help
+pub fn retryable(cause: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(e) = cause.downcast_ref::<RepoError>() {
+        return e.is_retryable();
+    }
+    if let Some(e) = cause.downcast_ref::<ApiError>() {
+        return e.transient();
+    }
+    // Contents can get corrupted on the way, sending them again should fix it
+    if cause.is::<ChecksumMismatch>() {
+        return true;
+    }
+    if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+        return e.is_timeout() || e.is_connect() || e.is_body();
+    }
+    if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+        use std::io::ErrorKind::*;
+        return matches!(e.kind(), Interrupted | TimedOut | ConnectionReset | ConnectionAborted | BrokenPipe | UnexpectedEof);
+    }
+    false
+}
+
+impl Display for RepoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepoError::NotFound(msg) => write!(f, "Not found: {msg}"),
+            RepoError::PermissionDenied(msg) => write!(f, "Permission denied: {msg}"),
+            RepoError::QuotaExceeded(msg) => write!(f, "Quota exceeded: {msg}"),
+            RepoError::RateLimited(msg) => write!(f, "Rate limited: {msg}"),
+            RepoError::Conflict(msg) => write!(f, "Conflict: {msg}"),
+            RepoError::Io(e) => write!(f, "{e}"),
+            RepoError::Api { status, body } => write!(f, "Request failed with {status}: {body}"),
+            RepoError::Unconfirmed(e) => write!(f, "{e:#}, the entry may have been created anyway"),
+            RepoError::Other(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for RepoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RepoError::Io(e) => Some(e),
+            RepoError::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for RepoError {
+    fn from(e: std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::NotFound => RepoError::NotFound(e.to_string()),
+            ErrorKind::PermissionDenied => RepoError::PermissionDenied(e.to_string()),
+            ErrorKind::AlreadyExists => RepoError::Conflict(e.to_string()),
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded => RepoError::QuotaExceeded(e.to_string()),
+            _ => RepoError::Io(e),
+        }
+    }
+}
+
+impl From<ApiError> for RepoError {
+    fn from(e: ApiError) -> Self {
+        // Reported as `storageQuotaExceeded` or `quotaExceeded`
+        let quota = e.reason.as_deref().is_some_and(|r| r.to_lowercase().contains("quotaexceeded"));
+        let transient = e.transient();
+        let message = e.message.unwrap_or(e.body);
+        match e.status {
+            StatusCode::NOT_FOUND => RepoError::NotFound(message),
+            StatusCode::CONFLICT | StatusCode::PRECONDITION_FAILED => RepoError::Conflict(message),
+            StatusCode::FORBIDDEN if quota => RepoError::QuotaExceeded(message),
+            status if transient && !status.is_server_error() => RepoError::RateLimited(message),
+            StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => RepoError::PermissionDenied(message),
+            status => RepoError::Api { status, body: message },
+        }
+    }
+}
+
+/// Errors without a kind of their own are classified by what they wrap
+impl From<anyhow::Error> for RepoError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<RepoError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<ApiError>() {
+            Ok(e) => return e.into(),
+            Err(e) => e,
+        };
+        match e.downcast::<std::io::Error>() {
+            Ok(e) => e.into(),
+            Err(e) => RepoError::Other(e),
+        }
+    }
+}
+
+macro_rules! other_errors {
+    ($($error:ty),*) => {
+        $(impl From<$error> for RepoError {
+            fn from(e: $error) -> Self {
+                RepoError::Other(e.into())
+            }
+        })*
+    };
+}
+
+other_errors!(reqwest::Error, serde_json::Error, hex::FromHexError, std::num::ParseIntError, tokio::task::JoinError, tokio::sync::AcquireError, std::path::StripPrefixError);
+
+impl From<walkdir::Error> for RepoError {
+    fn from(e: walkdir::Error) -> Self {
+        match e.into_io_error() {
+            Some(e) => e.into(),
+            None => RepoError::Other(format_err!("Filesystem loop")),
+        }
+    }
+}
+
 pub trait FileSource {
-    fn len(&self) -> impl Future<Output=usize>;
+    fn len(&self) -> impl Future<Output=u64>;
+
+    /// Stream file contents starting at `from`, in pieces of `chunks` bytes (except the last one).
+    fn stream(self, from: u64, chunks: usize) -> impl Stream<Item=anyhow::Result<Vec<u8>>>;
+
+    /// Stream the contents between `from` and `to` without consuming the source, so that several
+    /// ranges can be read at once. `None` for sources that can only be read front to back.
+    fn range(&self, _from: u64, _to: u64, _chunks: usize) -> Option<impl Stream<Item=anyhow::Result<Vec<u8>>>> {
+        None::<futures::stream::Empty<_>>
+    }
+
+    /// Modification time the written file should carry, for repos that can set it along with
+    /// the contents
+    fn modified(&self) -> Option<DateTime<Utc>> {
+        None
+    }
+}
+
+/// File contents held fully in memory.
+pub struct MemoryFile(pub Vec<u8>);
+
+impl FileSource for MemoryFile {
+    async fn len(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn stream(self, from: u64, chunks: usize) -> impl Stream<Item=anyhow::Result<Vec<u8>>> {
+        let data = self.0.get(from as usize..).unwrap_or_default().to_vec();
+        let chunks: Vec<_> = data.chunks(chunks.max(1)).map(|c| Ok(c.to_vec())).collect();
+        futures::stream::iter(chunks)
+    }
+
+    fn range(&self, from: u64, to: u64, chunks: usize) -> Option<impl Stream<Item=anyhow::Result<Vec<u8>>>> {
+        let data = self.0.get(from as usize..to as usize).unwrap_or_default();
+        let chunks: Vec<_> = data.chunks(chunks.max(1)).map(|c| Ok(c.to_vec())).collect();
+        Some(futures::stream::iter(chunks))
+    }
+}
+
+/// Local file read in chunks as it's streamed, instead of being loaded into memory at once.
+pub struct LocalFileSource {
+    file: tokio::fs::File,
+    len: u64,
+}
+
+impl LocalFileSource {
+    pub async fn open(path: &Path) -> std::io::Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        Ok(Self { file, len })
+    }
+}
+
+impl FileSource for LocalFileSource {
+    async fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn stream(self, from: u64, chunks: usize) -> impl Stream<Item=anyhow::Result<Vec<u8>>> {
+        let len = self.len;
+        futures::stream::try_unfold((self.file, from, true), move |(mut file, pos, first)| async move {
+            if first {
+                file.seek(SeekFrom::Start(pos)).await?;
+            }
+            if pos >= len {
+                return Ok(None);
+            }
+            let mut chunk = vec![0; (len - pos).min(chunks.max(1) as u64) as usize];
+            file.read_exact(&mut chunk).await?;
+            let read = chunk.len() as u64;
+            Ok(Some((chunk, (file, pos + read, false))))
+        })
+    }
+}
+
+/// Checksum algorithms repos can report
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum Checksum {
+    /// What Drive reports, so files don't have to be downloaded to hash them
+    #[default]
+    Sha256,
+    /// Also reported by Drive, weaker than the others
+    Md5,
+    /// Several times faster, for local pairs where dsync hashes both sides
+    Blake3,
+}
+
+impl Checksum {
+    /// Algorithms in the order they're picked when both sides support several, blake3 is as
+    /// strong as sha256 but faster to compute
+    pub const PREFERENCE: [Checksum; 3] = [Checksum::Blake3, Checksum::Sha256, Checksum::Md5];
+
+    /// The preferred algorithm both sides support, if any
+    pub fn common(srcs: &[Checksum], dsts: &[Checksum]) -> Option<Checksum> {
+        Self::PREFERENCE.into_iter().find(|c| srcs.contains(c) && dsts.contains(c))
+    }
+
+    /// Precedes stored checksums, so that they can be told apart from sha256 ones
+    fn prefix(self) -> &'static str {
+        match self {
+            Checksum::Sha256 => "",
+            Checksum::Md5 => "md5:",
+            Checksum::Blake3 => "blake3:",
+        }
+    }
+
+    /// The algorithm a checksum was computed with
+    pub fn of(checksum: &str) -> Self {
+        [Checksum::Md5, Checksum::Blake3]
+            .into_iter()
+            .find(|c| checksum.starts_with(c.prefix()))
+            .unwrap_or(Checksum::Sha256)
+    }
+
+    /// A checksum of this algorithm, given its hex digest
+    pub fn format(self, hex: &str) -> String {
+        format!("{}{hex}", self.prefix())
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Checksum::Sha256 => Hasher::Sha256(Default::default()),
+            Checksum::Md5 => Hasher::Md5(Default::default()),
+            Checksum::Blake3 => Hasher::Blake3(Default::default()),
+        }
+    }
+}
+
+/// Computes a checksum incrementally
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Md5(md5::Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Sha256(h) => hex::encode(h.finalize()),
+            Hasher::Md5(h) => Checksum::Md5.format(&hex::encode(h.finalize())),
+            Hasher::Blake3(h) => Checksum::Blake3.format(&h.finalize().to_hex()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
 
-    fn stream(self, from: u64, chunks: usize) -> impl Stream<Item=Vec<u8>>;
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a backend supports, so that syncs can pick the cheapest way to apply changes to it
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+    /// [`Repo::copy_file`] doesn't transfer file contents through dsync
+    pub server_copy: bool,
+    /// Files can be moved without copying them
+    pub server_move: bool,
+    /// Algorithms [`Repo::checksum`] supports, listed files only carry sha256 checksums
+    pub checksums: &'static [Checksum],
+    /// [`Repo::set_modified`] changes modification times, instead of keeping the time of writing
+    pub set_modified: bool,
+    /// Files can be written without knowing their length up front. Sources always know their
+    /// length for now, so nothing needs it yet
+    #[allow(dead_code)]
+    pub unknown_length: bool,
 }
 
 pub trait Repo {
-    async fn list(&self, path: PathBuf) -> anyhow::Result<Vec<Entry>>;
-    async fn create_dir(&self, path: PathBuf) -> anyhow::Result<()>;
-    async fn write_file(&self, path: PathBuf, data: impl FileSource) -> anyhow::Result<()>;
-    async fn copy_file(&self, source: PathBuf, dest: PathBuf) -> anyhow::Result<()>;
-    async fn delete(&self, path: PathBuf) -> anyhow::Result<()>;
+    type Source: FileSource;
+
+    fn capabilities(&self) -> Capabilities;
+
+    async fn list(&self, path: PathBuf) -> RepoResult<Vec<Entry>>;
+    /// List a directory incrementally, backends override this to yield entries before the
+    /// whole directory is listed
+    fn list_stream(&self, path: PathBuf) -> impl Stream<Item=RepoResult<Entry>> {
+        futures::stream::once(self.list(path))
+            .map_ok(|entries| futures::stream::iter(entries.into_iter().map(Ok)))
+            .try_flatten()
+    }
+    /// List all entries below a directory, along with their paths
+    async fn list_recursive(&self, path: PathBuf) -> RepoResult<Vec<(PathBuf, Entry)>> {
+        list_levels(self, path).await
+    }
+
+    /// Look up a single entry, `None` when nothing exists at the path
+    async fn stat(&self, path: PathBuf) -> RepoResult<Option<Entry>>;
+    async fn create_dir(&self, path: PathBuf) -> RepoResult<()>;
+    async fn read_file(&self, path: PathBuf) -> RepoResult<Self::Source>;
+    /// Write the file, returning its new metadata
+    async fn write_file(&self, path: PathBuf, data: impl FileSource) -> RepoResult<File>;
+    /// Compute the checksum of a file's contents
+    async fn checksum(&self, path: PathBuf, algorithm: Checksum) -> RepoResult<String>;
+    /// Copy a file within the repo, returning metadata of the copy
+    async fn copy_file(&self, source: PathBuf, dest: PathBuf) -> RepoResult<File>;
+    /// Move a file or directory to the trash, returning the id [`Repo::restore`] brings it back
+    /// with
+    async fn trash(&self, path: PathBuf) -> RepoResult<String>;
+    /// Keep the contents of a file that's about to be overwritten, returning the id
+    /// [`Repo::restore`] brings them back with. The file is moved to the trash, unless the repo
+    /// keeps overwritten contents by itself.
+    async fn keep_version(&self, path: PathBuf) -> RepoResult<String> {
+        self.trash(path).await
+    }
+    /// Bring back an entry moved to the trash, or contents kept by [`Repo::keep_version`], to
+    /// `path`. Whatever is at `path` now is moved to the trash.
+    async fn restore(&self, id: &str, path: PathBuf) -> RepoResult<()>;
+    async fn delete_permanent(&self, path: PathBuf) -> RepoResult<()>;
+    /// Move a file or directory within the repo, returning it under its new path
+    async fn rename(&self, from: PathBuf, to: PathBuf) -> RepoResult<Entry>;
+    /// Set the modification time of a file, returning its new metadata
+    async fn set_modified(&self, path: PathBuf, modified: DateTime<Utc>) -> RepoResult<File>;
+    /// Store metadata of a source file, like its [`MODE`] or extended attributes, on a written file
+    async fn set_metadata(&self, path: PathBuf, metadata: &BTreeMap<String, String>) -> RepoResult<()>;
+    /// Create a link at `path` to `target`, relative to the directory of `path`, returning its
+    /// metadata. Replaces a file existing at `path`.
+    async fn create_link(&self, path: PathBuf, target: PathBuf) -> RepoResult<File>;
+    /// Copy the file with `id` from another repo on the same storage to `dest`, without reading
+    /// its contents. Replaces a file existing at `dest`.
+    async fn copy_from(&self, id: &str, dest: PathBuf) -> RepoResult<File> {
+        Err(RepoError::Other(anyhow::format_err!("Cannot copy {id} to {dest:?}, the repo can only copy its own files")))
+    }
+}
+
+/// Directories listed at once while listing a tree
+const LISTINGS: usize = 16;
+
+/// List all entries below a directory a level of the tree at a time, listing the directories of
+/// each level concurrently
+pub async fn list_levels<R: Repo + ?Sized>(repo: &R, path: PathBuf) -> RepoResult<Vec<(PathBuf, Entry)>> {
+    let mut out = vec![];
+    let mut level = vec![path];
+    while !level.is_empty() {
+        let listed: Vec<(PathBuf, Vec<Entry>)> = futures::stream::iter(level)
+            .map(|dir| async move {
+                let entries = repo.list(dir.clone()).await?;
+                RepoResult::Ok((dir, entries))
+            })
+            .buffer_unordered(LISTINGS)
+            .try_collect()
+            .await?;
+
+        level = vec![];
+        for (dir, entries) in listed {
+            for entry in entries {
+                if let Entry::Dir(child) = &entry {
+                    level.push(dir.join(&child.name));
+                }
+                out.push((dir.join(entry.name()), entry));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Prefix of metadata keys holding extended attributes, with hex encoded values
+pub const XATTR: &str = "xattr.";
+/// Metadata key of Google documents listed as an export, holding the mime type they're exported
+/// in. Their size isn't known until they're exported.
+pub const EXPORTED: &str = "exported";
+/// Metadata key of octal permission bits of local files
+pub const MODE: &str = "mode";
+/// Metadata keys of the owner and group of local files
+pub const UID: &str = "uid";
+pub const GID: &str = "gid";
+/// Metadata key of links kept with `--links keep`, holding the path they point to relative to
+/// their directory
+pub const LINK: &str = "link";
+
+/// Resolve a link `target` relative to `dir`, `None` when it points outside of the tree
+pub fn link_target(dir: &Path, target: &Path) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+    for part in dir.join(target).components() {
+        match part {
+            Component::Normal(name) => out.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !out.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(..) => return None,
+        }
+    }
+    Some(out)
 }
 
+/// Path of `target` relative to the directory `dir`, both relative to the same root
+pub fn relative_link(dir: &Path, target: &Path) -> PathBuf {
+    let common = dir.components().zip(target.components()).take_while(|(a, b)| a == b).count();
+    let mut out: PathBuf = dir.components().skip(common).map(|_| Component::ParentDir).collect();
+    out.extend(target.components().skip(common));
+    out
+}
+
+/// What to do with symlinks found in a local repo
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Links {
+    /// Leave them out, as if they didn't exist
+    #[default]
+    Skip,
+    /// Sync whatever they point to
+    Follow,
+    /// Fail the listing
+    Error,
+    /// Keep links to entries inside the synced tree as links, stored as shortcuts on Drive
+    Keep,
+}
 
 pub struct LocalRepo {
     pub(crate) path: PathBuf,
+    pub(crate) links: Links,
+    /// Read extended attributes of listed files
+    pub(crate) xattrs: bool,
+    /// Store names the filesystem can't hold under an encoding, see [`sanitize::encode`]
+    pub(crate) sanitize: bool,
+    /// Number of ranges files of at least `stream_cutoff` bytes are written in at once
+    pub(crate) streams: usize,
+    pub(crate) stream_cutoff: u64,
+}
+
+impl LocalRepo {
+    /// Path of an entry on disk
+    fn real(&self, path: &Path) -> PathBuf {
+        if !self.sanitize {
+            return self.path.join(path);
+        }
+        let parts: PathBuf = path.iter().map(|part| sanitize::encode(&part.to_string_lossy()).0).collect();
+        self.path.join(parts)
+    }
+
+    /// Name of an entry as it's synced, given its path on disk
+    fn name(&self, real: &Path) -> String {
+        let name = real.file_name().unwrap_or_default().to_string_lossy();
+        if !self.sanitize {
+            return name.into_owned();
+        }
+        match xattr::get(real, sanitize::ORIGINAL_NAME) {
+            Ok(Some(original)) => String::from_utf8_lossy(&original).into_owned(),
+            _ => sanitize::decode(&name),
+        }
+    }
+
+    /// Write a file of `len` bytes to `tmp` in several ranges at once, returning whether holes
+    /// were left in it. `None` when the file is small or the source can't be read in ranges.
+    async fn write_ranges(&self, tmp: &Path, key: &str, data: &impl FileSource, len: u64) -> RepoResult<Option<bool>> {
+        if self.streams < 2 || len < self.stream_cutoff.max(1) {
+            return Ok(None);
+        }
+        let streams = self.streams as u64;
+        let Some(ranges) = (0..streams)
+            .map(|i| (len * i / streams, len * (i + 1) / streams))
+            .map(|(from, to)| Some((from, data.range(from, to, 1 << 20)?)))
+            .collect::<Option<Vec<_>>>() else {
+            return Ok(None);
+        };
+        info!("Writing {tmp:?} in {streams} streams");
+
+        // Pieces of the file are written out of order, so it can't be continued later
+        PartialTransfer::remove(key);
+        let file = std::fs::File::create(tmp)?;
+        file.set_len(len)?;
+        let holes = futures::future::try_join_all(ranges.into_iter().map(|(from, stream)| async move {
+            let mut file = std::fs::OpenOptions::new().write(true).open(tmp)?;
+            file.seek(SeekFrom::Start(from))?;
+            write_sparse(&mut file, stream).await
+        })).await?;
+        file.sync_all()?;
+        Ok(Some(holes.contains(&true)))
+    }
+
+    /// Remember the original names of entries along `path` that had to be shortened
+    fn keep_names(&self, path: &Path) -> RepoResult<()> {
+        if !self.sanitize {
+            return Ok(());
+        }
+        let mut real = self.path.clone();
+        for part in path.iter() {
+            let name = part.to_string_lossy();
+            let (encoded, decodable) = sanitize::encode(&name);
+            real.push(encoded);
+            if !decodable {
+                xattr::set(&real, sanitize::ORIGINAL_NAME, name.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Metadata of the file at `path`, stored at `real`
+    fn file(&self, path: &Path, real: &Path) -> RepoResult<File> {
+        let mut file = local_file(real, &std::fs::metadata(real)?);
+        file.name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        Ok(file)
+    }
+
+    /// Metadata of the symlink at `real`, `None` when it points outside of the repo
+    fn link(&self, real: &Path, meta: &std::fs::Metadata) -> anyhow::Result<Option<File>> {
+        let target = std::fs::read_link(real)?;
+        let dir = real.parent().and_then(|p| p.strip_prefix(&self.path).ok()).unwrap_or(Path::new(""));
+        let resolved = match target.strip_prefix(&self.path) {
+            Ok(inside) if target.is_absolute() => Some(inside.to_owned()),
+            _ if target.is_absolute() => None,
+            _ => link_target(dir, &target),
+        };
+        let Some(resolved) = resolved else {
+            return Ok(None);
+        };
+        let mut metadata = BTreeMap::new();
+        metadata.insert(LINK.to_string(), relative_link(dir, &resolved).to_string_lossy().into_owned());
+        Ok(Some(File {
+            id: real.to_string_lossy().into_owned(),
+            name: self.name(real),
+            shasum: None,
+            size: 0,
+            modified: meta.modified().ok().map(DateTime::from),
+            created: meta.created().ok().map(DateTime::from),
+            metadata,
+        }))
+    }
+
+    /// Turn a path into a repo entry, `None` for entries that aren't synced
+    fn entry(&self, path: &Path) -> anyhow::Result<Option<Entry>> {
+        let mut meta = std::fs::symlink_metadata(path)?;
+        if meta.is_symlink() {
+            match self.links {
+                Links::Skip => {
+                    info!("Skipping symlink {path:?}");
+                    return Ok(None);
+                }
+                Links::Follow => meta = std::fs::metadata(path)?,
+                Links::Error => bail!("Cannot sync symlink {path:?}, use --links skip, follow or keep"),
+                Links::Keep => {
+                    let link = self.link(path, &meta)?;
+                    if link.is_none() {
+                        info!("Skipping symlink {path:?}, it points outside of the synced tree");
+                    }
+                    return Ok(link.map(Entry::File));
+                }
+            }
+        }
+
+        if meta.is_dir() {
+            Ok(Some(Entry::Dir(Dir {
+                id: path.to_string_lossy().into_owned(),
+                name: self.name(path),
+            })))
+        } else if meta.is_file() {
+            let mut file = local_file(path, &meta);
+            file.name = self.name(path);
+            if self.xattrs {
+                for name in xattr::list(path)? {
+                    if let Some(value) = xattr::get(path, &name)? {
+                        file.metadata.insert(format!("{XATTR}{}", name.to_string_lossy()), hex::encode(value));
+                    }
+                }
+            }
+            Ok(Some(Entry::File(file)))
+        } else {
+            warn!("Skipping {path:?}, it's neither a file nor a directory");
+            Ok(None)
+        }
+    }
 }
-fn shasum(file: &Path) -> anyhow::Result<String> {
+
+/// Write a stream at the current position of `file`, skipping over blocks of zeros to leave
+/// holes in it. Returns whether any were left.
+async fn write_sparse(file: &mut std::fs::File, stream: impl Stream<Item=anyhow::Result<Vec<u8>>>) -> anyhow::Result<bool> {
+    let mut holes = false;
+    futures::pin_mut!(stream);
+    while let Some(chunk) = stream.try_next().await? {
+        for block in chunk.chunks(SPARSE_BLOCK) {
+            if block.len() == SPARSE_BLOCK && block.iter().all(|b| *b == 0) {
+                file.seek(SeekFrom::Current(SPARSE_BLOCK as i64))?;
+                holes = true;
+            } else {
+                file.write_all(block)?;
+            }
+        }
+    }
+    Ok(holes)
+}
+
+fn checksum(file: &Path, algorithm: Checksum) -> anyhow::Result<String> {
     let mut file = std::fs::OpenOptions::new()
         .read(true)
         .open(file)?;
 
-    let mut sha = sha2::Sha256::default();
-    std::io::copy(&mut file, &mut sha)?;
+    let mut hasher = algorithm.hasher();
+    std::io::copy(&mut file, &mut hasher)?;
 
-    return Ok(hex::encode(sha.finalize()))
+    Ok(hasher.finish())
+}
+
+/// Size of zero blocks left as holes when writing local files
+const SPARSE_BLOCK: usize = 4096;
+
+/// Metadata key identifying the inode of local files with multiple hard links
+pub const INODE: &str = "inode";
+
+fn local_file(path: &Path, meta: &std::fs::Metadata) -> File {
+    let mut metadata = BTreeMap::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if meta.nlink() > 1 {
+            metadata.insert(INODE.to_string(), format!("{}:{}", meta.dev(), meta.ino()));
+        }
+        metadata.insert(MODE.to_string(), format!("{:o}", meta.mode() & 0o7777));
+        metadata.insert(UID.to_string(), meta.uid().to_string());
+        metadata.insert(GID.to_string(), meta.gid().to_string());
+    }
+    File {
+        id: path.to_string_lossy().into_owned(),
+        name: path.file_name().unwrap().to_string_lossy().into_owned(),
+        shasum: None,
+        size: meta.len(),
+        modified: meta.modified().ok().map(DateTime::from),
+        created: meta.created().ok().map(DateTime::from),
+        metadata,
+    }
 }
 
 impl Repo for LocalRepo {
-    async fn list(&self, path: PathBuf) -> anyhow::Result<Vec<Entry>> {
-        let path = self.path.join(path);
-        let entries = std::fs::read_dir(path)?;
+    type Source = LocalFileSource;
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            server_copy: true,
+            server_move: true,
+            checksums: &[Checksum::Sha256, Checksum::Md5, Checksum::Blake3],
+            set_modified: true,
+            unknown_length: true,
+        }
+    }
+
+    async fn list(&self, path: PathBuf) -> RepoResult<Vec<Entry>> {
+        self.list_stream(path).try_collect().await
+    }
+
+    fn list_stream(&self, path: PathBuf) -> impl Stream<Item=RepoResult<Entry>> {
+        let path = self.real(&path);
+        futures::stream::once(async move { std::fs::read_dir(path) })
+            .map_ok(futures::stream::iter)
+            .try_flatten()
+            .map_err(RepoError::from)
+            .try_filter_map(|entry| futures::future::ready(self.entry(&entry.path()).map_err(RepoError::from)))
+    }
+
+    async fn list_recursive(&self, path: PathBuf) -> RepoResult<Vec<(PathBuf, Entry)>> {
+        let walk = walkdir::WalkDir::new(self.real(&path))
+            .min_depth(1)
+            .follow_links(self.links == Links::Follow);
+
+        // Paths are built from synced names, which differ from the ones on disk when sanitizing
+        let mut dirs = HashMap::new();
+        dirs.insert(self.real(&path), path);
         let mut out = vec![];
-        for entry in entries {
+        for entry in walk {
             let entry = entry?;
-            let meta = entry.metadata()?;
-            if meta.is_dir() {
-                out.push(Entry::Dir(Dir {
-                    id: entry.path().to_string_lossy().into_owned(),
-                    name: entry.file_name().to_string_lossy().into_owned(),
-                }))
-            } else if meta.is_file() {
-                out.push(Entry::File(File {
-                    id: entry.path().to_string_lossy().into_owned(),
-                    name: entry.file_name().to_string_lossy().into_owned(),
-                    shasum: shasum(&entry.path())?,
-                    size: meta.len(),
-                }))
-            } else {
-                panic!("Invalid file: {:?}", entry);
+            if let Some(listed) = self.entry(entry.path())? {
+                let parent = entry.path().parent().and_then(|p| dirs.get(p)).cloned().unwrap_or_default();
+                let path = parent.join(listed.name());
+                if let Entry::Dir(..) = listed {
+                    dirs.insert(entry.path().to_owned(), path.clone());
+                }
+                out.push((path, listed));
             }
         }
-        return Ok(out)
+        Ok(out)
     }
 
-    async fn create_dir(&self, path: PathBuf) -> anyhow::Result<()> {
-        let path = self.path.join(path);
-        std::fs::create_dir_all(path)?;
-        Ok(())
+    async fn stat(&self, path: PathBuf) -> RepoResult<Option<Entry>> {
+        let path = self.real(&path);
+        match std::fs::symlink_metadata(&path) {
+            Ok(_) => Ok(self.entry(&path)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn create_dir(&self, path: PathBuf) -> RepoResult<()> {
+        std::fs::create_dir_all(self.real(&path))?;
+        self.keep_names(&path)
+    }
+
+    async fn read_file(&self, path: PathBuf) -> RepoResult<LocalFileSource> {
+        Ok(LocalFileSource::open(&self.real(&path)).await?)
+    }
+
+    async fn write_file(&self, path: PathBuf, data: impl FileSource) -> RepoResult<File> {
+        let real = self.real(&path);
+        let name = real.file_name().unwrap().to_string_lossy();
+        // Leave room for the prefix and suffix in names close to the length limit
+        let tmp = real.with_file_name(format!(".{}.dsync-tmp", sanitize::truncate(&name, sanitize::MAX_LEN - 11)));
+
+        let len = data.len().await;
+        let key = format!("local:{}", real.display());
+
+        let holes = match self.write_ranges(&tmp, &key, &data, len).await? {
+            Some(holes) => holes,
+            None => {
+                // Continue writing the temporary file of an interrupted transfer
+                let partial = PartialTransfer::load(&key)
+                    .filter(|p| p.len == len && Path::new(&p.location) == tmp)
+                    .and_then(|_| std::fs::metadata(&tmp).ok())
+                    .map(|meta| meta.len())
+                    .filter(|written| *written <= len);
+
+                let (mut file, offset) = match partial {
+                    Some(written) => {
+                        let mut file = std::fs::OpenOptions::new().write(true).open(&tmp)?;
+                        file.seek(SeekFrom::Start(written))?;
+                        (file, written)
+                    }
+                    None => {
+                        PartialTransfer { location: tmp.to_string_lossy().into_owned(), len }.save(&key)?;
+                        (std::fs::File::create(&tmp)?, 0)
+                    }
+                };
+
+                let holes = write_sparse(&mut file, data.stream(offset, 1 << 20)).await?;
+                // Trailing holes don't extend the file on their own
+                let end = file.stream_position()?;
+                file.set_len(end)?;
+                file.sync_all()?;
+                holes
+            }
+        };
+
+        std::fs::rename(&tmp, &real)?;
+        PartialTransfer::remove(&key);
+
+        let meta = std::fs::metadata(&real)?;
+        #[cfg(unix)]
+        if holes {
+            use std::os::unix::fs::MetadataExt;
+            info!("Wrote {real:?} as a sparse file, {} of {} bytes allocated", meta.blocks() * 512, meta.len());
+        }
+        self.keep_names(&path)?;
+        let mut file = local_file(&real, &meta);
+        file.name = self.name(&real);
+        Ok(file)
+    }
+
+    async fn checksum(&self, path: PathBuf, algorithm: Checksum) -> RepoResult<String> {
+        let path = self.real(&path);
+        let meta = std::fs::metadata(&path)?;
+        let modified = meta.modified().ok().map(DateTime::from);
+        let cached = HashCache::get(&path, meta.len(), modified).filter(|c| Checksum::of(c) == algorithm);
+        if let Some(checksum) = cached {
+            return Ok(checksum);
+        }
+        let checksum = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || checksum(&path, algorithm)
+        }).await??;
+        HashCache::insert(path, meta.len(), modified, checksum.clone());
+        Ok(checksum)
+    }
+
+    async fn copy_file(&self, source: PathBuf, dest: PathBuf) -> RepoResult<File> {
+        let src = self.real(&source);
+        let real = self.real(&dest);
+        std::fs::copy(src, &real)?;
+        self.keep_names(&dest)?;
+        self.file(&dest, &real)
     }
 
-    async fn write_file(&self, path: PathBuf, data: impl FileSource) -> anyhow::Result<()> {
-        todo!()
+    async fn trash(&self, path: PathBuf) -> RepoResult<String> {
+        Ok(trash::trash(&self.real(&path))?.to_string_lossy().into_owned())
+    }
+
+    async fn restore(&self, id: &str, path: PathBuf) -> RepoResult<()> {
+        let real = self.real(&path);
+        if std::fs::symlink_metadata(&real).is_ok() {
+            trash::trash(&real)?;
+        }
+        Ok(trash::restore(Path::new(id), &real)?)
     }
 
-    async fn copy_file(&self, source: PathBuf, dest: PathBuf) -> anyhow::Result<()> {
-        let src = self.path.join(source);
-        let dst = self.path.join(dest);
-        std::fs::copy(src, dst)?;
+    async fn delete_permanent(&self, path: PathBuf) -> RepoResult<()> {
+        let path = self.real(&path);
+        if std::fs::symlink_metadata(&path)?.is_dir() {
+            std::fs::remove_dir_all(path)?;
+        } else {
+            std::fs::remove_file(path)?;
+        }
         Ok(())
     }
 
-    async fn delete(&self, path: PathBuf) -> anyhow::Result<()> {
-        let path = self.path.join(path);
-        std::fs::remove_file(path)?;
+    async fn rename(&self, from: PathBuf, to: PathBuf) -> RepoResult<Entry> {
+        let real = self.real(&to);
+        std::fs::rename(self.real(&from), &real)?;
+        if self.sanitize {
+            // The original name of the old path no longer applies
+            let _ = xattr::remove(&real, sanitize::ORIGINAL_NAME);
+        }
+        self.keep_names(&to)?;
+        self.stat(to.clone()).await?.ok_or_else(|| RepoError::NotFound(format!("{to:?}")))
+    }
+
+    async fn set_modified(&self, path: PathBuf, modified: DateTime<Utc>) -> RepoResult<File> {
+        let real = self.real(&path);
+        std::fs::File::open(&real)?.set_modified(modified.into())?;
+        self.file(&path, &real)
+    }
+
+    async fn set_metadata(&self, path: PathBuf, metadata: &BTreeMap<String, String>) -> RepoResult<()> {
+        let path = self.real(&path);
+        for (key, value) in metadata {
+            if let Some(name) = key.strip_prefix(XATTR) {
+                xattr::set(&path, name, &hex::decode(value)?)
+                    .map_err(|e| anyhow::format_err!("Failed to set {name} on {path:?}: {e}"))?;
+            }
+        }
+        // Changing the owner clears setuid bits, so the mode goes last
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let uid = metadata.get(UID).map(|uid| uid.parse()).transpose()?;
+            let gid = metadata.get(GID).map(|gid| gid.parse()).transpose()?;
+            if uid.is_some() || gid.is_some() {
+                std::os::unix::fs::chown(&path, uid, gid)
+                    .map_err(|e| anyhow::format_err!("Failed to change owner of {path:?}: {e}"))?;
+            }
+            if let Some(mode) = metadata.get(MODE) {
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(u32::from_str_radix(mode, 8)?))?;
+            }
+        }
         Ok(())
     }
+
+    async fn create_link(&self, path: PathBuf, target: PathBuf) -> RepoResult<File> {
+        let real = self.real(&path);
+        let target: PathBuf = match self.sanitize {
+            true => target.iter()
+                .map(|part| match part.to_str() {
+                    Some(".." | ".") | None => part.to_owned(),
+                    Some(name) => sanitize::encode(name).0.into(),
+                })
+                .collect(),
+            false => target,
+        };
+        match std::fs::symlink_metadata(&real) {
+            Ok(meta) if !meta.is_dir() => std::fs::remove_file(&real)?,
+            _ => {}
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&target, &real)?;
+        #[cfg(windows)]
+        std::os::windows::fs::symlink_file(&target, &real)?;
+        self.keep_names(&path)?;
+
+        let link = self.link(&real, &std::fs::symlink_metadata(&real)?)?;
+        let mut file = link.ok_or_else(|| RepoError::NotFound(format!("{target:?}")))?;
+        file.name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        Ok(file)
+    }
+}
+
+
+/// Either of the supported repos, for when the backend is only known at runtime
+// There's only one repo per side of a sync, so its size doesn't matter
+#[allow(clippy::large_enum_variant)]
+pub enum AnyRepo<A: Authorizer> {
+    Local(LocalRepo),
+    Drive(GDriveRepo<A>),
+    Memory(MemoryRepo),
+}
+
+pub enum AnySource<L, D> {
+    Local(L),
+    Drive(D),
+    Memory(MemoryFile),
+}
+
+impl<L: FileSource, D: FileSource> FileSource for AnySource<L, D> {
+    async fn len(&self) -> u64 {
+        match self {
+            AnySource::Local(s) => s.len().await,
+            AnySource::Drive(s) => s.len().await,
+            AnySource::Memory(s) => s.len().await,
+        }
+    }
+
+    fn stream(self, from: u64, chunks: usize) -> impl Stream<Item=anyhow::Result<Vec<u8>>> {
+        match self {
+            AnySource::Local(s) => Either::Left(s.stream(from, chunks)),
+            AnySource::Drive(s) => Either::Right(Either::Left(s.stream(from, chunks))),
+            AnySource::Memory(s) => Either::Right(Either::Right(s.stream(from, chunks))),
+        }
+    }
+
+    fn range(&self, from: u64, to: u64, chunks: usize) -> Option<impl Stream<Item=anyhow::Result<Vec<u8>>>> {
+        Some(match self {
+            AnySource::Local(s) => Either::Left(s.range(from, to, chunks)?),
+            AnySource::Drive(s) => Either::Right(Either::Left(s.range(from, to, chunks)?)),
+            AnySource::Memory(s) => Either::Right(Either::Right(s.range(from, to, chunks)?)),
+        })
+    }
 }
 
+impl<A: Authorizer> AnyRepo<A> {
+    /// Set how symlinks of a local repo are handled, and whether a Drive repo lists shortcuts as
+    /// links
+    pub fn with_links(self, links: Links) -> Self {
+        match self {
+            AnyRepo::Local(repo) => AnyRepo::Local(LocalRepo { links, ..repo }),
+            AnyRepo::Drive(repo) => AnyRepo::Drive(repo.with_shortcuts(links == Links::Keep)),
+            repo => repo,
+        }
+    }
 
-pub async fn sync<S: Repo, D: Repo>(src: S, dst: D) -> anyhow::Result<()> {
-    let root = PathBuf::from(".");
+    /// Set whether a local repo encodes names its filesystem can't hold
+    pub fn with_sanitize(mut self, sanitize: bool) -> Self {
+        if let AnyRepo::Local(repo) = &mut self {
+            repo.sanitize = sanitize;
+        }
+        self
+    }
 
-    let mut srcs = src.list(root.clone()).await?;
-    let mut dsts = dst.list(root.clone()).await?;
+    /// Set the size below which a Drive repo uploads files in a single request
+    pub fn with_upload_cutoff(self, cutoff: u64) -> Self {
+        match self {
+            AnyRepo::Drive(repo) => AnyRepo::Drive(repo.with_upload_cutoff(cutoff)),
+            repo => repo,
+        }
+    }
 
-    srcs.sort_by(|v1, v2| v1.name().cmp(v2.name()));
+    /// Set the size of the pieces a Drive repo sends resumable uploads in
+    pub fn with_chunk_size(self, size: usize) -> Self {
+        match self {
+            AnyRepo::Drive(repo) => AnyRepo::Drive(repo.with_chunk_size(size)),
+            repo => repo,
+        }
+    }
 
-    let mut dsts: HashMap<&str, &Entry> = dsts.iter().map(|d| (d.name(), d)).collect();
+    /// Set how many ranges a local repo writes files of at least `cutoff` bytes in at once
+    pub fn with_streams(mut self, streams: usize, cutoff: u64) -> Self {
+        if let AnyRepo::Local(repo) = &mut self {
+            repo.streams = streams;
+            repo.stream_cutoff = cutoff;
+        }
+        self
+    }
 
-    for entry in srcs {
-        match entry {
-            Entry::Dir(..) => {}
-            Entry::File(src) => {
-                match dsts.get(src.name.as_str()) {
-                    Some(Entry::File(dst)) => {
-                        if src.shasum == dst.shasum {
-                            continue;
-                        }
-                    }
-                    None => {}
-                    _ => {}
-                }
+    /// Set the age after which a Drive repo lists the whole drive again, instead of updating the
+    /// listing kept from previous runs
+    pub fn with_listing_ttl(self, ttl: Duration) -> Self {
+        match self {
+            AnyRepo::Drive(repo) => AnyRepo::Drive(repo.with_listing_ttl(ttl)),
+            repo => repo,
+        }
+    }
+
+    /// Set the formats a Drive repo exports Google documents in, by extension
+    pub fn with_export_formats(self, formats: Vec<String>) -> Self {
+        match self {
+            AnyRepo::Drive(repo) => AnyRepo::Drive(repo.with_export_formats(formats)),
+            repo => repo,
+        }
+    }
+
+    /// Set the extensions of files a Drive repo converts into Google documents when uploading
+    pub fn with_import_formats(self, formats: Vec<String>) -> Self {
+        match self {
+            AnyRepo::Drive(repo) => AnyRepo::Drive(repo.with_import_formats(formats)),
+            repo => repo,
+        }
+    }
+
+    /// Set whether a Drive repo keeps the revisions of uploaded files forever
+    pub fn with_keep_forever(self, keep: bool) -> Self {
+        match self {
+            AnyRepo::Drive(repo) => AnyRepo::Drive(repo.with_keep_forever(keep)),
+            repo => repo,
+        }
+    }
+
+    /// Set the labels a Drive repo applies to uploaded files
+    pub fn with_labels(self, labels: Vec<LabelModification>) -> Self {
+        match self {
+            AnyRepo::Drive(repo) => AnyRepo::Drive(repo.with_labels(labels)),
+            repo => repo,
+        }
+    }
+
+    /// Set the files a Drive repo stars when they're uploaded
+    pub fn with_starred(self, paths: Vec<PathBuf>) -> Self {
+        match self {
+            AnyRepo::Drive(repo) => AnyRepo::Drive(repo.with_starred(paths)),
+            repo => repo,
+        }
+    }
+
+    /// Set what a Drive repo does with entries sharing a name with a newer one
+    pub fn with_duplicates(self, duplicates: Duplicates) -> Self {
+        match self {
+            AnyRepo::Drive(repo) => AnyRepo::Drive(repo.with_duplicates(duplicates)),
+            repo => repo,
+        }
+    }
+
+    /// Set whether a local repo reads extended attributes of its files
+    pub fn with_xattrs(mut self, xattrs: bool) -> Self {
+        if let AnyRepo::Local(repo) = &mut self {
+            repo.xattrs = xattrs;
+        }
+        self
+    }
+}
+
+impl<A: Authorizer> Repo for AnyRepo<A> {
+    type Source = AnySource<<LocalRepo as Repo>::Source, <GDriveRepo<A> as Repo>::Source>;
+
+    fn capabilities(&self) -> Capabilities {
+        match self {
+            AnyRepo::Local(r) => r.capabilities(),
+            AnyRepo::Drive(r) => r.capabilities(),
+            AnyRepo::Memory(r) => r.capabilities(),
+        }
+    }
+
+    async fn list(&self, path: PathBuf) -> RepoResult<Vec<Entry>> {
+        match self {
+            AnyRepo::Local(r) => r.list(path).await,
+            AnyRepo::Drive(r) => r.list(path).await,
+            AnyRepo::Memory(r) => r.list(path).await,
+        }
+    }
+
+    fn list_stream(&self, path: PathBuf) -> impl Stream<Item=RepoResult<Entry>> {
+        match self {
+            AnyRepo::Local(r) => Either::Left(r.list_stream(path)),
+            AnyRepo::Drive(r) => Either::Right(Either::Left(r.list_stream(path))),
+            AnyRepo::Memory(r) => Either::Right(Either::Right(r.list_stream(path))),
+        }
+    }
+
+    async fn list_recursive(&self, path: PathBuf) -> RepoResult<Vec<(PathBuf, Entry)>> {
+        match self {
+            AnyRepo::Local(r) => r.list_recursive(path).await,
+            AnyRepo::Drive(r) => r.list_recursive(path).await,
+            AnyRepo::Memory(r) => r.list_recursive(path).await,
+        }
+    }
+
+    async fn stat(&self, path: PathBuf) -> RepoResult<Option<Entry>> {
+        match self {
+            AnyRepo::Local(r) => r.stat(path).await,
+            AnyRepo::Drive(r) => r.stat(path).await,
+            AnyRepo::Memory(r) => r.stat(path).await,
+        }
+    }
+
+    async fn create_dir(&self, path: PathBuf) -> RepoResult<()> {
+        match self {
+            AnyRepo::Local(r) => r.create_dir(path).await,
+            AnyRepo::Drive(r) => r.create_dir(path).await,
+            AnyRepo::Memory(r) => r.create_dir(path).await,
+        }
+    }
+
+    async fn read_file(&self, path: PathBuf) -> RepoResult<Self::Source> {
+        match self {
+            AnyRepo::Local(r) => r.read_file(path).await.map(AnySource::Local),
+            AnyRepo::Drive(r) => r.read_file(path).await.map(AnySource::Drive),
+            AnyRepo::Memory(r) => r.read_file(path).await.map(AnySource::Memory),
+        }
+    }
+
+    async fn write_file(&self, path: PathBuf, data: impl FileSource) -> RepoResult<File> {
+        match self {
+            AnyRepo::Local(r) => r.write_file(path, data).await,
+            AnyRepo::Drive(r) => r.write_file(path, data).await,
+            AnyRepo::Memory(r) => r.write_file(path, data).await,
+        }
+    }
+
+    async fn checksum(&self, path: PathBuf, algorithm: Checksum) -> RepoResult<String> {
+        match self {
+            AnyRepo::Local(r) => r.checksum(path, algorithm).await,
+            AnyRepo::Drive(r) => r.checksum(path, algorithm).await,
+            AnyRepo::Memory(r) => r.checksum(path, algorithm).await,
+        }
+    }
+
+    async fn copy_file(&self, source: PathBuf, dest: PathBuf) -> RepoResult<File> {
+        match self {
+            AnyRepo::Local(r) => r.copy_file(source, dest).await,
+            AnyRepo::Drive(r) => r.copy_file(source, dest).await,
+            AnyRepo::Memory(r) => r.copy_file(source, dest).await,
+        }
+    }
+
+    async fn trash(&self, path: PathBuf) -> RepoResult<String> {
+        match self {
+            AnyRepo::Local(r) => r.trash(path).await,
+            AnyRepo::Drive(r) => r.trash(path).await,
+            AnyRepo::Memory(r) => r.trash(path).await,
+        }
+    }
+
+    async fn keep_version(&self, path: PathBuf) -> RepoResult<String> {
+        match self {
+            AnyRepo::Local(r) => r.keep_version(path).await,
+            AnyRepo::Drive(r) => r.keep_version(path).await,
+            AnyRepo::Memory(r) => r.keep_version(path).await,
+        }
+    }
+
+    async fn restore(&self, id: &str, path: PathBuf) -> RepoResult<()> {
+        match self {
+            AnyRepo::Local(r) => r.restore(id, path).await,
+            AnyRepo::Drive(r) => r.restore(id, path).await,
+            AnyRepo::Memory(r) => r.restore(id, path).await,
+        }
+    }
+
+    async fn delete_permanent(&self, path: PathBuf) -> RepoResult<()> {
+        match self {
+            AnyRepo::Local(r) => r.delete_permanent(path).await,
+            AnyRepo::Drive(r) => r.delete_permanent(path).await,
+            AnyRepo::Memory(r) => r.delete_permanent(path).await,
+        }
+    }
+
+    async fn rename(&self, from: PathBuf, to: PathBuf) -> RepoResult<Entry> {
+        match self {
+            AnyRepo::Local(r) => r.rename(from, to).await,
+            AnyRepo::Drive(r) => r.rename(from, to).await,
+            AnyRepo::Memory(r) => r.rename(from, to).await,
+        }
+    }
+
+    async fn set_modified(&self, path: PathBuf, modified: DateTime<Utc>) -> RepoResult<File> {
+        match self {
+            AnyRepo::Local(r) => r.set_modified(path, modified).await,
+            AnyRepo::Drive(r) => r.set_modified(path, modified).await,
+            AnyRepo::Memory(r) => r.set_modified(path, modified).await,
+        }
+    }
+
+    async fn set_metadata(&self, path: PathBuf, metadata: &BTreeMap<String, String>) -> RepoResult<()> {
+        match self {
+            AnyRepo::Local(r) => r.set_metadata(path, metadata).await,
+            AnyRepo::Drive(r) => r.set_metadata(path, metadata).await,
+            AnyRepo::Memory(r) => r.set_metadata(path, metadata).await,
+        }
+    }
+
+    async fn create_link(&self, path: PathBuf, target: PathBuf) -> RepoResult<File> {
+        match self {
+            AnyRepo::Local(r) => r.create_link(path, target).await,
+            AnyRepo::Drive(r) => r.create_link(path, target).await,
+            AnyRepo::Memory(r) => r.create_link(path, target).await,
+        }
+    }
+
+    async fn copy_from(&self, id: &str, dest: PathBuf) -> RepoResult<File> {
+        match self {
+            AnyRepo::Local(r) => r.copy_from(id, dest).await,
+            AnyRepo::Drive(r) => r.copy_from(id, dest).await,
+            AnyRepo::Memory(r) => r.copy_from(id, dest).await,
+        }
+    }
+}
+
+/// Value computed once and shared by everyone asking for it
+type Shared<T> = Arc<OnceCell<T>>;
+
+/// Remembers listings and checksums of the wrapped repo, so that it's only read once when
+/// used as the source of multiple syncs.
+pub struct CachedRepo<R> {
+    inner: R,
+    lists: Mutex<HashMap<PathBuf, Shared<Vec<Entry>>>>,
+    hashes: Mutex<HashMap<(PathBuf, Checksum), Shared<String>>>,
+}
+
+impl<R: Repo> CachedRepo<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            lists: Default::default(),
+            hashes: Default::default(),
+        }
+    }
+
+    /// Fill the listing cache of a whole tree with a single recursive listing
+    pub async fn prefetch(&self, path: PathBuf) -> anyhow::Result<()> {
+        let mut lists: HashMap<PathBuf, Vec<Entry>> = HashMap::new();
+        lists.insert(path.clone(), vec![]);
+        for (entry_path, entry) in self.inner.list_recursive(path).await? {
+            if matches!(entry, Entry::Dir(..)) {
+                lists.entry(entry_path.clone()).or_default();
             }
+            let parent = entry_path.parent().unwrap_or(Path::new("")).to_owned();
+            lists.entry(parent).or_default().push(entry);
+        }
+
+        let mut cells = self.lists.lock().unwrap();
+        for (dir, entries) in lists {
+            cells.insert(dir, Arc::new(OnceCell::new_with(Some(entries))));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Repo> Repo for CachedRepo<R> {
+    type Source = R::Source;
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    async fn list(&self, path: PathBuf) -> RepoResult<Vec<Entry>> {
+        let cell = self.lists.lock().unwrap().entry(path.clone()).or_default().clone();
+        Ok(cell.get_or_try_init(|| self.inner.list(path)).await?.clone())
+    }
+
+    async fn stat(&self, path: PathBuf) -> RepoResult<Option<Entry>> {
+        self.inner.stat(path).await
+    }
+
+    async fn create_dir(&self, path: PathBuf) -> RepoResult<()> {
+        self.inner.create_dir(path).await
+    }
+
+    async fn read_file(&self, path: PathBuf) -> RepoResult<Self::Source> {
+        self.inner.read_file(path).await
+    }
+
+    async fn write_file(&self, path: PathBuf, data: impl FileSource) -> RepoResult<File> {
+        self.inner.write_file(path, data).await
+    }
+
+    async fn checksum(&self, path: PathBuf, algorithm: Checksum) -> RepoResult<String> {
+        let cell = self.hashes.lock().unwrap().entry((path.clone(), algorithm)).or_default().clone();
+        Ok(cell.get_or_try_init(|| self.inner.checksum(path, algorithm)).await?.clone())
+    }
+
+    async fn copy_file(&self, source: PathBuf, dest: PathBuf) -> RepoResult<File> {
+        self.inner.copy_file(source, dest).await
+    }
+
+    async fn trash(&self, path: PathBuf) -> RepoResult<String> {
+        self.inner.trash(path).await
+    }
+
+    async fn keep_version(&self, path: PathBuf) -> RepoResult<String> {
+        self.inner.keep_version(path).await
+    }
+
+    async fn restore(&self, id: &str, path: PathBuf) -> RepoResult<()> {
+        self.inner.restore(id, path).await
+    }
+
+    async fn delete_permanent(&self, path: PathBuf) -> RepoResult<()> {
+        self.inner.delete_permanent(path).await
+    }
+
+    async fn rename(&self, from: PathBuf, to: PathBuf) -> RepoResult<Entry> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn set_modified(&self, path: PathBuf, modified: DateTime<Utc>) -> RepoResult<File> {
+        self.inner.set_modified(path, modified).await
+    }
+
+    async fn set_metadata(&self, path: PathBuf, metadata: &BTreeMap<String, String>) -> RepoResult<()> {
+        self.inner.set_metadata(path, metadata).await
+    }
+
+    async fn create_link(&self, path: PathBuf, target: PathBuf) -> RepoResult<File> {
+        self.inner.create_link(path, target).await
+    }
+
+    async fn copy_from(&self, id: &str, dest: PathBuf) -> RepoResult<File> {
+        self.inner.copy_from(id, dest).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_checksums() {
+        use Checksum::*;
+        assert_eq!(Checksum::common(&[Sha256, Md5, Blake3], &[Sha256, Md5, Blake3]), Some(Blake3));
+        assert_eq!(Checksum::common(&[Sha256, Md5, Blake3], &[Md5, Sha256]), Some(Sha256));
+        assert_eq!(Checksum::common(&[Md5], &[Sha256, Md5]), Some(Md5));
+        assert_eq!(Checksum::common(&[Blake3], &[Sha256, Md5]), None);
+        assert_eq!(Checksum::common(&[], &[Sha256]), None);
+    }
+
+    #[test]
+    fn tells_checksums_apart() {
+        for checksum in Checksum::PREFERENCE {
+            assert_eq!(Checksum::of(&checksum.format("00ff")), checksum);
         }
+        assert_eq!(Checksum::Sha256.format("00ff"), "00ff");
+        assert_eq!(Checksum::Md5.format("00ff"), "md5:00ff");
     }
 
-    // For each entry in src,
-    // If dir, Ensure DST exists in dir, sync dir
+    #[test]
+    fn classifies_errors() {
+        assert!(RepoError::RateLimited(String::new()).is_retryable());
+        assert!(RepoError::Api { status: StatusCode::BAD_GATEWAY, body: String::new() }.is_retryable());
+        assert!(!RepoError::Api { status: StatusCode::BAD_REQUEST, body: String::new() }.is_retryable());
+        assert!(!RepoError::NotFound(String::new()).is_retryable());
+        assert!(RepoError::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset)).is_retryable());
+        assert!(matches!(RepoError::from(std::io::Error::from(std::io::ErrorKind::NotFound)), RepoError::NotFound(..)));
 
-    // Get shasums of files in src,
-    // For file in src, if shasum exists in dst, copy
-    // If not, copy from src to dst
-    Ok(())
+        let unconfirmed = RepoError::Unconfirmed(std::io::Error::from(std::io::ErrorKind::TimedOut).into());
+        assert!(!unconfirmed.is_retryable());
+        let wrapped = anyhow::Error::from(unconfirmed).context("Uploading");
+        assert!(!wrapped.chain().any(retryable));
+        assert!(RepoError::Other(anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::TimedOut)).context("Sending")).is_retryable());
+    }
 }
@@ -0,0 +1,132 @@
+use sha2::Digest;
+
+/// Extended attribute holding the original name of a local entry whose name was shortened
+pub const ORIGINAL_NAME: &str = "user.dsync.name";
+
+/// Characters the local filesystem doesn't allow in names, besides `/` which synced names
+/// never contain
+#[cfg(windows)]
+const INVALID: &[char] = &['\\', ':', '*', '?', '"', '<', '>', '|'];
+#[cfg(not(windows))]
+const INVALID: &[char] = &[];
+
+/// Characters the local filesystem doesn't allow at the end of names
+#[cfg(windows)]
+const TRAILING: &[char] = &['.', ' '];
+#[cfg(not(windows))]
+const TRAILING: &[char] = &[];
+
+/// Longest name in bytes most filesystems allow
+pub const MAX_LEN: usize = 255;
+
+/// Precedes characters of the original name that look like replacements, only needed where
+/// there are replacements
+const QUOTE: char = '‛';
+const QUOTED: bool = cfg!(windows);
+
+/// Stand-in for a character, its fullwidth form, or the symbol for spaces and control characters
+fn replacement(c: char) -> char {
+    match c {
+        ' ' => '␠',
+        c if c.is_ascii_control() => char::from_u32(0x2400 + c as u32).unwrap(),
+        c => char::from_u32(c as u32 + 0xFEE0).unwrap(),
+    }
+}
+
+/// The character a replacement stands for
+fn original(c: char) -> Option<char> {
+    match c {
+        '␠' if TRAILING.contains(&' ') => Some(' '),
+        '\u{2400}'..='\u{241F}' if cfg!(windows) => char::from_u32(c as u32 - 0x2400),
+        c => char::from_u32((c as u32).wrapping_sub(0xFEE0)).filter(|c| INVALID.contains(c) || TRAILING.contains(c)),
+    }
+}
+
+/// Encode a name so that the local filesystem can store it, along with whether it can be
+/// decoded from the encoded name alone. Names that are too long are shortened, their original
+/// is kept in the [`ORIGINAL_NAME`] attribute.
+pub fn encode(name: &str) -> (String, bool) {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        if QUOTED && c == QUOTE || original(c).is_some() {
+            out.push(QUOTE);
+            out.push(c);
+        } else if INVALID.contains(&c) || cfg!(windows) && c.is_ascii_control() {
+            out.push(replacement(c));
+        } else {
+            out.push(c);
+        }
+    }
+    if out.ends_with(TRAILING) {
+        let last = out.pop().unwrap();
+        out.push(replacement(last));
+    }
+
+    if out.len() <= MAX_LEN {
+        return (out, true);
+    }
+    let hash = hex::encode(&sha2::Sha256::digest(name.as_bytes())[..4]);
+    let ext = match out.rsplit_once('.') {
+        Some((_, ext)) if ext.len() <= 16 => format!(".{ext}"),
+        _ => String::new(),
+    };
+    let start = truncate(&out, MAX_LEN - ext.len() - hash.len() - 1);
+    (format!("{start}~{hash}{ext}"), false)
+}
+
+/// The longest prefix of `name` that fits into `len` bytes
+pub fn truncate(name: &str, len: usize) -> &str {
+    let mut end = len.min(name.len());
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// Reverse [`encode`] for names that weren't shortened
+pub fn decode(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            QUOTE if QUOTED => out.extend(chars.next()),
+            c => out.push(original(c).unwrap_or(c)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let names = ["plain.txt", "a:b*c?.txt", "quote\"d <x>|y", "trailing. ", "ctrl\u{1}", "‛quoted", "ａ：fullwidth", "␠", "trailing."];
+        for name in names {
+            let (encoded, reversible) = encode(name);
+            assert!(reversible, "{name:?}");
+            assert!(!encoded.contains(INVALID), "{encoded:?}");
+            assert!(!encoded.ends_with(TRAILING), "{encoded:?}");
+            assert_eq!(decode(&encoded), name, "{encoded:?}");
+        }
+    }
+
+    #[test]
+    fn shortens_long_names() {
+        let name = format!("{}.txt", "é".repeat(200));
+        let (encoded, reversible) = encode(&name);
+        assert!(!reversible);
+        assert!(encoded.len() <= MAX_LEN);
+        assert!(encoded.ends_with(".txt"));
+        assert_eq!(encode(&name).0, encoded);
+        assert_ne!(encode(&format!("{}.txt", "é".repeat(201))).0, encoded);
+    }
+
+    #[test]
+    fn truncates_at_char_boundaries() {
+        assert_eq!(truncate("aé", 2), "a");
+        assert_eq!(truncate("aé", 3), "aé");
+        assert_eq!(truncate("abc", 10), "abc");
+    }
+}
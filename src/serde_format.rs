@@ -1,25 +1,3 @@
-pub mod string {
-    use std::fmt::Display;
-    use std::str::FromStr;
-
-    use serde::{de, Serializer, Deserialize, Deserializer};
-
-    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
-        where T: Display,
-              S: Serializer
-    {
-        serializer.collect_str(value)
-    }
-
-    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
-        where T: FromStr,
-              T::Err: Display,
-              D: Deserializer<'de>
-    {
-        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
-    }
-}
-
 pub mod opt_string {
     use std::fmt::Display;
     use std::str::FromStr;
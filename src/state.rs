@@ -0,0 +1,546 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use anyhow::{bail, format_err};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use crate::repo::File;
+use crate::sync::{Op, SyncPlan};
+
+const HEADER: &str = "dsync-state 1";
+const HASHES_HEADER: &str = "dsync-hashes 1";
+
+/// Directory holding dsync's own bookkeeping (snapshots, journals, caches)
+pub fn state_dir() -> PathBuf {
+    dirs::data_local_dir().unwrap().join("dsync")
+}
+
+/// What a file looked like on both sides after it was last synced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateEntry {
+    pub size: u64,
+    pub shasum: Option<String>,
+    pub src_modified: Option<DateTime<Utc>>,
+    pub dst_modified: Option<DateTime<Utc>>,
+}
+
+impl StateEntry {
+    pub fn new(src: &File, dst: &File) -> Self {
+        Self {
+            size: src.size,
+            shasum: src.shasum.clone().or_else(|| dst.shasum.clone()),
+            src_modified: src.modified,
+            dst_modified: dst.modified,
+        }
+    }
+
+    /// Neither side changed since the snapshot was taken
+    pub fn unchanged(&self, src: &File, dst: &File) -> bool {
+        self.src_modified.is_some()
+            && self.dst_modified.is_some()
+            && self.size == src.size
+            && self.size == dst.size
+            && self.src_modified == src.modified
+            && self.dst_modified == dst.modified
+    }
+}
+
+/// Snapshot of the last successful sync between a source and a destination.
+///
+/// Stored as a line based text file, one file per line:
+/// `<size>\t<shasum>\t<src mtime>\t<dst mtime>\t<path>`, with `-` for unknown values
+/// and tabs, newlines and backslashes in paths escaped.
+#[derive(Debug, Default, Clone)]
+pub struct SyncState {
+    pub entries: BTreeMap<PathBuf, StateEntry>,
+}
+
+impl SyncState {
+    /// Location of the state file for a given pair of sync locations
+    pub fn path_for(src: &str, dst: &str) -> PathBuf {
+        let mut sha = sha2::Sha256::default();
+        sha.update(src.as_bytes());
+        sha.update(b"\n");
+        sha.update(dst.as_bytes());
+        state_dir().join("state").join(hex::encode(sha.finalize()))
+    }
+
+    /// Name of the state shared through the appDataFolder of a drive, for syncs with the Drive
+    /// folder `folder_id` on the `src` or destination side. Unlike [`SyncState::path_for`] it
+    /// doesn't depend on the local path, so every machine syncing the folder finds it.
+    pub fn shared_name(folder_id: &str, src: bool) -> String {
+        let mut sha = sha2::Sha256::default();
+        sha.update(folder_id.as_bytes());
+        sha.update(if src { b"\nsrc" } else { b"\ndst" });
+        format!("state-{}", hex::encode(sha.finalize()))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::File::open(path) {
+            Ok(file) => Self::read(BufReader::new(file), &format!("{path:?}")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Parse a state file, `origin` names it in errors
+    pub fn read(reader: impl BufRead, origin: &str) -> anyhow::Result<Self> {
+        let mut lines = reader.lines();
+        match lines.next().transpose()? {
+            Some(header) if header == HEADER => {}
+            Some(header) => bail!("Unsupported state file {origin}: {header:?}"),
+            None => return Ok(Self::default()),
+        }
+
+        let mut entries = BTreeMap::new();
+        for (num, line) in lines.enumerate() {
+            let line = line?;
+            let (path, entry) = parse_line(&line)
+                .ok_or_else(|| format_err!("Invalid state file {origin} at line {}", num + 2))?;
+            entries.insert(path, entry);
+        }
+
+        Ok(Self { entries })
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension("tmp");
+
+        let mut out = BufWriter::new(std::fs::File::create(&tmp)?);
+        self.write(&mut out)?;
+        out.into_inner()?.sync_all()?;
+
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn write(&self, mut out: impl Write) -> std::io::Result<()> {
+        writeln!(out, "{HEADER}")?;
+        for (path, entry) in &self.entries {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}",
+                entry.size,
+                entry.shasum.as_deref().unwrap_or("-"),
+                format_time(entry.src_modified),
+                format_time(entry.dst_modified),
+                escape(&path.to_string_lossy()),
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&StateEntry> {
+        self.entries.get(path)
+    }
+
+    pub fn insert(&mut self, path: PathBuf, entry: StateEntry) {
+        self.entries.insert(path, entry);
+    }
+}
+
+fn format_time(time: Option<DateTime<Utc>>) -> String {
+    time.map(|t| t.to_rfc3339()).unwrap_or_else(|| "-".to_string())
+}
+
+fn parse_time(s: &str) -> Option<Option<DateTime<Utc>>> {
+    if s == "-" {
+        return Some(None);
+    }
+    DateTime::parse_from_rfc3339(s).ok().map(|t| Some(t.with_timezone(&Utc)))
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, StateEntry)> {
+    let mut parts = line.splitn(5, '\t');
+    let size = parts.next()?.parse().ok()?;
+    let shasum = parts.next()?;
+    let src_modified = parse_time(parts.next()?)?;
+    let dst_modified = parse_time(parts.next()?)?;
+    let path = PathBuf::from(unescape(parts.next()?)?);
+
+    let entry = StateEntry {
+        size,
+        shasum: (shasum != "-").then(|| shasum.to_string()),
+        src_modified,
+        dst_modified,
+    };
+    Some((path, entry))
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => out.push('\\'),
+            't' => out.push('\t'),
+            'n' => out.push('\n'),
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+/// Checksums of local files by absolute path, reused while their size and modification
+/// time stay the same.
+#[derive(Debug, Default)]
+pub struct HashCache {
+    entries: HashMap<PathBuf, (u64, Option<DateTime<Utc>>, String)>,
+    /// Whether anything changed since loading
+    dirty: bool,
+}
+
+static HASHES: OnceLock<Mutex<HashCache>> = OnceLock::new();
+
+impl HashCache {
+    fn path() -> PathBuf {
+        state_dir().join("hashes")
+    }
+
+    /// The cache shared by the whole process, loaded on first use
+    fn global() -> &'static Mutex<HashCache> {
+        HASHES.get_or_init(|| {
+            let cache = Self::load(&Self::path()).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring hash cache: {e}");
+                Self::default()
+            });
+            Mutex::new(cache)
+        })
+    }
+
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        match lines.next().transpose()? {
+            Some(header) if header == HASHES_HEADER => {}
+            Some(header) => bail!("Unsupported hash cache {path:?}: {header:?}"),
+            None => return Ok(Self::default()),
+        }
+
+        let mut entries = HashMap::new();
+        for (num, line) in lines.enumerate() {
+            let line = line?;
+            let invalid = || format_err!("Invalid hash cache {path:?} at line {}", num + 2);
+            let mut parts = line.splitn(4, '\t');
+            let size = parts.next().and_then(|s| s.parse().ok()).ok_or_else(invalid)?;
+            let modified = parts.next().and_then(parse_time).ok_or_else(invalid)?;
+            let shasum = parts.next().ok_or_else(invalid)?.to_string();
+            let path = parts.next().and_then(unescape).ok_or_else(invalid)?;
+            entries.insert(PathBuf::from(path), (size, modified, shasum));
+        }
+        Ok(Self { entries, dirty: false })
+    }
+
+    /// Checksum of the file at `path`, if it was hashed with the same size and modification time
+    pub fn get(path: &Path, size: u64, modified: Option<DateTime<Utc>>) -> Option<String> {
+        let cache = Self::global().lock().unwrap();
+        match cache.entries.get(path) {
+            Some((s, m, shasum)) if *s == size && *m == modified && modified.is_some() => Some(shasum.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn insert(path: PathBuf, size: u64, modified: Option<DateTime<Utc>>, shasum: String) {
+        let mut cache = Self::global().lock().unwrap();
+        cache.entries.insert(path, (size, modified, shasum));
+        cache.dirty = true;
+    }
+
+    /// Write the cache back to disk, if it was used and changed
+    pub fn save() -> anyhow::Result<()> {
+        let Some(cache) = HASHES.get() else {
+            return Ok(());
+        };
+        let cache = cache.lock().unwrap();
+        if !cache.dirty {
+            return Ok(());
+        }
+
+        let path = Self::path();
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension("tmp");
+
+        let mut out = BufWriter::new(std::fs::File::create(&tmp)?);
+        writeln!(out, "{HASHES_HEADER}")?;
+        for (path, (size, modified, shasum)) in &cache.entries {
+            writeln!(out, "{size}\t{}\t{shasum}\t{}", format_time(*modified), escape(&path.to_string_lossy()))?;
+        }
+        out.into_inner()?.sync_all()?;
+
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// File under `dir` in the state directory holding what's stored for `key`
+pub fn key_path(dir: &str, key: &str) -> PathBuf {
+    let mut sha = sha2::Sha256::default();
+    sha.update(key.as_bytes());
+    state_dir().join(dir).join(hex::encode(sha.finalize()))
+}
+
+/// Marker of a transfer that was interrupted part-way, so it can be continued instead of
+/// starting over.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PartialTransfer {
+    /// Where the partial data lives, an upload session URI or a temporary file
+    pub location: String,
+    pub len: u64,
+}
+
+impl PartialTransfer {
+    pub fn load(key: &str) -> Option<Self> {
+        let data = std::fs::read(key_path("partial", key)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub fn save(&self, key: &str) -> anyhow::Result<()> {
+        let path = key_path("partial", key);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    pub fn remove(key: &str) {
+        let _ = std::fs::remove_file(key_path("partial", key));
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalLine {
+    done: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    entry: Option<(PathBuf, StateEntry)>,
+}
+
+/// Progress of a plan being applied.
+///
+/// The first line holds the plan itself, every following line marks one completed operation.
+pub struct Journal {
+    path: PathBuf,
+    file: std::fs::File,
+}
+
+/// A plan that was interrupted, with the operations that did complete
+pub struct Resumed {
+    pub plan: SyncPlan,
+    pub done: HashSet<usize>,
+    pub entries: Vec<(PathBuf, StateEntry)>,
+}
+
+impl Journal {
+    pub fn create(path: &Path, plan: &SyncPlan) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let mut file = std::fs::File::create(path)?;
+        serde_json::to_writer(&mut file, plan)?;
+        writeln!(file)?;
+        file.sync_data()?;
+        Ok(Self { path: path.to_owned(), file })
+    }
+
+    /// Continue appending to an existing journal
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new().append(true).open(path)?;
+        Ok(Self { path: path.to_owned(), file })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Option<Resumed>> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        let Some(plan) = lines.next().transpose()? else {
+            return Ok(None);
+        };
+        let mut resumed = Resumed {
+            plan: serde_json::from_str(&plan)?,
+            done: HashSet::new(),
+            entries: vec![],
+        };
+
+        for line in lines {
+            // The last line may be cut short if we were killed while writing it
+            let Ok(line) = serde_json::from_str::<JournalLine>(&line?) else {
+                break;
+            };
+            resumed.done.insert(line.done);
+            resumed.entries.extend(line.entry);
+        }
+
+        Ok(Some(resumed))
+    }
+
+    pub fn complete(&mut self, done: usize, entry: Option<(PathBuf, StateEntry)>) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.file, &JournalLine { done, entry })?;
+        writeln!(self.file)?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// The plan was fully applied, the journal is no longer needed
+    pub fn finish(self) -> anyhow::Result<()> {
+        drop(self.file);
+        std::fs::remove_file(self.path)?;
+        Ok(())
+    }
+}
+
+/// A single sync of a destination with a source
+pub struct Run {
+    pub id: String,
+    pub started: DateTime<Utc>,
+    pub src: String,
+    pub dst: String,
+}
+
+impl Run {
+    pub fn new(src: &str, dst: &str) -> Self {
+        let started = Utc::now();
+        let state = SyncState::path_for(src, dst);
+        let pair = state.file_name().unwrap().to_string_lossy();
+        Self {
+            id: format!("{}-{}", started.format("%Y%m%d%H%M%S"), &pair[..8]),
+            started,
+            src: src.to_string(),
+            dst: dst.to_string(),
+        }
+    }
+
+    pub fn state_path(&self) -> PathBuf {
+        SyncState::path_for(&self.src, &self.dst)
+    }
+
+    /// Journal of the sync in progress, shared by all runs between the same locations
+    pub fn journal_path(&self) -> PathBuf {
+        self.state_path().with_extension("journal")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UndoHeader {
+    pub src: String,
+    pub dst: String,
+    pub started: DateTime<Utc>,
+}
+
+/// An applied operation, with the location the data it replaced was preserved at
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UndoEntry {
+    pub op: Op,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backup: Option<PathBuf>,
+    /// Id the replaced or deleted entry was trashed or kept under, when it wasn't backed up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trashed: Option<String>,
+}
+
+/// Record of the operations applied by a run, used to revert it.
+///
+/// The first line holds an [`UndoHeader`], every following line one [`UndoEntry`]. The file is
+/// only created once the first operation is applied.
+pub struct UndoLog {
+    path: PathBuf,
+    header: UndoHeader,
+    file: Option<std::fs::File>,
+}
+
+impl UndoLog {
+    fn dir() -> PathBuf {
+        state_dir().join("runs")
+    }
+
+    pub fn create(run: &Run) -> Self {
+        Self {
+            path: Self::dir().join(&run.id),
+            header: UndoHeader { src: run.src.clone(), dst: run.dst.clone(), started: run.started },
+            file: None,
+        }
+    }
+
+    pub fn record(&mut self, op: Op, backup: Option<PathBuf>, trashed: Option<String>) -> anyhow::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                std::fs::create_dir_all(Self::dir())?;
+                let mut file = std::fs::File::create(&self.path)?;
+                serde_json::to_writer(&mut file, &self.header)?;
+                writeln!(file)?;
+                self.file.insert(file)
+            }
+        };
+        serde_json::to_writer(&mut *file, &UndoEntry { op, backup, trashed })?;
+        writeln!(file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn load(id: &str) -> anyhow::Result<(UndoHeader, Vec<UndoEntry>)> {
+        let file = std::fs::File::open(Self::dir().join(id))
+            .map_err(|e| format_err!("Unknown run {id:?}: {e}"))?;
+        let mut lines = BufReader::new(file).lines();
+        let header = lines.next().transpose()?
+            .ok_or_else(|| format_err!("Empty undo log for run {id:?}"))?;
+        let header = serde_json::from_str(&header)?;
+
+        let mut entries = vec![];
+        for line in lines {
+            // The last line may be cut short if we were killed while writing it
+            let Ok(entry) = serde_json::from_str(&line?) else {
+                break;
+            };
+            entries.push(entry);
+        }
+        Ok((header, entries))
+    }
+
+    /// Ids of recorded runs, oldest first
+    pub fn list() -> anyhow::Result<Vec<(String, UndoHeader)>> {
+        let mut runs = vec![];
+        let entries = match std::fs::read_dir(Self::dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(runs),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let id = entry?.file_name().to_string_lossy().into_owned();
+            let (header, _) = Self::load(&id)?;
+            runs.push((id, header));
+        }
+        runs.sort_by_key(|(_, header)| header.started);
+        Ok(runs)
+    }
+
+    /// The run was reverted, there is nothing left to undo
+    pub fn remove(id: &str) -> anyhow::Result<()> {
+        std::fs::remove_file(Self::dir().join(id))?;
+        Ok(())
+    }
+}
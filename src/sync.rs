@@ -0,0 +1,1882 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{self, AtomicBool};
+use std::time::{Duration, Instant};
+use anyhow::bail;
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::ValueEnum;
+use futures::future::{LocalBoxFuture, try_join, try_join_all};
+use futures::{Stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use rand::Rng;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use crate::filter::Filter;
+use crate::repo::{self, Checksum, Entry, File, FileSource, Repo, RepoError, EXPORTED, GID, INODE, LINK, MODE, UID, XATTR};
+use crate::state::{Journal, Resumed, Run, StateEntry, SyncState, UndoEntry, UndoLog};
+
+/// What to do when a file exists on both sides with different contents
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConflictStrategy {
+    /// Keep the more recently modified file
+    Newer,
+    /// Keep the larger file
+    Larger,
+    /// Always overwrite the destination
+    #[default]
+    SrcWins,
+    /// Never overwrite the destination
+    DstWins,
+    /// Keep both versions on the destination, the destination one renamed with a suffix
+    RenameBoth,
+}
+
+/// How to decide whether a file on both sides has the same contents
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CompareMode {
+    /// Same size
+    Size,
+    /// Same modification time
+    Mtime,
+    /// Same checksum, requires hashing files without a stored checksum
+    #[default]
+    Checksum,
+    /// Same size and modification time
+    #[value(name = "size+mtime")]
+    SizeMtime,
+}
+
+/// Order in which operations of the same phase are scheduled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OrderBy {
+    /// Alphabetically by destination path
+    #[default]
+    Name,
+    /// Smallest files first
+    #[value(name = "size,asc")]
+    SizeAsc,
+    /// Largest files first
+    #[value(name = "size,desc")]
+    SizeDesc,
+    /// Least recently modified files first
+    Mtime,
+}
+
+impl OrderBy {
+    pub fn compare(&self, a: &Op, b: &Op) -> std::cmp::Ordering {
+        match self {
+            OrderBy::Name => a.target().cmp(b.target()),
+            OrderBy::SizeAsc => a.file().map(|f| f.size).cmp(&b.file().map(|f| f.size)),
+            OrderBy::SizeDesc => b.file().map(|f| f.size).cmp(&a.file().map(|f| f.size)),
+            OrderBy::Mtime => a.file().and_then(|f| f.modified).cmp(&b.file().and_then(|f| f.modified)),
+        }
+    }
+}
+
+/// Modification times closer than this are considered equal, backends store them with
+/// different precision.
+const MODIFY_WINDOW: TimeDelta = TimeDelta::seconds(1);
+
+fn same_mtime(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (a - b).abs() < MODIFY_WINDOW,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// Remove destination entries that no longer exist in the source
+    pub delete: bool,
+    pub conflict: ConflictStrategy,
+    pub compare: CompareMode,
+    /// Number of operations applied concurrently
+    pub transfers: usize,
+    pub order: OrderBy,
+    /// Stop starting new transfers after this many bytes
+    pub max_transfer: Option<u64>,
+    /// Stop starting new operations after this much time
+    pub max_duration: Option<Duration>,
+    /// Destination directory receiving overwritten and deleted files instead of losing them
+    pub backup_dir: Option<PathBuf>,
+    /// Include/exclude rules, entries they exclude are left alone on both sides
+    pub filter: Option<Filter>,
+    /// Directories containing a file with one of these names are left alone on both sides
+    pub exclude_if_present: Vec<String>,
+    /// Leave entries whose name starts with a `.` alone on both sides, trashed Drive files are
+    /// never listed
+    pub skip_hidden: bool,
+    /// Entries nested deeper than this are left alone on both sides, 1 syncs only the entries
+    /// of the root directory
+    pub max_depth: Option<usize>,
+    /// Files smaller than this are left alone on both sides
+    pub min_size: Option<u64>,
+    /// Files larger than this are left alone on both sides
+    pub max_size: Option<u64>,
+    /// Source files modified more recently than this are skipped
+    pub min_age: Option<Duration>,
+    /// Source files modified longer ago than this are skipped
+    pub max_age: Option<Duration>,
+    /// Hash files by downloading them when the backend doesn't provide a checksum
+    pub download: bool,
+    /// How many times failed operations are retried as a whole. Drive requests back off on their
+    /// own within each attempt, so this bounds neither the requests sent nor the time spent.
+    pub retries: u32,
+    /// How many files are hashed at once
+    pub checkers: usize,
+    /// Only sync these paths instead of the whole tree
+    pub files_from: Option<Vec<PathBuf>>,
+    /// Suffix appended to names of overwritten and deleted files, `{timestamp}` is replaced
+    /// with the time of the run
+    pub suffix: Option<String>,
+    /// Mirror source directories that contain no files
+    pub create_empty_dirs: bool,
+    /// Delete destination directories left empty by deletions
+    pub delete_empty_dirs: bool,
+    /// Carry the permissions and extended attributes of transferred files over to the destination
+    pub metadata: bool,
+    /// Carry the owner and group of transferred files over to the destination
+    pub owner: bool,
+    /// Delete destination files for good instead of moving them to the trash
+    pub permanent: bool,
+    /// Algorithm files are hashed with when comparing checksums, picked from what both sides
+    /// provide when not given
+    pub hash: Option<Checksum>,
+    /// Both sides are on the same drive, so files are copied there instead of transferred
+    pub server_side: bool,
+    /// Download transferred files into this directory before writing them, instead of
+    /// streaming them from one side to the other
+    pub staging_dir: Option<PathBuf>,
+}
+
+impl SyncOptions {
+    fn checksum(&self) -> Checksum {
+        self.hash.unwrap_or_default()
+    }
+
+    /// Whether a directory is excluded by `--exclude-if-present`, given its contents
+    fn marked(&self, entries: &[Entry]) -> bool {
+        entries.iter().any(|e| matches!(e, Entry::File(..)) && self.exclude_if_present.iter().any(|m| m == e.name()))
+    }
+
+    /// Whether the entry is within `--min-size` and `--max-size`, directories always are
+    fn size_allowed(&self, entry: &Entry) -> bool {
+        match entry {
+            Entry::File(file) => {
+                self.min_size.is_none_or(|min| file.size >= min) && self.max_size.is_none_or(|max| file.size <= max)
+            }
+            Entry::Dir(..) => true,
+        }
+    }
+
+    /// Whether a source entry is within `--min-age` and `--max-age`, directories and files
+    /// without a modification time always are
+    fn age_allowed(&self, entry: &Entry) -> bool {
+        let Entry::File(File { modified: Some(modified), .. }) = entry else {
+            return true;
+        };
+        let age = (Utc::now() - *modified).to_std().unwrap_or_default();
+        self.min_age.is_none_or(|min| age >= min) && self.max_age.is_none_or(|max| age <= max)
+    }
+
+    /// The part of a source file's metadata that's carried over to the destination
+    fn preserved(&self, file: &File) -> BTreeMap<String, String> {
+        file.metadata.iter()
+            .filter(|(key, _)| match key.as_str() {
+                MODE => self.metadata,
+                UID | GID => self.owner,
+                key => self.metadata && key.starts_with(XATTR),
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Whether the destination file `existing` lacks some of the metadata preserved from `file`.
+    /// Metadata only present on the destination is left alone.
+    fn metadata_differs(&self, file: &File, existing: &File) -> bool {
+        self.preserved(file).iter().any(|(key, value)| existing.metadata.get(key) != Some(value))
+    }
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            delete: false,
+            conflict: Default::default(),
+            compare: Default::default(),
+            transfers: 4,
+            order: Default::default(),
+            max_transfer: None,
+            max_duration: None,
+            backup_dir: None,
+            suffix: None,
+            files_from: None,
+            retries: 3,
+            checkers: 8,
+            download: false,
+            filter: None,
+            exclude_if_present: vec![],
+            skip_hidden: false,
+            max_depth: None,
+            min_size: None,
+            max_size: None,
+            min_age: None,
+            max_age: None,
+            create_empty_dirs: false,
+            delete_empty_dirs: false,
+            metadata: false,
+            owner: false,
+            permanent: false,
+            hash: None,
+            server_side: false,
+            staging_dir: None,
+        }
+    }
+}
+
+/// The run was stopped by `--max-transfer` or `--max-duration`, progress is kept in the journal
+#[derive(Debug)]
+pub struct LimitReached;
+
+impl Display for LimitReached {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Transfer limit reached, run again to continue")
+    }
+}
+
+impl std::error::Error for LimitReached {}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Stop running syncs: operations in progress are finished, but no new ones are started
+pub fn interrupt() {
+    INTERRUPTED.store(true, atomic::Ordering::Relaxed);
+}
+
+/// Fail once [`interrupt`] was called, for stopping between directories while planning
+fn check_interrupted() -> anyhow::Result<()> {
+    match INTERRUPTED.load(atomic::Ordering::Relaxed) {
+        true => Err(Interrupted { remaining: None }.into()),
+        false => Ok(()),
+    }
+}
+
+fn paused() -> &'static tokio::sync::watch::Sender<bool> {
+    static PAUSED: OnceLock<tokio::sync::watch::Sender<bool>> = OnceLock::new();
+    PAUSED.get_or_init(|| tokio::sync::watch::channel(false).0)
+}
+
+/// Hold running syncs: no new operations are started and transfers stop between chunks
+pub fn pause() {
+    paused().send_replace(true);
+}
+
+/// Continue syncs held by [`pause`]
+pub fn resume() {
+    paused().send_replace(false);
+}
+
+/// Wait until syncs aren't paused
+async fn unpaused() {
+    let _ = paused().subscribe().wait_for(|paused| !paused).await;
+}
+
+/// Source that stops streaming while syncs are paused
+struct Pausable<S>(S);
+
+impl<S: FileSource> FileSource for Pausable<S> {
+    async fn len(&self) -> u64 {
+        self.0.len().await
+    }
+
+    fn stream(self, from: u64, chunks: usize) -> impl Stream<Item=anyhow::Result<Vec<u8>>> {
+        self.0.stream(from, chunks).then(|chunk| async move {
+            unpaused().await;
+            chunk
+        })
+    }
+
+    fn range(&self, from: u64, to: u64, chunks: usize) -> Option<impl Stream<Item=anyhow::Result<Vec<u8>>>> {
+        Some(self.0.range(from, to, chunks)?.then(|chunk| async move {
+            unpaused().await;
+            chunk
+        }))
+    }
+}
+
+/// Source carrying the modification time of the file it's read from
+struct Modified<S>(S, Option<DateTime<Utc>>);
+
+impl<S: FileSource> FileSource for Modified<S> {
+    async fn len(&self) -> u64 {
+        self.0.len().await
+    }
+
+    fn stream(self, from: u64, chunks: usize) -> impl Stream<Item=anyhow::Result<Vec<u8>>> {
+        self.0.stream(from, chunks)
+    }
+
+    fn range(&self, from: u64, to: u64, chunks: usize) -> Option<impl Stream<Item=anyhow::Result<Vec<u8>>>> {
+        self.0.range(from, to, chunks)
+    }
+
+    fn modified(&self) -> Option<DateTime<Utc>> {
+        self.1
+    }
+}
+
+/// File downloaded into the staging directory, removed once it's dropped
+struct Staged(PathBuf);
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Download `data` into a file in `dir`, returning it along with a source reading it back
+async fn stage(data: impl FileSource, dir: &Path) -> anyhow::Result<(Staged, repo::LocalFileSource)> {
+    let staged = Staged(dir.join(format!(".dsync-staged-{:016x}", rand::random::<u64>())));
+    let mut out = tokio::fs::File::create(&staged.0).await?;
+    let stream = data.stream(0, 1 << 20);
+    futures::pin_mut!(stream);
+    while let Some(chunk) = stream.try_next().await? {
+        out.write_all(&chunk).await?;
+    }
+    out.flush().await?;
+    let source = repo::LocalFileSource::open(&staged.0).await?;
+    Ok((staged, source))
+}
+
+/// The run was stopped by [`interrupt`], progress is kept in the journal
+#[derive(Debug)]
+pub struct Interrupted {
+    /// `None` when stopped while planning, before anything was applied
+    pub remaining: Option<usize>,
+}
+
+impl Display for Interrupted {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.remaining {
+            Some(remaining) => write!(f, "Interrupted with {remaining} operation(s) remaining, run again to continue"),
+            None => write!(f, "Interrupted while planning, nothing was changed"),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// The run was stopped because the destination ran out of storage, progress is kept in the
+/// journal
+#[derive(Debug)]
+pub struct OutOfStorage {
+    pub remaining: usize,
+}
+
+impl Display for OutOfStorage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Destination is out of storage with {} operation(s) remaining, free up space and run again to continue", self.remaining)
+    }
+}
+
+impl std::error::Error for OutOfStorage {}
+
+/// An operation that couldn't be applied
+#[derive(Debug)]
+pub struct Failure {
+    pub op: &'static str,
+    pub path: PathBuf,
+    pub error: anyhow::Error,
+}
+
+/// Some operations of a run failed, everything else was applied
+#[derive(Debug)]
+pub struct SyncFailed(pub Vec<Failure>);
+
+impl Display for SyncFailed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} operation(s) failed:", self.0.len())?;
+        for Failure { op, path, error } in &self.0 {
+            write!(f, "\n  {op:<10} {}: {error:#}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SyncFailed {}
+
+/// Keeps track of the limits of a single run
+struct Budget {
+    deadline: Option<Instant>,
+    max_bytes: Option<u64>,
+    bytes: Cell<u64>,
+    /// Set once an operation failed in a way no other operation can succeed
+    aborted: Cell<bool>,
+    exhausted: Cell<bool>,
+}
+
+impl Budget {
+    fn new(opts: &SyncOptions) -> Self {
+        Self {
+            deadline: opts.max_duration.map(|d| Instant::now() + d),
+            max_bytes: opts.max_transfer,
+            bytes: Cell::new(0),
+            aborted: Cell::new(false),
+            exhausted: Cell::new(false),
+        }
+    }
+
+    /// Whether `op` may be started, operations already running are always allowed to finish
+    fn start(&self, op: &Op) -> bool {
+        let over_time = self.deadline.is_some_and(|d| Instant::now() >= d);
+        let over_size = self.max_bytes.is_some_and(|max| self.bytes.get() >= max);
+        let interrupted = INTERRUPTED.load(atomic::Ordering::Relaxed);
+        if self.aborted.get() {
+            return false;
+        }
+        if self.exhausted.get() || over_time || over_size || interrupted {
+            self.exhausted.set(true);
+            return false;
+        }
+        if let Op::Transfer { file, .. } = op {
+            self.bytes.set(self.bytes.get() + file.size);
+        }
+        true
+    }
+}
+
+const CONFLICT_SUFFIX: &str = ".conflict-dst";
+
+/// Name the destination version of a conflicting file is kept under, with a suffix between
+/// file stem and extension: `a.txt` -> `a.conflict-dst.txt`
+fn conflict_name(name: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem}{CONFLICT_SUFFIX}.{ext}"),
+        _ => format!("{name}{CONFLICT_SUFFIX}"),
+    }
+}
+
+/// Names produced by [`conflict_name`]
+fn is_conflict_copy(name: &str) -> bool {
+    name.ends_with(CONFLICT_SUFFIX) || name.contains(&format!("{CONFLICT_SUFFIX}."))
+}
+
+/// A single change to apply to the destination
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    CreateDir { path: PathBuf },
+    /// Server-side copy of a destination file with the same contents as source `file`
+    Copy { from: PathBuf, to: PathBuf, file: File },
+    /// Server-side move, `file` is the source file now living at `to`, if any
+    Move { from: PathBuf, to: PathBuf, file: Option<File> },
+    /// Transfer source `path` to destination `to`, `replaces` is set when a different file
+    /// already exists there
+    Transfer {
+        path: PathBuf,
+        to: PathBuf,
+        file: File,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        replaces: bool,
+    },
+    /// Server-side copy of a file transferred earlier in the same run, for hard links to the
+    /// same source file
+    Duplicate { from: PathBuf, to: PathBuf, file: File },
+    /// Carry the metadata of source `file` over to the destination file at `path`, whose
+    /// contents are already in sync
+    Metadata { path: PathBuf, file: File },
+    Delete { path: PathBuf },
+}
+
+impl Op {
+    /// Ops are applied in phases: directories first, then everything reading existing
+    /// destination files, transfers, copies of transferred files, and deletions last.
+    fn phase(&self) -> u8 {
+        match self {
+            Op::CreateDir { .. } => 0,
+            Op::Copy { .. } => 1,
+            Op::Move { .. } => 2,
+            // Links may point at files transferred in the same run
+            Op::Transfer { file, .. } if file.metadata.contains_key(LINK) => 4,
+            Op::Transfer { .. } | Op::Metadata { .. } => 3,
+            Op::Duplicate { .. } => 4,
+            Op::Delete { .. } => 5,
+        }
+    }
+
+    /// Destination path changed by this op
+    pub fn target(&self) -> &Path {
+        match self {
+            Op::CreateDir { path } | Op::Metadata { path, .. } | Op::Delete { path } => path,
+            Op::Copy { to, .. } | Op::Move { to, .. } | Op::Transfer { to, .. } | Op::Duplicate { to, .. } => to,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Op::CreateDir { .. } => "create_dir",
+            Op::Copy { .. } => "copy",
+            Op::Move { .. } => "move",
+            Op::Transfer { .. } => "transfer",
+            Op::Duplicate { .. } => "duplicate",
+            Op::Metadata { .. } => "metadata",
+            Op::Delete { .. } => "delete",
+        }
+    }
+
+    /// Kind of destination data loss caused by this op, if any
+    pub fn destructive(&self) -> Option<&'static str> {
+        match self {
+            Op::Delete { .. } => Some("delete"),
+            Op::Transfer { replaces: true, .. } => Some("overwrite"),
+            _ => None,
+        }
+    }
+
+    /// Source file written by this op
+    fn file(&self) -> Option<&File> {
+        match self {
+            Op::Copy { file, .. } | Op::Transfer { file, .. } | Op::Duplicate { file, .. } => Some(file),
+            Op::Move { file, .. } => file.as_ref(),
+            Op::CreateDir { .. } | Op::Metadata { .. } | Op::Delete { .. } => None,
+        }
+    }
+}
+
+/// Answer to the confirmation of a destructive operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    Yes,
+    No,
+    /// Accept this and all remaining operations of the same kind
+    All,
+    /// Abort the sync
+    Quit,
+}
+
+/// Asked about every operation that deletes or overwrites destination data, before anything
+/// is applied
+pub trait Confirm {
+    fn confirm(&self, op: &Op) -> anyhow::Result<Answer>;
+}
+
+/// Accepts every operation
+pub struct NoConfirm;
+
+impl Confirm for NoConfirm {
+    fn confirm(&self, _op: &Op) -> anyhow::Result<Answer> {
+        Ok(Answer::Yes)
+    }
+}
+
+/// Drop destructive operations that weren't confirmed
+fn confirm_ops(ops: Vec<Op>, confirm: &dyn Confirm) -> anyhow::Result<Vec<Op>> {
+    let mut accepted = HashSet::new();
+    let mut out = Vec::with_capacity(ops.len());
+    for op in ops {
+        if let Some(kind) = op.destructive().filter(|kind| !accepted.contains(kind)) {
+            match confirm.confirm(&op)? {
+                Answer::Yes => {}
+                Answer::No => {
+                    info!("Skipping {kind} of {:?}", op.target());
+                    continue;
+                }
+                Answer::All => {
+                    accepted.insert(kind);
+                }
+                Answer::Quit => bail!("Aborted"),
+            }
+        }
+        out.push(op);
+    }
+    Ok(out)
+}
+
+/// Everything needed to bring the destination in sync with the source
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SyncPlan {
+    pub ops: Vec<Op>,
+    /// Files that were already in sync when planning
+    pub in_sync: BTreeMap<PathBuf, StateEntry>,
+}
+
+/// Sync `dst` with `src`, or finish the previous sync if it was interrupted
+pub async fn sync<S: Repo, D: Repo>(
+    src: &S,
+    dst: &D,
+    opts: &SyncOptions,
+    state: &mut SyncState,
+    run: &Run,
+    confirm: &dyn Confirm,
+) -> anyhow::Result<()> {
+    let journal = &run.journal_path();
+    if let Some(resumed) = Journal::load(journal)? {
+        info!(
+            "Resuming interrupted sync, {} of {} operations already done",
+            resumed.done.len(),
+            resumed.plan.ops.len()
+        );
+        let journal = Journal::open(journal)?;
+        return execute(src, dst, resumed, opts, state, run, journal).await;
+    }
+
+    let plan = plan(src, dst, opts, state).await?;
+    apply(src, dst, plan, opts, state, run, confirm).await
+}
+
+/// How two trees differ, paths in each list are sorted
+#[derive(Debug, Default)]
+pub struct TreeDiff {
+    pub only_src: Vec<PathBuf>,
+    pub only_dst: Vec<PathBuf>,
+    /// Present on both sides with different contents, or a file on one side and a directory
+    /// on the other
+    pub differ: Vec<PathBuf>,
+    pub identical: Vec<PathBuf>,
+}
+
+/// Compare both sides without changing anything, leaving out what a sync with the same options
+/// wouldn't touch. Directories present on one side only are reported as a whole.
+pub async fn diff<S: Repo, D: Repo>(src: &S, dst: &D, opts: &SyncOptions, state: &SyncState) -> anyhow::Result<TreeDiff> {
+    let opts = &negotiate(src, dst, opts)?;
+    let syncer = Syncer {
+        src,
+        dst,
+        opts,
+        prev: state,
+        next: Default::default(),
+        diff: Default::default(),
+        backup: None,
+        backup_dirs: Default::default(),
+        kept: Default::default(),
+        checkers: Semaphore::new(opts.checkers),
+    };
+    let out = Mutex::new(TreeDiff::default());
+    syncer.diff_dir(PathBuf::new(), &out).await?;
+
+    let mut out = out.into_inner().unwrap();
+    for list in [&mut out.only_src, &mut out.only_dst, &mut out.differ, &mut out.identical] {
+        // Directories holding some of the paths are reported as a whole as well
+        if let Some(paths) = &opts.files_from {
+            list.retain(|path| paths.iter().any(|p| path.starts_with(p) || p.starts_with(path)));
+        }
+        list.sort();
+    }
+    Ok(out)
+}
+
+/// Verify that both sides are identical by comparing checksums of every file, ignoring
+/// anything remembered from previous syncs
+pub async fn check<S: Repo, D: Repo>(src: &S, dst: &D, opts: &SyncOptions) -> anyhow::Result<TreeDiff> {
+    let opts = SyncOptions { compare: CompareMode::Checksum, ..opts.clone() };
+    diff(src, dst, &opts, &SyncState::default()).await
+}
+
+/// Compare both sides, producing the operations needed to sync them without changing anything
+pub async fn plan<S: Repo, D: Repo>(src: &S, dst: &D, opts: &SyncOptions, state: &SyncState) -> anyhow::Result<SyncPlan> {
+    let opts = &negotiate(src, dst, opts)?;
+    let syncer = Syncer {
+        src,
+        dst,
+        opts,
+        prev: state,
+        next: Default::default(),
+        diff: Default::default(),
+        backup: None,
+        backup_dirs: Default::default(),
+        kept: Default::default(),
+        checkers: Semaphore::new(opts.checkers),
+    };
+    match &opts.files_from {
+        Some(paths) => {
+            // Paths that weren't looked at keep their previous state
+            for (path, entry) in &state.entries {
+                if !paths.iter().any(|p| path.starts_with(p)) {
+                    syncer.record(path.clone(), entry.clone());
+                }
+            }
+            syncer.sync_paths(paths).await?
+        }
+        None => syncer.sync_dir(PathBuf::new(), true).await?,
+    }
+
+    let mut ops = syncer.resolve().await?;
+    ops.sort_by_key(Op::phase);
+
+    Ok(SyncPlan {
+        ops,
+        in_sync: syncer.next.into_inner().unwrap().entries,
+    })
+}
+
+/// Execute a plan, replacing `state` with the snapshot of the synced files once it succeeds.
+///
+/// Progress is recorded in the journal so that an interrupted run can be resumed, and every
+/// applied operation in the undo log of the `run`. Destructive operations are only applied
+/// once accepted by `confirm`.
+pub async fn apply<S: Repo, D: Repo>(
+    src: &S,
+    dst: &D,
+    plan: SyncPlan,
+    opts: &SyncOptions,
+    state: &mut SyncState,
+    run: &Run,
+    confirm: &dyn Confirm,
+) -> anyhow::Result<()> {
+    let mut plan = plan;
+    plan.ops = confirm_ops(plan.ops, confirm)?;
+
+    let journal = Journal::create(&run.journal_path(), &plan)?;
+    let resumed = Resumed {
+        plan,
+        done: Default::default(),
+        entries: vec![],
+    };
+    execute(src, dst, resumed, opts, state, run, journal).await
+}
+
+async fn execute<S: Repo, D: Repo>(
+    src: &S,
+    dst: &D,
+    resumed: Resumed,
+    opts: &SyncOptions,
+    state: &mut SyncState,
+    run: &Run,
+    mut journal: Journal,
+) -> anyhow::Result<()> {
+    let mut undo = UndoLog::create(run);
+    let Resumed { plan, done, entries } = resumed;
+
+    let syncer = Syncer {
+        src,
+        dst,
+        opts,
+        prev: state,
+        next: Mutex::new(SyncState { entries: plan.in_sync }),
+        diff: Default::default(),
+        backup: Backup::new(opts, run.started),
+        backup_dirs: Default::default(),
+        kept: Default::default(),
+        checkers: Semaphore::new(opts.checkers),
+    };
+    for (path, entry) in entries {
+        syncer.record(path, entry);
+    }
+
+    let mut ops: Vec<(usize, Op)> = plan.ops
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, _)| !done.contains(idx))
+        .collect();
+    ops.sort_by(|(_, a), (_, b)| a.phase().cmp(&b.phase()).then_with(|| opts.order.compare(a, b)));
+
+    let budget = Budget::new(opts);
+    let mut failures = vec![];
+    let mut remaining = ops.len();
+
+    // Operations within a phase are independent and run concurrently, except for directory
+    // creation where parents must exist before their children.
+    while let Some(phase) = ops.first().map(|(_, op)| op.phase()) {
+        let split = ops.iter().position(|(_, op)| op.phase() != phase).unwrap_or(ops.len());
+        let rest = ops.split_off(split);
+        let limit = if phase == 0 { 1 } else { opts.transfers.max(1) };
+
+        let syncer = &syncer;
+        let budget = &budget;
+        let mut results = futures::stream::iter(ops)
+            .take_while(|(_, op)| futures::future::ready(budget.start(op)))
+            .map(|(idx, op)| async move {
+                unpaused().await;
+                let applied = syncer.apply_retrying(&op).await;
+                (idx, op, applied)
+            })
+            .buffer_unordered(limit);
+
+        while let Some((idx, op, applied)) = results.next().await {
+            remaining -= 1;
+            let Applied { entry, backup, trashed } = match applied {
+                Ok(applied) => applied,
+                Err(error) => {
+                    // Not marked as done, so that it's tried again if this run gets resumed
+                    warn!("Failed to {} {:?}: {error:#}", op.name(), op.target());
+                    if is_fatal(&error) {
+                        budget.aborted.set(true);
+                    }
+                    failures.push(Failure { op: op.name(), path: op.target().to_owned(), error });
+                    continue;
+                }
+            };
+            if let Some((path, entry)) = &entry {
+                syncer.record(path.clone(), entry.clone());
+            }
+            undo.record(op, backup, trashed)?;
+            journal.complete(idx, entry)?;
+        }
+        if budget.aborted.get() {
+            return Err(OutOfStorage { remaining: remaining + failures.len() }.into());
+        }
+        if INTERRUPTED.load(atomic::Ordering::Relaxed) {
+            return Err(Interrupted { remaining: Some(remaining + failures.len()) }.into());
+        }
+        if budget.exhausted.get() {
+            return Err(LimitReached.into());
+        }
+        ops = rest;
+    }
+    journal.finish()?;
+
+    let next = syncer.next.into_inner().unwrap();
+    for path in state.entries.keys().filter(|p| !next.entries.contains_key(*p)) {
+        info!("{path:?} is no longer in sync since the last run");
+    }
+    *state = next;
+
+    if !failures.is_empty() {
+        return Err(SyncFailed(failures).into());
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Diff {
+    ops: Vec<Op>,
+    /// Source files missing on the destination
+    missing: Vec<(PathBuf, File)>,
+    /// Destination files missing in the source
+    extra: Vec<(PathBuf, File)>,
+    /// Number of entries in each listed destination directory
+    dirs: HashMap<PathBuf, usize>,
+}
+
+struct Syncer<'a, S, D> {
+    src: &'a S,
+    dst: &'a D,
+    opts: &'a SyncOptions,
+    /// Snapshot of the previous successful sync
+    prev: &'a SyncState,
+    /// Snapshot being built by this sync
+    next: Mutex<SyncState>,
+    diff: Mutex<Diff>,
+    /// Where replaced files are preserved during this run
+    backup: Option<Backup>,
+    /// Serializes creation of backup directories, so that they aren't created twice
+    backup_dirs: tokio::sync::Mutex<()>,
+    /// Trash ids of replaced files, remembered for retries of the operations replacing them
+    kept: Mutex<HashMap<PathBuf, String>>,
+    /// Limits the number of files hashed at once
+    checkers: Semaphore,
+}
+
+/// `--backup-dir` relative to the destination root
+fn backup_root(opts: &SyncOptions) -> Option<&Path> {
+    let dir = opts.backup_dir.as_deref()?;
+    Some(dir.strip_prefix("/").unwrap_or(dir))
+}
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H%M%SZ";
+
+/// Destination entries without a source counterpart that earlier runs set aside: versions kept
+/// by `--suffix` and destination copies kept by `--conflict rename-both`. They are neither
+/// deleted nor moved, source files with such names are synced as usual.
+fn set_aside(opts: &SyncOptions, name: &str) -> bool {
+    is_versioned(opts, name) || is_conflict_copy(name)
+}
+
+/// Names produced by `--suffix`
+fn is_versioned(opts: &SyncOptions, name: &str) -> bool {
+    let Some(suffix) = &opts.suffix else {
+        return false;
+    };
+    match suffix.split_once("{timestamp}") {
+        Some((pre, post)) => name.ends_with(post) && name[..name.len() - post.len()].contains(pre),
+        None => name.ends_with(suffix.as_str()),
+    }
+}
+
+/// Only create directories that will have files written into them
+fn skip_empty_dirs(ops: &mut Vec<Op>) {
+    let mut used = HashSet::new();
+    for op in ops.iter().filter(|op| op.file().is_some()) {
+        used.extend(op.target().ancestors().skip(1).map(Path::to_owned));
+    }
+    ops.retain(|op| match op {
+        Op::CreateDir { path } => used.contains(path),
+        _ => true,
+    });
+}
+
+/// Delete destination directories that would be left empty by the deletions in `ops`,
+/// `dirs` holds the number of entries listed in each destination directory, including ones
+/// that aren't synced.
+fn prune_empty_dirs(ops: &mut Vec<Op>, dirs: HashMap<PathBuf, usize>) {
+    let mut removed: HashMap<PathBuf, usize> = HashMap::new();
+    let mut added: HashSet<PathBuf> = HashSet::new();
+    for op in ops.iter() {
+        match op {
+            Op::Delete { path } | Op::Move { from: path, .. } => {
+                *removed.entry(parent(path)).or_default() += 1;
+            }
+            _ => {}
+        }
+        if !matches!(op, Op::Delete { .. } | Op::Transfer { replaces: true, .. }) {
+            added.insert(parent(op.target()));
+        }
+    }
+
+    // Deepest first, so that parents emptied by pruning their children are pruned as well
+    let mut candidates: Vec<_> = dirs.into_iter().filter(|(dir, _)| dir != Path::new("")).collect();
+    candidates.sort_by_key(|(dir, _)| std::cmp::Reverse(dir.components().count()));
+
+    let mut pruned: Vec<PathBuf> = vec![];
+    for (dir, len) in candidates {
+        let gone = removed.get(&dir).copied().unwrap_or(0);
+        if gone == 0 || gone < len || added.contains(&dir) {
+            continue;
+        }
+        info!("Removing {dir:?}, it would be left empty");
+        *removed.entry(parent(&dir)).or_default() += 1;
+        pruned.push(dir);
+    }
+
+    ops.retain(|op| match op {
+        Op::Delete { path } => !pruned.iter().any(|d| path.starts_with(d)),
+        _ => true,
+    });
+    let nested = |dir: &PathBuf| pruned.iter().any(|d| d != dir && dir.starts_with(d));
+    let pruned: Vec<PathBuf> = pruned.iter().filter(|dir| !nested(dir)).cloned().collect();
+    ops.extend(pruned.into_iter().map(|path| Op::Delete { path }));
+}
+
+fn parent(path: &Path) -> PathBuf {
+    path.parent().map(Path::to_owned).unwrap_or_default()
+}
+
+/// Where files replaced during a run are preserved
+struct Backup {
+    /// `--backup-dir` with the time of the run appended
+    dir: Option<PathBuf>,
+    /// `--suffix` with the time of the run filled in
+    suffix: Option<String>,
+}
+
+impl Backup {
+    fn new(opts: &SyncOptions, started: DateTime<Utc>) -> Option<Self> {
+        let stamp = started.format(TIMESTAMP_FORMAT).to_string();
+        let backup = Self {
+            dir: backup_root(opts).map(|root| root.join(&stamp)),
+            suffix: opts.suffix.as_ref().map(|s| s.replace("{timestamp}", &stamp)),
+        };
+        (backup.dir.is_some() || backup.suffix.is_some()).then_some(backup)
+    }
+
+    /// Location the old version of `path` is preserved at
+    fn target(&self, path: &Path) -> PathBuf {
+        let target = match &self.dir {
+            Some(dir) => dir.join(path),
+            None => path.to_owned(),
+        };
+        match &self.suffix {
+            Some(suffix) => {
+                let name = target.file_name().unwrap_or_default().to_string_lossy();
+                target.with_file_name(format!("{name}{suffix}"))
+            }
+            None => target,
+        }
+    }
+}
+
+/// Checksum of a file, listed or computed by the repo
+async fn file_shasum<R: Repo>(repo: &R, path: &Path, file: &File, algorithm: Checksum) -> anyhow::Result<String> {
+    match &file.shasum {
+        Some(shasum) if Checksum::of(shasum) == algorithm => Ok(shasum.clone()),
+        _ => Ok(repo.checksum(path.to_owned(), algorithm).await?),
+    }
+}
+
+/// Hash a file by reading its contents
+async fn download_shasum<R: Repo>(repo: &R, path: &Path, algorithm: Checksum) -> anyhow::Result<String> {
+    let data = repo.read_file(path.to_owned()).await?;
+    let stream = data.stream(0, 1 << 20);
+    futures::pin_mut!(stream);
+
+    let mut hasher = algorithm.hasher();
+    while let Some(chunk) = stream.try_next().await? {
+        hasher.update(&chunk);
+    }
+    Ok(hasher.finish())
+}
+
+/// Settle on the checksum algorithm files are compared with: the strongest one both sides
+/// provide, unless one was picked explicitly. Without any in common, files are compared by
+/// size and modification time instead.
+fn negotiate<S: Repo, D: Repo>(src: &S, dst: &D, opts: &SyncOptions) -> anyhow::Result<SyncOptions> {
+    let mut opts = opts.clone();
+    if opts.compare != CompareMode::Checksum {
+        return Ok(opts);
+    }
+    let (srcs, dsts) = (src.capabilities().checksums, dst.capabilities().checksums);
+    let common = Checksum::common(srcs, dsts);
+    match (opts.hash, common) {
+        // Files are downloaded to hash them when needed
+        (Some(_), _) if opts.download => {}
+        (Some(hash), _) if srcs.contains(&hash) && dsts.contains(&hash) => {}
+        (Some(hash), _) => {
+            let name = hash.to_possible_value().unwrap();
+            bail!("{} checksums aren't available on both sides, use --download or leave out --hash", name.get_name());
+        }
+        (None, Some(hash)) => {
+            info!("Comparing files by {hash:?} checksums");
+            opts.hash = Some(hash);
+        }
+        (None, None) if opts.download => opts.hash = Some(Checksum::Sha256),
+        (None, None) => {
+            warn!("Both sides have no checksum algorithm in common, comparing files by size and modification time");
+            opts.compare = CompareMode::SizeMtime;
+        }
+    }
+    Ok(opts)
+}
+
+impl<'a, S: Repo, D: Repo> Syncer<'a, S, D> {
+    /// Checksum of a file, downloading it if the repo can't provide one and `--download` is set
+    async fn shasum<R: Repo>(&self, repo: &R, path: &Path, file: &File) -> anyhow::Result<String> {
+        let algorithm = self.opts.checksum();
+        if let Some(shasum) = file.shasum.as_ref().filter(|s| Checksum::of(s) == algorithm) {
+            return Ok(shasum.clone());
+        }
+        let _permit = self.checkers.acquire().await?;
+        match file_shasum(repo, path, file, algorithm).await {
+            Err(e) if self.opts.download => {
+                info!("Hashing {path:?} by downloading it, no checksum available: {e:#}");
+                download_shasum(repo, path, algorithm).await
+            }
+            res => res,
+        }
+    }
+
+    fn record(&self, path: PathBuf, entry: StateEntry) {
+        self.next.lock().unwrap().insert(path, entry);
+    }
+
+    fn push(&self, op: Op) {
+        self.diff.lock().unwrap().ops.push(op);
+    }
+
+    /// Returns the state entry describing both files if they have the same contents.
+    ///
+    /// Files that didn't change since the previous sync aren't hashed again.
+    async fn compare(&self, path: &Path, file: &File, existing: &File) -> anyhow::Result<Option<StateEntry>> {
+        // Links are the same when they point to the same place, whatever their times
+        if file.metadata.contains_key(LINK) || existing.metadata.contains_key(LINK) {
+            let same = file.metadata.get(LINK) == existing.metadata.get(LINK);
+            return Ok(same.then(|| StateEntry::new(file, existing)));
+        }
+        // Exported documents have neither a size nor a checksum, only their times tell
+        let exported = file.metadata.contains_key(EXPORTED) || existing.metadata.contains_key(EXPORTED);
+        let sizes = exported || file.size == existing.size;
+        let unchanged = self.prev.get(path).filter(|e| e.unchanged(file, existing));
+        let mtimes = unchanged.is_some() || same_mtime(file.modified, existing.modified);
+
+        let same = match self.opts.compare {
+            _ if exported => mtimes,
+            CompareMode::Size => sizes,
+            CompareMode::Mtime => mtimes,
+            CompareMode::SizeMtime => sizes && mtimes,
+            CompareMode::Checksum => {
+                if !sizes {
+                    return Ok(None);
+                }
+                if let Some(prev) = unchanged {
+                    return Ok(Some(prev.clone()));
+                }
+                return self.compare_checksums(path, file, existing).await;
+            }
+        };
+
+        Ok(same.then(|| match unchanged {
+            Some(prev) => prev.clone(),
+            None => StateEntry::new(file, existing),
+        }))
+    }
+
+    async fn compare_checksums(&self, path: &Path, file: &File, existing: &File) -> anyhow::Result<Option<StateEntry>> {
+        let (a, b) = try_join(
+            self.shasum(self.src, path, file),
+            self.shasum(self.dst, path, existing),
+        ).await?;
+
+        Ok((a == b).then(|| StateEntry {
+            shasum: Some(a),
+            ..StateEntry::new(file, existing)
+        }))
+    }
+
+    /// Checksum of a destination file, taken from the previous snapshot if it didn't change
+    async fn dst_shasum(&self, path: &Path, file: &File) -> anyhow::Result<String> {
+        let prev = self.prev.get(path)
+            .filter(|e| e.dst_modified.is_some() && e.dst_modified == file.modified && e.size == file.size)
+            .and_then(|e| e.shasum.clone())
+            .filter(|s| Checksum::of(s) == self.opts.checksum());
+        match prev {
+            Some(shasum) => Ok(shasum),
+            None => self.shasum(self.dst, path, file).await,
+        }
+    }
+
+    /// Diff a single directory, descending into subdirectories concurrently.
+    ///
+    /// `exists` is false for directories missing on the destination, they are created unless excluded.
+    fn sync_dir(&'a self, path: PathBuf, exists: bool) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+        let (src, dst) = (self.src, self.dst);
+        Box::pin(async move {
+            // Directories at the depth limit are only created, not descended into
+            if self.opts.max_depth.is_some_and(|max| path.components().count() >= max) {
+                if !exists {
+                    self.push(Op::CreateDir { path });
+                }
+                return Ok(());
+            }
+
+            check_interrupted()?;
+            let mut dsts = if exists { dst.list(path.clone()).await? } else { vec![] };
+            // Counted before anything is left alone, so that directories holding skipped entries
+            // are never considered emptied by deletions
+            if exists {
+                self.diff.lock().unwrap().dirs.insert(path.clone(), dsts.len());
+            }
+
+            // Source entries are synced as they're listed, unless the whole listing is needed
+            // to look for markers
+            let mut srcs = src.list_stream(path.clone()).map_err(anyhow::Error::from).boxed_local();
+            if !self.opts.exclude_if_present.is_empty() {
+                let listed: Vec<Entry> = srcs.try_collect().await?;
+                if self.opts.marked(&listed) || self.opts.marked(&dsts) {
+                    info!("Skipping {path:?}, it contains an --exclude-if-present marker");
+                    return Ok(());
+                }
+                srcs = futures::stream::iter(listed.into_iter().map(Ok)).boxed_local();
+            }
+            if !exists {
+                self.push(Op::CreateDir { path: path.clone() });
+            }
+
+            // Never sync into or out of the backup directory
+            dsts.retain(|e| !self.skipped(&path.join(e.name()), e));
+
+            // Files outside of the size limits on either side are left alone on both
+            // as are files of the wrong age in the source
+            let (dsts, limited): (Vec<_>, Vec<_>) = dsts.into_iter().partition(|e| self.opts.size_allowed(e));
+            let limited: HashSet<String> = limited.iter().map(|e| e.name().to_owned()).collect();
+            let mut dsts: HashMap<String, Entry> = dsts.into_iter().map(|d| (d.name().to_owned(), d)).collect();
+
+            srcs.try_filter_map(|entry| {
+                let existing = dsts.remove(entry.name());
+                let synced = !self.skipped(&path.join(entry.name()), &entry)
+                    && !limited.contains(entry.name())
+                    && self.opts.size_allowed(&entry)
+                    && self.opts.age_allowed(&entry);
+                futures::future::ready(Ok(synced.then_some((entry, existing))))
+            }).try_for_each_concurrent(None, |(entry, existing)| {
+                self.sync_entry(path.join(entry.name()), Some(entry), existing)
+            }).await?;
+
+            let children = dsts.into_iter()
+                .filter(|(name, _)| !set_aside(self.opts, name))
+                .map(|(name, entry)| self.sync_entry(path.join(name), None, Some(entry)));
+            try_join_all(children).await?;
+            Ok(())
+        })
+    }
+
+    /// Entries excluded by filters or `--skip-hidden` aren't synced, neither is the backup
+    /// directory
+    fn skipped(&self, path: &Path, entry: &Entry) -> bool {
+        let is_dir = matches!(entry, Entry::Dir(..));
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        backup_root(self.opts) == Some(path)
+            || self.opts.skip_hidden && name.starts_with('.')
+            || self.opts.filter.as_ref().is_some_and(|f| !f.included(path, is_dir))
+    }
+
+    /// Diff a single path, given what it is on either side
+    fn sync_entry(&'a self, path: PathBuf, src: Option<Entry>, dst: Option<Entry>) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            match (src, dst) {
+                (Some(Entry::Dir(..)), Some(Entry::File(..))) => bail!("Cannot sync directory {path:?}, destination is a file"),
+                (Some(Entry::File(..)), Some(Entry::Dir(..))) => bail!("Cannot sync file {path:?}, destination is a directory"),
+                (Some(Entry::Dir(..)), dst) => self.sync_dir(path, dst.is_some()).await?,
+                (Some(Entry::File(file)), Some(Entry::File(existing))) => self.sync_file(path, file, existing).await?,
+                (Some(Entry::File(file)), None) => self.diff.lock().unwrap().missing.push((path, file)),
+                (None, Some(Entry::File(file))) => self.diff.lock().unwrap().extra.push((path, file)),
+                (None, Some(Entry::Dir(..))) if self.opts.delete => {
+                    // Files of removed directories may have been moved elsewhere
+                    self.push(Op::Delete { path: path.clone() });
+                    self.walk_extra(path).await?;
+                }
+                (None, _) => {}
+            }
+            Ok(())
+        })
+    }
+
+    /// Diff a file that exists on both sides
+    async fn sync_file(&self, path: PathBuf, file: File, existing: File) -> anyhow::Result<()> {
+        if let Some(entry) = self.compare(&path, &file, &existing).await? {
+            self.record(path.clone(), entry);
+            if self.opts.metadata_differs(&file, &existing) {
+                self.push(Op::Metadata { path, file });
+            }
+            return Ok(());
+        }
+
+        let mut replaces = true;
+        let overwrite = match self.opts.conflict {
+            ConflictStrategy::Newer => file.modified > existing.modified,
+            ConflictStrategy::Larger => file.size > existing.size,
+            ConflictStrategy::SrcWins => true,
+            ConflictStrategy::DstWins => false,
+            ConflictStrategy::RenameBoth => {
+                // The source version takes the name, so that the next run finds it in sync
+                let renamed = parent(&path).join(conflict_name(&existing.name));
+                info!("Conflict on {path:?}, keeping destination as {renamed:?}");
+                self.push(Op::Move { from: path.clone(), to: renamed, file: None });
+                replaces = false;
+                true
+            }
+        };
+        if !overwrite {
+            info!("Conflict on {path:?}, keeping destination");
+            return Ok(());
+        }
+        self.push(Op::Transfer { path: path.clone(), to: path, file, replaces });
+        Ok(())
+    }
+
+    /// Diff only the given paths instead of walking the whole tree. Directories among them are
+    /// synced recursively.
+    async fn sync_paths(&self, paths: &[PathBuf]) -> anyhow::Result<()> {
+        let mut src_lists = HashMap::new();
+        let mut dst_lists = HashMap::new();
+        let mut created = HashSet::new();
+
+        for path in paths {
+            if paths.iter().any(|p| p != path && path.starts_with(p)) {
+                continue;
+            }
+            check_interrupted()?;
+            let src = lookup(self.src, path, &mut src_lists).await?;
+            let dst = lookup(self.dst, path, &mut dst_lists).await?;
+            let Some(entry) = src.as_ref().or(dst.as_ref()) else {
+                warn!("{path:?} doesn't exist on either side");
+                continue;
+            };
+            let sized = src.iter().chain(&dst).all(|e| self.opts.size_allowed(e))
+                && src.iter().all(|e| self.opts.age_allowed(e));
+            let deep = self.opts.max_depth.is_some_and(|max| path.components().count() > max);
+            let kept = src.is_none() && set_aside(self.opts, &path.file_name().unwrap_or_default().to_string_lossy());
+            if self.skipped(path, entry) || !sized || deep || kept {
+                continue;
+            }
+
+            // Parents of new entries may be missing on the destination as well
+            let dir = parent(path);
+            if src.is_some() && dst.is_none() && dir != Path::new("") && !created.contains(&dir)
+                && lookup(self.dst, &dir, &mut dst_lists).await?.is_none() {
+                created.insert(dir.clone());
+                self.push(Op::CreateDir { path: dir });
+            }
+
+            self.sync_entry(path.clone(), src, dst).await?;
+        }
+        Ok(())
+    }
+
+    /// Compare a directory present on both sides, descending into subdirectories concurrently
+    fn diff_dir(&'a self, path: PathBuf, out: &'a Mutex<TreeDiff>) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            // Directories at the depth limit aren't descended into
+            if self.opts.max_depth.is_some_and(|max| path.components().count() >= max) {
+                return Ok(());
+            }
+            let (srcs, dsts) = try_join(self.src.list(path.clone()), self.dst.list(path.clone())).await?;
+            if self.opts.marked(&srcs) || self.opts.marked(&dsts) {
+                return Ok(());
+            }
+            // Left alone like when syncing: files outside of the size limits on either side, and
+            // source files of the wrong age
+            let (dsts, limited): (Vec<_>, Vec<_>) = dsts.into_iter()
+                .filter(|e| !self.skipped(&path.join(e.name()), e))
+                .partition(|e| self.opts.size_allowed(e));
+            let limited: HashSet<String> = limited.iter().map(|e| e.name().to_owned()).collect();
+            let mut dsts: HashMap<String, Entry> = dsts.into_iter().map(|e| (e.name().to_owned(), e)).collect();
+
+            let mut children = vec![];
+            for entry in srcs {
+                let entry_path = path.join(entry.name());
+                let existing = dsts.remove(entry.name());
+                let compared = !self.skipped(&entry_path, &entry)
+                    && !limited.contains(entry.name())
+                    && self.opts.size_allowed(&entry)
+                    && self.opts.age_allowed(&entry);
+                if !compared {
+                    continue;
+                }
+                match (entry, existing) {
+                    (Entry::Dir(..), Some(Entry::Dir(..))) => children.push(self.diff_dir(entry_path, out)),
+                    (Entry::File(file), Some(Entry::File(existing))) => children.push(Box::pin(async move {
+                        let same = self.compare(&entry_path, &file, &existing).await?.is_some();
+                        let mut out = out.lock().unwrap();
+                        if same { &mut out.identical } else { &mut out.differ }.push(entry_path);
+                        Ok(())
+                    })),
+                    (_, Some(..)) => out.lock().unwrap().differ.push(entry_path),
+                    (_, None) => out.lock().unwrap().only_src.push(entry_path),
+                }
+            }
+            out.lock().unwrap().only_dst.extend(dsts.into_keys()
+                .filter(|name| !set_aside(self.opts, name))
+                .map(|name| path.join(name)));
+
+            try_join_all(children).await?;
+            Ok(())
+        })
+    }
+
+    /// Collect files of a destination directory that doesn't exist in the source
+    fn walk_extra(&'a self, path: PathBuf) -> LocalBoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.dst.list_stream(path.clone()).map_err(anyhow::Error::from).try_for_each_concurrent(None, |entry| {
+                let path = &path;
+                async move {
+                    match entry {
+                        Entry::Dir(dir) => self.walk_extra(path.join(&dir.name)).await?,
+                        Entry::File(file) => {
+                            self.diff.lock().unwrap().extra.push((path.join(&file.name), file));
+                        }
+                    }
+                    Ok(())
+                }
+            }).await
+        })
+    }
+
+    /// Match missing source files against extra destination files by content, turning
+    /// transfers into server-side copies, or moves when the old file would be deleted.
+    async fn resolve(&self) -> anyhow::Result<Vec<Op>> {
+        let Diff { mut ops, missing, extra, dirs } = std::mem::take(&mut *self.diff.lock().unwrap());
+
+        // Reusing destination files only pays off when they don't have to be transferred again
+        let caps = self.dst.capabilities();
+        let reuse = caps.server_copy || caps.server_move && self.opts.delete;
+        let mut by_size: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (idx, (_, file)) in extra.iter().enumerate().filter(|_| reuse) {
+            by_size.entry(file.size).or_default().push(idx);
+        }
+
+        let mut hashes: HashMap<usize, String> = HashMap::new();
+        let mut moved = HashSet::new();
+        // First transferred path of each hard linked source file
+        let mut linked: HashMap<String, PathBuf> = HashMap::new();
+
+        'missing: for (path, mut file) in missing {
+            if let Some(candidates) = by_size.get(&file.size) {
+                let shasum = self.shasum(self.src, &path, &file).await?;
+                file.shasum = Some(shasum.clone());
+
+                for &idx in candidates {
+                    let (from, existing) = &extra[idx];
+                    let candidate = match hashes.get(&idx) {
+                        Some(hash) => hash.clone(),
+                        None => {
+                            let hash = self.dst_shasum(from, existing).await?;
+                            hashes.insert(idx, hash.clone());
+                            hash
+                        }
+                    };
+                    if candidate != shasum {
+                        continue;
+                    }
+
+                    if self.opts.delete && moved.insert(idx) {
+                        info!("Detected move of {from:?} to {path:?}");
+                        ops.push(Op::Move { from: from.clone(), to: path, file: Some(file) });
+                    } else if caps.server_copy {
+                        info!("Copying {from:?} to {path:?}");
+                        ops.push(Op::Copy { from: from.clone(), to: path, file });
+                    } else {
+                        continue;
+                    }
+                    continue 'missing;
+                }
+            }
+            if let Some(inode) = file.metadata.get(INODE).filter(|_| caps.server_copy) {
+                if let Some(from) = linked.get(inode) {
+                    info!("{path:?} is a hard link to {from:?}, copying it after the transfer");
+                    ops.push(Op::Duplicate { from: from.clone(), to: path, file });
+                    continue;
+                }
+                linked.insert(inode.clone(), path.clone());
+            }
+            ops.push(Op::Transfer { path: path.clone(), to: path, file, replaces: false });
+        }
+
+        if self.opts.delete {
+            // Files inside of deleted directories go away with them
+            let dirs: Vec<PathBuf> = ops.iter()
+                .filter_map(|op| match op {
+                    Op::Delete { path } => Some(path.clone()),
+                    _ => None,
+                })
+                .collect();
+
+            ops.extend(extra.into_iter()
+                .enumerate()
+                .filter(|(idx, (path, _))| !moved.contains(idx) && !dirs.iter().any(|d| path.starts_with(d)))
+                .map(|(_, (path, _))| Op::Delete { path }));
+        }
+
+        if self.opts.delete_empty_dirs {
+            prune_empty_dirs(&mut ops, dirs);
+        }
+        if !self.opts.create_empty_dirs {
+            skip_empty_dirs(&mut ops);
+        }
+
+        Ok(ops)
+    }
+
+    /// Move a destination file or directory that is about to be replaced or deleted out of the
+    /// way, returning where it was preserved
+    async fn backup(&self, path: &Path) -> anyhow::Result<Option<PathBuf>> {
+        let Some(backup) = &self.backup else {
+            return Ok(None);
+        };
+        if self.dst.stat(path.to_owned()).await?.is_none() {
+            return Ok(None);
+        }
+        let target = backup.target(path);
+        let dir = parent(&target);
+        if dir != Path::new("") {
+            let _lock = self.backup_dirs.lock().await;
+            self.dst.create_dir(dir).await?;
+        }
+        info!("Backing up {path:?} to {target:?}");
+        self.dst.rename(path.to_owned(), target.clone()).await?;
+        Ok(Some(target))
+    }
+
+    /// Apply a single operation, retrying it with exponential backoff while it fails with
+    /// transient errors
+    async fn apply_retrying(&self, op: &Op) -> anyhow::Result<Applied> {
+        let mut attempt = 0;
+        loop {
+            match self.apply(op.clone()).await {
+                Err(e) if attempt < self.opts.retries && is_retryable(&e) => {
+                    attempt += 1;
+                    let delay = backoff(attempt);
+                    warn!("Failed to {} {:?}, retrying in {delay:?}: {e:#}", op.name(), op.target());
+                    tokio::time::sleep(delay).await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Apply a single operation
+    async fn apply(&self, op: Op) -> anyhow::Result<Applied> {
+        let (src, dst) = (self.src, self.dst);
+        let (mut backup, mut trashed) = (None, None);
+        match op {
+            Op::CreateDir { path } => {
+                info!("Creating {path:?}");
+                dst.create_dir(path).await?;
+            }
+            Op::Copy { from, to, file } | Op::Duplicate { from, to, file } => {
+                let copy = dst.copy_file(from, to.clone()).await?;
+                return Ok(Applied::synced(to, StateEntry::new(&file, &copy)));
+            }
+            Op::Move { from, to, file } => {
+                let moved = move_file(dst, from, to.clone()).await?;
+                if let Some(file) = file {
+                    return Ok(Applied::synced(to, StateEntry::new(&file, &moved)));
+                }
+            }
+            Op::Transfer { path, to, file, replaces } => {
+                info!("Transferring {path:?}");
+                if replaces {
+                    backup = self.backup(&to).await?;
+                    if backup.is_none() && !self.opts.permanent {
+                        trashed = match dst.keep_version(to.clone()).await {
+                            Ok(id) => {
+                                self.kept.lock().unwrap().insert(to.clone(), id.clone());
+                                Some(id)
+                            }
+                            // Kept by an earlier attempt
+                            Err(RepoError::NotFound(..)) => self.kept.lock().unwrap().get(&to).cloned(),
+                            Err(e) => return Err(e.into()),
+                        };
+                    }
+                }
+                let written = match file.metadata.get(LINK) {
+                    Some(target) => dst.create_link(to.clone(), PathBuf::from(target)).await?,
+                    None => {
+                        // Exports are new contents, only the documents themselves could be copied
+                        let mut written = match self.opts.server_side && !file.metadata.contains_key(EXPORTED) {
+                            true => dst.copy_from(&file.id, to.clone()).await?,
+                            false => {
+                                let data = src.read_file(path.clone()).await?;
+                                match &self.opts.staging_dir {
+                                    Some(dir) => {
+                                        let (_staged, data) = stage(data, dir).await?;
+                                        dst.write_file(to.clone(), Modified(Pausable(data), file.modified)).await?
+                                    }
+                                    None => dst.write_file(to.clone(), Modified(Pausable(data), file.modified)).await?,
+                                }
+                            }
+                        };
+                        // Repos that set it while writing store it with less precision
+                        let stamped = written.modified.map(|m| m.timestamp_millis()) == file.modified.map(|m| m.timestamp_millis());
+                        if let Some(modified) = file.modified.filter(|_| dst.capabilities().set_modified && !stamped) {
+                            written = dst.set_modified(to.clone(), modified).await?;
+                        }
+                        let metadata = self.opts.preserved(&file);
+                        if !metadata.is_empty() {
+                            dst.set_metadata(to.clone(), &metadata).await?;
+                        }
+                        written
+                    }
+                };
+                if path == to {
+                    return Ok(Applied { entry: Some((path, StateEntry::new(&file, &written))), backup, trashed });
+                }
+            }
+            Op::Metadata { path, file } => {
+                info!("Updating metadata of {path:?}");
+                dst.set_metadata(path, &self.opts.preserved(&file)).await?;
+            }
+            Op::Delete { path } => {
+                backup = self.backup(&path).await?;
+                if backup.is_some() {
+                    return Ok(Applied { entry: None, backup, trashed });
+                }
+                let deleted = match self.opts.permanent {
+                    true => {
+                        info!("Deleting {path:?}");
+                        dst.delete_permanent(path.clone()).await.map(|()| None)
+                    }
+                    false => {
+                        info!("Moving {path:?} to the trash");
+                        dst.trash(path.clone()).await.map(Some)
+                    }
+                };
+                match deleted {
+                    Err(RepoError::NotFound(..)) => info!("{path:?} was already deleted"),
+                    res => trashed = res?,
+                }
+            }
+        }
+        Ok(Applied { entry: None, backup, trashed })
+    }
+}
+
+/// Revert the operations of a run on its destination, newest first.
+///
+/// Overwritten and deleted files are restored from where `--backup-dir` or `--suffix` preserved
+/// them, or from the trash. Those deleted with `--permanent` are reported and skipped.
+pub async fn undo<D: Repo>(dst: &D, entries: Vec<UndoEntry>) -> anyhow::Result<()> {
+    let mut lost = 0;
+    for UndoEntry { op, backup, trashed } in entries.into_iter().rev() {
+        match op {
+            Op::CreateDir { path } => {
+                if dst.list(path.clone()).await?.is_empty() {
+                    info!("Removing {path:?}");
+                    dst.delete_permanent(path).await?;
+                } else {
+                    warn!("Keeping {path:?}, it is not empty");
+                }
+            }
+            Op::Copy { to, .. } | Op::Duplicate { to, .. } | Op::Transfer { to, replaces: false, .. } => {
+                info!("Removing {to:?}");
+                dst.trash(to).await?;
+            }
+            Op::Move { from, to, .. } => {
+                info!("Moving {to:?} back to {from:?}");
+                move_file(dst, to, from).await?;
+            }
+            Op::Metadata { path, .. } => {
+                // Previous values aren't kept, and contents were left alone
+                warn!("Keeping the updated metadata of {path:?}");
+            }
+            Op::Transfer { to: path, replaces: true, .. } | Op::Delete { path } => {
+                let Some(backup) = backup else {
+                    let Some(trashed) = trashed else {
+                        warn!("Cannot restore {path:?}, it was deleted permanently");
+                        lost += 1;
+                        continue;
+                    };
+                    info!("Restoring {path:?} from the trash");
+                    match dst.restore(&trashed, path.clone()).await {
+                        Err(RepoError::NotFound(..)) => {
+                            warn!("Cannot restore {path:?}, it is no longer in the trash");
+                            lost += 1;
+                        }
+                        res => res?,
+                    }
+                    continue;
+                };
+                if dst.stat(backup.clone()).await?.is_none() {
+                    warn!("Cannot restore {path:?}, backup {backup:?} no longer exists");
+                    lost += 1;
+                    continue;
+                }
+                info!("Restoring {path:?} from {backup:?}");
+                if dst.stat(path.clone()).await?.is_some() {
+                    dst.trash(path.clone()).await?;
+                }
+                dst.rename(backup, path).await?;
+            }
+        }
+    }
+    if lost > 0 {
+        bail!("{lost} overwritten or deleted entries could not be restored");
+    }
+    Ok(())
+}
+
+/// Move a file within `repo`, copying and deleting it where moves aren't supported
+async fn move_file<R: Repo>(repo: &R, from: PathBuf, to: PathBuf) -> anyhow::Result<File> {
+    if !repo.capabilities().server_move {
+        let copy = repo.copy_file(from.clone(), to).await?;
+        repo.delete_permanent(from).await?;
+        return Ok(copy);
+    }
+    match repo.rename(from.clone(), to).await? {
+        Entry::File(file) => Ok(file),
+        Entry::Dir(..) => bail!("Moved {from:?} is a directory"),
+    }
+}
+
+/// Whether retrying an operation that failed with `error` may help
+fn is_retryable(error: &anyhow::Error) -> bool {
+    error.chain().any(repo::retryable)
+}
+
+/// Whether `error` means no further operation can succeed
+fn is_fatal(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| cause.downcast_ref::<RepoError>().is_some_and(RepoError::is_fatal))
+}
+
+/// Delay before the given retry: doubling from one second up to a minute, with random jitter
+/// so that concurrent transfers don't retry in lockstep
+fn backoff(attempt: u32) -> Duration {
+    let max = Duration::from_secs(1 << attempt.saturating_sub(1).min(6)).min(Duration::from_secs(60));
+    max.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// Outcome of a single applied operation
+struct Applied {
+    /// State entry of the file that is now in sync
+    entry: Option<(PathBuf, StateEntry)>,
+    /// Where the replaced destination file was preserved
+    backup: Option<PathBuf>,
+    /// Id the replaced or deleted destination entry can be restored from, see [`Repo::restore`]
+    trashed: Option<String>,
+}
+
+impl Applied {
+    fn synced(path: PathBuf, entry: StateEntry) -> Self {
+        Self { entry: Some((path, entry)), backup: None, trashed: None }
+    }
+}
+
+/// Look up an entry by walking down from the root, `None` if it or any of its parents doesn't
+/// exist. Listings are kept in `lists` to be reused by later lookups.
+async fn lookup<R: Repo>(repo: &R, path: &Path, lists: &mut HashMap<PathBuf, Vec<Entry>>) -> anyhow::Result<Option<Entry>> {
+    let mut dir = PathBuf::new();
+    let mut current: Option<Entry> = None;
+    for (idx, name) in path.iter().enumerate() {
+        if idx > 0 {
+            match current {
+                Some(Entry::Dir(parent)) => dir.push(&parent.name),
+                _ => return Ok(None),
+            }
+        }
+        if !lists.contains_key(&dir) {
+            lists.insert(dir.clone(), repo.list(dir.clone()).await?);
+        }
+        current = lists[&dir].iter().find(|e| name == e.name()).cloned();
+    }
+    Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryRepo;
+
+    /// Runs keep their journal and undo log in the data directory, tests get their own
+    fn run(name: &str) -> Run {
+        static DATA: OnceLock<()> = OnceLock::new();
+        DATA.get_or_init(|| std::env::set_var("XDG_DATA_HOME", std::env::temp_dir().join(format!("dsync-test-{}", std::process::id()))));
+        Run::new(&format!("memory:{name}"), "memory:dst")
+    }
+
+    fn repo(files: &[(&str, &str)]) -> MemoryRepo {
+        let repo = MemoryRepo::default();
+        for (path, data) in files {
+            repo.insert(*path, data.as_bytes().to_vec(), Utc::now() - TimeDelta::days(1));
+        }
+        repo
+    }
+
+    /// Contents of every file in the repo
+    async fn files(repo: &MemoryRepo) -> BTreeMap<PathBuf, String> {
+        let mut out = BTreeMap::new();
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            for entry in repo.list(dir.clone()).await.unwrap() {
+                let path = dir.join(entry.name());
+                match entry {
+                    Entry::Dir(..) => dirs.push(path),
+                    Entry::File(..) => {
+                        let data = repo.read_file(path.clone()).await.unwrap().0;
+                        out.insert(path, String::from_utf8(data).unwrap());
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn ops(plan: &SyncPlan) -> Vec<(&'static str, &Path)> {
+        let mut ops: Vec<_> = plan.ops.iter().map(|op| (op.name(), op.target())).collect();
+        ops.sort();
+        ops
+    }
+
+    /// Plan and apply a sync, then undo it
+    async fn sync_and_undo(src: &MemoryRepo, dst: &MemoryRepo, opts: &SyncOptions, run: &Run) -> SyncPlan {
+        let mut state = SyncState::default();
+        let plan = plan(src, dst, opts, &state).await.unwrap();
+        let planned = SyncPlan { ops: plan.ops.clone(), in_sync: plan.in_sync.clone() };
+        apply(src, dst, plan, opts, &mut state, run, &NoConfirm).await.unwrap();
+        assert_eq!(files(dst).await, files(src).await);
+
+        let again = super::plan(src, dst, opts, &state).await.unwrap();
+        assert!(again.ops.is_empty(), "{:?}", again.ops);
+
+        let (_, entries) = UndoLog::load(&run.id).unwrap();
+        undo(dst, entries).await.unwrap();
+        planned
+    }
+
+    #[tokio::test]
+    async fn transfers_and_deletes() {
+        let src = repo(&[("a.txt", "new"), ("dir/b.txt", "b")]);
+        let dst = repo(&[("a.txt", "old"), ("c.txt", "c")]);
+        let before = files(&dst).await;
+        let opts = SyncOptions { delete: true, ..Default::default() };
+
+        let plan = sync_and_undo(&src, &dst, &opts, &run("transfers_and_deletes")).await;
+        assert_eq!(ops(&plan), [
+            ("create_dir", Path::new("dir")),
+            ("delete", Path::new("c.txt")),
+            ("transfer", Path::new("a.txt")),
+            ("transfer", Path::new("dir/b.txt")),
+        ]);
+        assert_eq!(files(&dst).await, before);
+        assert!(dst.stat("dir".into()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn detects_moves() {
+        let src = repo(&[("new/x.txt", "moved"), ("y.txt", "y")]);
+        let dst = repo(&[("old/x.txt", "moved"), ("y.txt", "y")]);
+        let before = files(&dst).await;
+        let opts = SyncOptions { delete: true, ..Default::default() };
+
+        let plan = sync_and_undo(&src, &dst, &opts, &run("detects_moves")).await;
+        assert_eq!(ops(&plan), [
+            ("create_dir", Path::new("new")),
+            ("delete", Path::new("old")),
+            ("move", Path::new("new/x.txt")),
+        ]);
+        assert_eq!(files(&dst).await, before);
+    }
+
+    #[tokio::test]
+    async fn keeps_both_sides_of_conflicts() {
+        let src = repo(&[("a.txt", "src")]);
+        let dst = repo(&[("a.txt", "dst")]);
+        let before = files(&dst).await;
+        let opts = SyncOptions { delete: true, conflict: ConflictStrategy::RenameBoth, ..Default::default() };
+
+        let mut state = SyncState::default();
+        let run = run("keeps_both_sides_of_conflicts");
+        let plan = plan(&src, &dst, &opts, &state).await.unwrap();
+        assert_eq!(ops(&plan), [("move", Path::new("a.conflict-dst.txt")), ("transfer", Path::new("a.txt"))]);
+        apply(&src, &dst, plan, &opts, &mut state, &run, &NoConfirm).await.unwrap();
+        assert_eq!(files(&dst).await, BTreeMap::from([
+            (PathBuf::from("a.conflict-dst.txt"), "dst".to_string()),
+            (PathBuf::from("a.txt"), "src".to_string()),
+        ]));
+
+        // The set aside copy is neither synced again nor deleted
+        let again = super::plan(&src, &dst, &opts, &state).await.unwrap();
+        assert!(again.ops.is_empty(), "{:?}", again.ops);
+
+        let (_, entries) = UndoLog::load(&run.id).unwrap();
+        undo(&dst, entries).await.unwrap();
+        assert_eq!(files(&dst).await, before);
+    }
+
+    fn delete(path: &str) -> Op {
+        Op::Delete { path: path.into() }
+    }
+
+    fn pruned(mut ops: Vec<Op>, dirs: &[(&str, usize)]) -> Vec<(&'static str, PathBuf)> {
+        prune_empty_dirs(&mut ops, dirs.iter().map(|(dir, len)| (PathBuf::from(dir), *len)).collect());
+        let mut ops: Vec<_> = ops.iter().map(|op| (op.name(), op.target().to_owned())).collect();
+        ops.sort();
+        ops
+    }
+
+    #[test]
+    fn prunes_emptied_dirs() {
+        let ops = pruned(vec![delete("a/x"), delete("a/b/y"), delete("c/z")], &[("", 2), ("a", 2), ("a/b", 1), ("c", 2)]);
+        assert_eq!(ops, [("delete", "a".into()), ("delete", "c/z".into())]);
+
+        // Moving the last entry out empties the directory as well
+        let ops = vec![Op::Move { from: "a/x".into(), to: "x".into(), file: None }];
+        assert_eq!(pruned(ops, &[("a", 1)]), [("delete", "a".into()), ("move", "x".into())]);
+    }
+
+    #[test]
+    fn keeps_dirs_with_remaining_entries() {
+        // Entries that aren't synced still count
+        assert_eq!(pruned(vec![delete("a/x")], &[("a", 2)]), [("delete", "a/x".into())]);
+        // So do entries about to be created
+        let ops = vec![delete("a/x"), Op::CreateDir { path: "a/new".into() }];
+        assert_eq!(pruned(ops, &[("a", 1)]), [("create_dir", "a/new".into()), ("delete", "a/x".into())]);
+        // The root is never removed
+        assert_eq!(pruned(vec![delete("x")], &[("", 1)]), [("delete", "x".into())]);
+    }
+
+    #[tokio::test]
+    async fn diffs_what_sync_would_touch() {
+        let src = repo(&[("a.txt", "a"), ("big.txt", "too large"), ("same.txt", "same"), ("skip.log", "s"), ("dir/deep/x.txt", "x")]);
+        let dst = repo(&[("same.txt", "same"), ("big.txt", "other"), ("dir/y.txt", "y")]);
+        let mut filter = crate::filter::Filter::default();
+        filter.add(false, "*.log");
+        let opts = SyncOptions { max_size: Some(4), max_depth: Some(1), filter: Some(filter), ..Default::default() };
+
+        let diff = diff(&src, &dst, &opts, &SyncState::default()).await.unwrap();
+        assert_eq!(diff.only_src, [PathBuf::from("a.txt")]);
+        assert!(diff.only_dst.is_empty() && diff.differ.is_empty());
+        assert_eq!(diff.identical, [PathBuf::from("same.txt")]);
+    }
+}
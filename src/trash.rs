@@ -0,0 +1,87 @@
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use chrono::Local;
+
+/// Move a local file or directory into the trash, following the freedesktop.org trash
+/// specification, so that file managers can restore it. Returns where it's kept in the trash.
+pub fn trash(path: &Path) -> std::io::Result<PathBuf> {
+    let dir = trash_dir(path)?;
+    let (files, info) = (dir.join("files"), dir.join("info"));
+    std::fs::create_dir_all(&files)?;
+    std::fs::create_dir_all(&info)?;
+
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    for n in 1.. {
+        let trashed = match n {
+            1 => name.to_string(),
+            n => format!("{name}.{n}"),
+        };
+        // Creating the info file first claims the name against concurrent trashing
+        let info = info.join(format!("{trashed}.trashinfo"));
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&info) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        };
+        let deleted = Local::now().format("%Y-%m-%dT%H:%M:%S");
+        write!(file, "[Trash Info]\nPath={}\nDeletionDate={deleted}\n", escape(path))?;
+
+        let trashed = files.join(&trashed);
+        if let Err(e) = std::fs::rename(path, &trashed) {
+            let _ = std::fs::remove_file(&info);
+            return Err(e);
+        }
+        return Ok(trashed);
+    }
+    unreachable!()
+}
+
+/// Move an entry kept in the trash at `trashed` back to `path`, dropping its trash info
+pub fn restore(trashed: &Path, path: &Path) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::rename(trashed, path)?;
+    if let (Some(files), Some(name)) = (trashed.parent(), trashed.file_name()) {
+        let info = files.with_file_name("info").join(format!("{}.trashinfo", name.to_string_lossy()));
+        let _ = std::fs::remove_file(info);
+    }
+    Ok(())
+}
+
+/// The trash of the user's home, or of the mount `path` is on when that's a different one,
+/// since entries can't be renamed across filesystems
+fn trash_dir(path: &Path) -> std::io::Result<PathBuf> {
+    let home = dirs::data_dir()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "No data directory to keep the trash in"))?
+        .join("Trash");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let device = |path: &Path| path.ancestors().find_map(|p| std::fs::metadata(p).ok()).map(|m| m.dev());
+
+        let parent = path.parent().unwrap_or(path);
+        let mount = device(parent);
+        if mount != device(&home) {
+            let top = parent.ancestors().take_while(|p| device(p) == mount).last().unwrap_or(parent);
+            // SAFETY: getuid has no preconditions and can't fail
+            let uid = unsafe { libc::getuid() };
+            return Ok(top.join(format!(".Trash-{uid}")));
+        }
+    }
+    Ok(home)
+}
+
+/// Percent-encode a path for the `Path` key of trash info files
+fn escape(path: &Path) -> String {
+    let mut out = String::new();
+    for b in path.to_string_lossy().bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => out.push(b as char),
+            b => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}